@@ -4,6 +4,8 @@ use deku::prelude::*;
 
 use derive_more::From;
 
+use std::time::{Duration, SystemTime};
+
 #[derive(
     Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, TryFromPrimitive, IntoPrimitive, From,
 )]
@@ -23,9 +25,12 @@ pub enum EntryId {
     ShortName,
     AFPFileInfo,
     DirectoryID,
+    /// The SHA-256 checksum of the data fork, in the range of IDs which
+    /// Apple leaves to others.
+    Sha256 = 0x8e6c_0001,
 }
 
-#[derive(Debug, Copy, Clone, DekuRead, DekuWrite)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
 #[deku(endian = "big")]
 pub struct EntryDescriptor {
     pub id: u32,
@@ -95,7 +100,7 @@ impl AppleSingleHeaderStub {
     }
 }
 
-#[derive(Debug, Clone, From, DekuRead, DekuWrite)]
+#[derive(Debug, Clone, From, PartialEq, Eq, DekuRead, DekuWrite)]
 pub struct AppleSingleHeader {
     magic: AppleSingleDoubleMagic,
     version: AppleSingleVersion,
@@ -161,6 +166,163 @@ impl AppleSingleHeader {
     }
 }
 
+/// Creation, modification, backup and access times, in seconds relative to
+/// 2000-01-01 00:00:00 UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub struct FileDatesInfo {
+    pub created: i32,
+    pub modified: i32,
+    pub backup: i32,
+    pub accessed: i32,
+}
+
+impl FileDatesInfo {
+    /// The value stored for times which are not known.
+    pub const UNKNOWN: i32 = i32::MIN;
+    /// Seconds from the Unix epoch to 2000-01-01 00:00:00 UTC.
+    const EPOCH: i64 = 946_684_800;
+    pub fn new(created: SystemTime, modified: SystemTime) -> Self {
+        Self {
+            created: Self::encode(created),
+            modified: Self::encode(modified),
+            ..Default::default()
+        }
+    }
+    pub fn created_at(&self) -> Option<SystemTime> {
+        Self::decode(self.created)
+    }
+    pub fn modified_at(&self) -> Option<SystemTime> {
+        Self::decode(self.modified)
+    }
+    pub fn encode(time: SystemTime) -> i32 {
+        let unix = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        (unix - Self::EPOCH).clamp(i32::MIN as i64 + 1, i32::MAX as i64) as i32
+    }
+    pub fn decode(value: i32) -> Option<SystemTime> {
+        if value == Self::UNKNOWN {
+            return None;
+        }
+        let unix = value as i64 + Self::EPOCH;
+        let since = Duration::from_secs(unix.unsigned_abs());
+        if unix < 0 {
+            SystemTime::UNIX_EPOCH.checked_sub(since)
+        } else {
+            SystemTime::UNIX_EPOCH.checked_add(since)
+        }
+    }
+    pub const fn calculate_size() -> usize {
+        4 + 4 + 4 + 4
+    }
+}
+
+impl Default for FileDatesInfo {
+    fn default() -> Self {
+        Self {
+            created: Self::UNKNOWN,
+            modified: Self::UNKNOWN,
+            backup: Self::UNKNOWN,
+            accessed: Self::UNKNOWN,
+        }
+    }
+}
+
+/// Lays out an AppleDouble file from its entries.
+///
+/// The resource fork is always the last entry so that it can be streamed
+/// after the bytes returned by [`AppleDoubleWriter::prefix`].
+#[derive(Debug, Clone)]
+pub struct AppleDoubleWriter {
+    finder_info: FinderInfo,
+    comment: Vec<u8>,
+    dates: Option<FileDatesInfo>,
+    sha256: Option<[u8; 32]>,
+    resource_fork_len: u32,
+}
+
+impl AppleDoubleWriter {
+    pub fn new(finder_info: FinderInfo) -> Self {
+        Self {
+            finder_info,
+            comment: vec![],
+            dates: None,
+            sha256: None,
+            resource_fork_len: 0,
+        }
+    }
+    pub fn comment(self, comment: Vec<u8>) -> Self {
+        Self { comment, ..self }
+    }
+    pub fn dates(self, dates: FileDatesInfo) -> Self {
+        Self {
+            dates: Some(dates),
+            ..self
+        }
+    }
+    pub fn sha256(self, sha256: [u8; 32]) -> Self {
+        Self {
+            sha256: Some(sha256),
+            ..self
+        }
+    }
+    pub fn resource_fork_len(self, resource_fork_len: u32) -> Self {
+        Self {
+            resource_fork_len,
+            ..self
+        }
+    }
+    pub fn header(&self) -> AppleSingleHeader {
+        let mut descriptors = vec![EntryDescriptor {
+            id: EntryId::FinderInfo.into(),
+            offset: 0,
+            length: FinderInfo::calculate_size() as u32,
+        }];
+        if !self.comment.is_empty() {
+            descriptors.push(EntryDescriptor {
+                id: EntryId::Comment.into(),
+                offset: 0,
+                length: self.comment.len() as u32,
+            });
+        }
+        if self.dates.is_some() {
+            descriptors.push(EntryDescriptor {
+                id: EntryId::FileDatesInfo.into(),
+                offset: 0,
+                length: FileDatesInfo::calculate_size() as u32,
+            });
+        }
+        if let Some(sha256) = self.sha256 {
+            descriptors.push(EntryDescriptor {
+                id: EntryId::Sha256.into(),
+                offset: 0,
+                length: sha256.len() as u32,
+            });
+        }
+        descriptors.push(EntryDescriptor {
+            id: EntryId::ResourceFork.into(),
+            offset: 0,
+            length: self.resource_fork_len,
+        });
+        AppleSingleHeader::new_double(descriptors)
+    }
+    /// The header and every entry preceding the resource fork.
+    pub fn prefix(&self) -> Result<Vec<u8>, DekuError> {
+        let mut bytes = self.header().to_bytes()?;
+        bytes.extend(self.finder_info.to_bytes()?);
+        bytes.extend(&self.comment);
+        if let Some(dates) = self.dates {
+            bytes.extend(dates.to_bytes()?);
+        }
+        if let Some(sha256) = self.sha256 {
+            bytes.extend(sha256);
+        }
+        Ok(bytes)
+    }
+}
+
 #[derive(DekuRead, DekuWrite, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FinderInfo {
     pub file_type: FileType,
@@ -254,31 +416,80 @@ pub struct Folder(#[deku(bits = "16")] u16);
 #[cfg(test)]
 mod tests {
     use super::*;
-    #[test]
-    fn test_write_appledouble() -> std::io::Result<()> {
-        let start = AppleSingleHeader::calculate_size(2);
-        let rsrc = [0u8; 128];
-        let finf_descriptor = EntryDescriptor {
-            id: EntryId::FinderInfo.into(),
-            offset: start,
-            length: FinderInfo::calculate_size() as u32,
-        };
-        let rsrc_descriptor = EntryDescriptor {
-            id: EntryId::ResourceFork.into(),
-            offset: finf_descriptor.next_offset(),
-            length: rsrc.len() as u32,
-        };
-        let entries = vec![finf_descriptor, rsrc_descriptor];
-        let hdr = AppleSingleHeader::new_double(entries);
-        eprintln!("{hdr:?}");
+    fn read_entry<'a>(file: &'a [u8], header: &AppleSingleHeader, id: EntryId) -> &'a [u8] {
+        let entry = header.entry(id).expect("missing entry");
+        &file[entry.offset as usize..entry.next_offset() as usize]
+    }
 
-        let _finf = FinderInfo {
+    #[test]
+    fn test_write_appledouble() -> Result<(), DekuError> {
+        let finf = FinderInfo {
             file_type: FileType(FourCC(*b"APPL")),
             creator: Creator(FourCC(*b"ttxt")),
             flags: FinderFlags::default(),
             location: Point::default(),
             folder: Folder::default(),
         };
+        let dates = FileDatesInfo {
+            created: -86400,
+            modified: 86400,
+            ..Default::default()
+        };
+        let rsrc = [0xa5u8; 128];
+        let writer = AppleDoubleWriter::new(finf)
+            .comment(b"hello".to_vec())
+            .dates(dates)
+            .resource_fork_len(rsrc.len() as u32);
+        let mut file = writer.prefix()?;
+        file.extend(rsrc);
+
+        let (_, header) = AppleSingleHeader::from_bytes((&file, 0))?;
+        assert_eq!(header.n_descriptors, 4);
+        assert_eq!(header, writer.header());
+        let finder_info = read_entry(&file, &header, EntryId::FinderInfo);
+        assert_eq!(FinderInfo::try_from(finder_info)?, finf);
+        assert_eq!(read_entry(&file, &header, EntryId::Comment), b"hello");
+        let file_dates = read_entry(&file, &header, EntryId::FileDatesInfo);
+        assert_eq!(FileDatesInfo::try_from(file_dates)?, dates);
+        assert_eq!(read_entry(&file, &header, EntryId::ResourceFork), rsrc);
+        assert_eq!(
+            header.resource_fork().unwrap().next_offset() as usize,
+            file.len()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_dates() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(946_684_800 + 3600);
+        let dates = FileDatesInfo::new(time, time);
+        assert_eq!(dates.modified, 3600);
+        assert_eq!(dates.modified_at(), Some(time));
+        assert_eq!(dates.backup, FileDatesInfo::UNKNOWN);
+        assert_eq!(FileDatesInfo::default().created_at(), None);
+        let before = SystemTime::UNIX_EPOCH - Duration::from_secs(60);
+        assert_eq!(
+            FileDatesInfo::decode(FileDatesInfo::encode(before)),
+            Some(before)
+        );
+    }
+
+    #[test]
+    fn test_write_appledouble_minimal() -> Result<(), DekuError> {
+        let writer = AppleDoubleWriter::new(FinderInfo::windows_file());
+        let file = writer.prefix()?;
+
+        let (_, header) = AppleSingleHeader::from_bytes((&file, 0))?;
+        assert_eq!(header.n_descriptors, 2);
+        assert!(header.entry(EntryId::Comment).is_none());
+        assert!(header.entry(EntryId::FileDatesInfo).is_none());
+        assert_eq!(header.entry_len(EntryId::ResourceFork), Some(0));
+        let finder_info = read_entry(&file, &header, EntryId::FinderInfo);
+        assert_eq!(
+            FinderInfo::try_from(finder_info)?,
+            FinderInfo::windows_file()
+        );
 
         Ok(())
     }
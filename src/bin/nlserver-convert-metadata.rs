@@ -0,0 +1,22 @@
+use anyhow::{anyhow, Result};
+
+use neolith::server::files::metadata::{self, MetadataStorage};
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+
+    let (Some(root), Some(from), Some(to)) = (args.next(), args.next(), args.next()) else {
+        return Err(anyhow!(
+            "usage: nlserver-convert-metadata <root> <from> <to>\n\
+             where <from> and <to> are one of apple_double, netatalk, extended_attributes"
+        ));
+    };
+
+    let from: MetadataStorage = from.parse()?;
+    let to: MetadataStorage = to.parse()?;
+
+    let converted = metadata::convert(root.as_ref(), from, to)?;
+    eprintln!("converted {converted} files from {from} to {to}");
+
+    Ok(())
+}
@@ -3,9 +3,11 @@ use anyhow::Result;
 use neolith::server::user_editor::InteractiveUserEditor;
 
 fn main() -> Result<()> {
-    let file = InteractiveUserEditor::default().interact()?.serialize()?;
+    let editor = InteractiveUserEditor::default().interact()?;
 
-    print!("{}", file);
+    print!("{}", editor.serialize()?);
+
+    editor.notify(true)?;
 
     Ok(())
 }
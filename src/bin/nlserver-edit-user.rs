@@ -15,9 +15,8 @@ fn main() -> Result<()> {
     }?;
 
     let input = fs::read(&filename)?;
-    let output = InteractiveUserEditor::deserialize(&input)?
-        .interact()?
-        .serialize()?;
+    let editor = InteractiveUserEditor::deserialize(&input)?.interact()?;
+    let output = editor.serialize()?;
 
     let write = Confirm::new()
         .with_prompt("Are you sure you want to modify this user?")
@@ -25,6 +24,7 @@ fn main() -> Result<()> {
     if write {
         fs::write(&filename, output)?;
         eprintln!("updated");
+        editor.notify(false)?;
     } else {
         eprintln!("cancelled");
     }
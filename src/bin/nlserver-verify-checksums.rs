@@ -0,0 +1,32 @@
+use anyhow::{anyhow, Result};
+
+use neolith::server::files::{checksum, metadata::MetadataStorage};
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+
+    let (Some(root), Some(storage)) = (args.next(), args.next()) else {
+        return Err(anyhow!(
+            "usage: nlserver-verify-checksums <root> <storage>\n\
+             where <storage> is one of apple_double, netatalk, extended_attributes"
+        ));
+    };
+
+    let storage: MetadataStorage = storage.parse()?;
+
+    let verification = checksum::verify(root.as_ref(), storage)?;
+    for path in &verification.mismatched {
+        println!("{}", path.display());
+    }
+    eprintln!(
+        "{} files verified, {} mismatched, {} without checksums",
+        verification.verified,
+        verification.mismatched.len(),
+        verification.unchecked,
+    );
+
+    if !verification.mismatched.is_empty() {
+        return Err(anyhow!("some files do not match their checksums"));
+    }
+    Ok(())
+}
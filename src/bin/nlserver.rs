@@ -2,12 +2,18 @@ use anyhow::{anyhow, bail};
 use derive_more::Into;
 use encoding_rs::MACINTOSH;
 use futures::stream::TryStreamExt;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::Arc,
+};
 use tokio::{
     io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
     net::TcpListener,
+    signal::unix::{signal, SignalKind},
     sync::watch,
 };
-use tracing::{debug, instrument, trace, warn};
+use tracing::{debug, info, instrument, trace, warn};
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
 type Result<T> = anyhow::Result<T>;
@@ -24,15 +30,29 @@ use neolith::{
         UserNameWithInfo,
     },
     server::{
-        application::UserAccountPermissions, files::OsFiles, users::UserAccounts, ChatRoomLeave,
-        ClientRequest, NeolithServer,
+        announcements::Announcer,
+        application::{Files, UserAccount, UserAccountPermissions},
+        files::{
+            hidden::HideRules,
+            homes::HomeFolders,
+            moderation::Moderation,
+            mounts::{Mount, Mounts},
+            quotas::Quotas,
+            types::TypeMap,
+            OsFiles,
+        },
+        hooks::Hooks,
+        users::UserAccounts,
+        ChatRoomLeave, ClientRequest, NeolithServer,
     },
 };
 
 use neolith::server::{
     bus::{Bus, Notification},
     chat::{Chats, ChatsService},
+    config::{self, Config, FilesConfig},
     news::{News, NewsService},
+    throttle::{Direction, Throttles},
     transaction_stream::Frames,
     transfers::{Requests, TransferConnection, TransfersService},
     users::{Users, UsersService},
@@ -43,6 +63,7 @@ use neolith::server::{
 #[derive(Debug, Clone)]
 struct Globals {
     user_id: Option<UserId>,
+    address: IpAddr,
     users: watch::Receiver<Users>,
     chats: watch::Receiver<Chats>,
     news: watch::Receiver<News>,
@@ -50,8 +71,11 @@ struct Globals {
     chats_tx: ChatsService,
     news_tx: NewsService,
     transfers_tx: TransfersService,
-    files: OsFiles,
+    files: Arc<dyn Files>,
     accounts: UserAccounts,
+    account: Option<UserAccount>,
+    quotas: Quotas,
+    moderation: Moderation,
     bus: Bus,
     transaction_id: i32,
 }
@@ -177,22 +201,39 @@ async fn main() -> Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .try_init()?;
 
+    let config = Config::load(config::DEFAULT_PATH).await?;
+
     let host = "0.0.0.0";
     let listener = TcpListener::bind((host, 5500)).await?;
     let transfer_listener = TcpListener::bind((host, 5501)).await?;
 
     let bus = Bus::new();
 
-    let (users_tx, users_rx) = UsersService::new(bus.clone());
+    let hooks = Hooks::new(config.hooks.clone());
+    let (users_tx, users_rx) = UsersService::new(bus.clone(), hooks.clone());
     let (chats_tx, chats_rx) = ChatsService::new(bus.clone());
-    let (news_tx, news_rx) = NewsService::new(MACINTOSH, bus.clone());
-    let (transfers_tx, transfers_rx) = TransfersService::new(bus.clone());
-
-    let files = OsFiles::with_root("files").await?;
+    let (news_tx, news_rx) = NewsService::new(MACINTOSH, bus.clone(), hooks.clone());
+    let files = mounted_files(&config.files).await?;
+    let quotas = Quotas::load(config.files.quota.clone()).await?;
+    let moderation = Moderation::load(config.files.moderation.clone()).await?;
+    if config.files.moderation.enabled {
+        tokio::fs::create_dir_all(config.files.root.join(moderation.folder())).await?;
+    }
     let accounts = UserAccounts::with_root("users").await?;
 
+    let (transfers_tx, transfers_rx) =
+        TransfersService::new(bus.clone(), files.clone(), config.transfers.clone(), hooks);
+    let announcer = Announcer::new(
+        config.announcements.clone(),
+        HomeFolders::new(accounts.iter()),
+        &bus,
+        chats_tx.clone(),
+        news_tx.clone(),
+    );
+
     let globals = Globals {
         user_id: None,
+        address: Ipv4Addr::UNSPECIFIED.into(),
         users: users_rx.subscribe(),
         chats: chats_rx.subscribe(),
         news: news_rx.subscribe(),
@@ -202,6 +243,9 @@ async fn main() -> Result<()> {
         transfers_tx: transfers_tx.clone(),
         files: files.clone(),
         accounts,
+        account: None,
+        quotas: quotas.clone(),
+        moderation: moderation.clone(),
         bus,
         transaction_id: 0,
     };
@@ -211,16 +255,25 @@ async fn main() -> Result<()> {
         transfers_tx.clone(),
         transfers_rx.subscribe(),
         files.clone(),
+        HomeFolders::new(globals.accounts.iter()),
+        quotas,
+        moderation,
     ));
     tokio::spawn(users_rx.run());
     tokio::spawn(chats_rx.run());
     tokio::spawn(news_rx.run());
     tokio::spawn(transfers_rx.run());
+    tokio::spawn(announcer.run());
+    tokio::spawn(reload_rates(transfers_tx.throttles().clone()));
 
     loop {
         let (socket, addr) = listener.accept().await?;
         let (r, w) = socket.into_split();
-        let mut conn = Connection::new(r, w, globals.clone());
+        let globals = Globals {
+            address: addr.ip(),
+            ..globals.clone()
+        };
+        let mut conn = Connection::new(r, w, globals);
         tokio::task::spawn(async move {
             while conn.process().await.is_ok() {}
             debug!("disconnect from {:?}", addr);
@@ -228,18 +281,72 @@ async fn main() -> Result<()> {
     }
 }
 
+async fn mounted_files(config: &FilesConfig) -> Result<Arc<dyn Files>> {
+    let types = TypeMap::new(config.types.clone());
+    let hidden = HideRules::new(&config.hide)?;
+    let os_files = |root: PathBuf| {
+        let (types, hidden) = (types.clone(), hidden.clone());
+        async move {
+            let files = OsFiles::with_root(root)
+                .await?
+                .with_metadata_storage(config.metadata)
+                .with_type_map(types)
+                .with_hide_rules(hidden);
+            Result::Ok(Arc::new(files))
+        }
+    };
+    let root = os_files(config.root.clone()).await?;
+    if config.mounts.is_empty() {
+        return Ok(root);
+    }
+    let mut mounts = Mounts::new(root);
+    for mount in &config.mounts {
+        let files = os_files(mount.root.clone()).await?;
+        mounts = mounts.with_mount(
+            Mount::new(&mount.path, files)
+                .read_only(mount.read_only)
+                .with_permissions(mount.permissions.clone()),
+        );
+    }
+    Ok(Arc::new(mounts))
+}
+
+/// Applies the transfer rates of the configuration file whenever the server
+/// is sent SIGHUP. Other settings only take effect on restart.
+async fn reload_rates(throttles: Throttles) -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match Config::load(config::DEFAULT_PATH).await {
+            Ok(config) => {
+                throttles.set_limits(Direction::Download, config.transfers.download_rate);
+                throttles.set_limits(Direction::Upload, config.transfers.upload_rate);
+                info!("reloaded transfer rates from {}", config::DEFAULT_PATH);
+            }
+            Err(e) => warn!("failed to reload {}: {e:?}", config::DEFAULT_PATH),
+        }
+    }
+    Ok(())
+}
+
 #[instrument]
 async fn transfers(
     listener: TcpListener,
     transfers_tx: TransfersService,
     transfers: watch::Receiver<Requests>,
-    files: OsFiles,
+    files: Arc<dyn Files>,
+    homes: HomeFolders,
+    quotas: Quotas,
+    moderation: Moderation,
 ) -> Result<()> {
     loop {
-        let (socket, _addr) = listener.accept().await?;
+        let (socket, addr) = listener.accept().await?;
         let conn = TransferConnection::new(
             socket,
+            addr.ip(),
             files.clone(),
+            homes.clone(),
+            quotas.clone(),
+            moderation.clone(),
             transfers_tx.clone(),
             transfers.clone(),
         );
@@ -378,7 +485,7 @@ impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Unauthenticated<R, W> {
         let login = request.login();
         let password = request.password();
 
-        let Some(account) = globals.accounts.verify(login, password) else {
+        let Some(account) = globals.accounts.verify(login, password).cloned() else {
             anyhow::bail!("login failure");
         };
 
@@ -417,6 +524,7 @@ impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Unauthenticated<R, W> {
         };
         debug!("adding user {user:?}");
         globals.user_add(&user).await;
+        globals.account.replace(account);
 
         Ok(request.into())
     }
@@ -462,8 +570,12 @@ impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Established<R, W> {
         let TransactionFrame { header, body } = frame.clone();
         let mut server = NeolithServer::new(
             globals.user_id.unwrap_or_default(),
+            globals.address,
             globals.files.clone(),
             globals.accounts.clone(),
+            globals.account.clone(),
+            globals.quotas.clone(),
+            globals.moderation.clone(),
             globals.users.clone(),
             globals.users_tx.clone(),
             globals.news.clone(),
@@ -616,8 +728,27 @@ impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Established<R, W> {
                 write_frame(w, broadcast.framed()).await?;
             }
             Notification::DownloadInfo(info) => {
-                let info: DownloadInfo = info.into();
-                write_frame(w, info.framed()).await?;
+                if current_user.map(|u| u.user_id) == Some(info.0) {
+                    let info: DownloadInfo = info.into();
+                    write_frame(w, info.framed()).await?;
+                }
+            }
+            Notification::ServerNotice(notice) => {
+                if current_user.map(|u| u.user_id) == Some(notice.0) {
+                    let message: ServerMessage = notice.into();
+                    write_frame(w, message.framed()).await?;
+                }
+            }
+            Notification::TransfersProgress(_) | Notification::UploadComplete(_) => {}
+            Notification::UploadPending(pending) => {
+                let moderates = globals
+                    .account
+                    .as_ref()
+                    .is_some_and(UserAccount::can_moderate_uploads);
+                if moderates {
+                    let message: ServerMessage = pending.into();
+                    write_frame(w, message.framed()).await?;
+                }
             }
             Notification::News(article) => {
                 let article: NotifyNewsMessage = article.into();
@@ -44,3 +44,41 @@ impl From<SystemTime> for DateParameter {
         Self::try_from(time).unwrap_or_default()
     }
 }
+
+impl TryFrom<DateParameter> for SystemTime {
+    type Error = ProtocolError;
+    /// Fails for the all-zero date clients send when a date is unknown.
+    fn try_from(date: DateParameter) -> Result<Self, Self::Error> {
+        if date == DateParameter::default() {
+            return Err(ProtocolError::SystemError);
+        }
+        let year_start = Date::from_ordinal_date(date.year as i32, 1)
+            .or(Err(ProtocolError::SystemError))?
+            .with_hms(0, 0, 0)
+            .or(Err(ProtocolError::SystemError))?
+            .assume_offset(UtcOffset::UTC);
+        let time = year_start
+            + Duration::seconds(date.seconds as i64)
+            + Duration::milliseconds(date.milliseconds as i64);
+        Ok(time.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_date_round_trip() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let date = DateParameter::from(time);
+        assert_eq!(date.year, 2001);
+        assert_eq!(SystemTime::try_from(date).unwrap(), time);
+    }
+
+    #[test]
+    fn test_unknown_date() {
+        assert!(SystemTime::try_from(DateParameter::default()).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Reading flattened file objects as they arrive over a transfer connection.
+
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt as _, ReadBuf, Take};
+
+use super::{FlattenedFileHeader, ForkHeader, ForkType, InfoFork, ProtocolError};
+
+/// The largest INFO fork, with a name and comment of the longest lengths.
+const MAX_INFO_SIZE: u64 = 74 + 2 * i16::MAX as u64;
+
+#[derive(Debug, Clone, Copy)]
+struct CurrentFork {
+    fork_type: ForkType,
+    size: u64,
+    remaining: u64,
+}
+
+impl CurrentFork {
+    fn truncated(&self) -> ProtocolError {
+        ProtocolError::Truncated {
+            fork: self.fork_type,
+            expected: self.size,
+            received: self.size - self.remaining,
+        }
+    }
+}
+
+/// Decodes the header and forks of a flattened file object from `R`.
+///
+/// Forks are handed out in the order they arrive. Whatever is left unread
+/// of a fork, including the whole of a fork the caller has no use for, is
+/// skipped when the next one is requested.
+#[derive(Debug)]
+pub struct FlattenedFileDecoder<R> {
+    reader: R,
+    header: FlattenedFileHeader,
+    forks_left: i16,
+    current: Option<CurrentFork>,
+}
+
+impl<R: AsyncRead + Unpin> FlattenedFileDecoder<R> {
+    /// Reads and validates the header of the flattened file.
+    pub async fn new(mut reader: R) -> Result<Self, ProtocolError> {
+        let mut buf = [0u8; 24];
+        reader.read_exact(&mut buf).await?;
+        let header = FlattenedFileHeader::try_from(&buf[..])?;
+        let forks_left = i16::from(header.fork_count);
+        if header.version != 1 || forks_left < 1 {
+            return Err(ProtocolError::InvalidFileHeader(header));
+        }
+        Ok(Self {
+            reader,
+            header,
+            forks_left,
+            current: None,
+        })
+    }
+    pub fn header(&self) -> &FlattenedFileHeader {
+        &self.header
+    }
+    /// Reads the header of the next fork, or returns `None` after the last
+    /// one.
+    pub async fn next_fork(&mut self) -> Result<Option<Fork<'_, R>>, ProtocolError> {
+        self.skip().await?;
+        if self.forks_left == 0 {
+            return Ok(None);
+        }
+        let mut buf = [0u8; 16];
+        self.reader.read_exact(&mut buf).await?;
+        let header = ForkHeader::try_from(&buf[..])?;
+        self.forks_left -= 1;
+        let size = i32::from(header.data_size) as u32 as u64;
+        let Self {
+            reader, current, ..
+        } = self;
+        let current = current.insert(CurrentFork {
+            fork_type: header.fork_type,
+            size,
+            remaining: size,
+        });
+        Ok(Some(Fork {
+            header,
+            body: reader.take(size),
+            current,
+        }))
+    }
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+    async fn skip(&mut self) -> Result<(), ProtocolError> {
+        let Some(mut current) = self.current.take() else {
+            return Ok(());
+        };
+        if current.remaining == 0 {
+            return Ok(());
+        }
+        let mut rest = (&mut self.reader).take(current.remaining);
+        let skipped = tokio::io::copy(&mut rest, &mut tokio::io::sink()).await?;
+        current.remaining -= skipped;
+        if current.remaining > 0 {
+            return Err(current.truncated());
+        }
+        Ok(())
+    }
+}
+
+/// A single fork of a flattened file, read no further than its size.
+///
+/// Reading past the end of the stream before the end of the fork fails with
+/// [`ErrorKind::UnexpectedEof`], carrying [`ProtocolError::Truncated`].
+#[derive(Debug)]
+pub struct Fork<'a, R> {
+    header: ForkHeader,
+    body: Take<&'a mut R>,
+    current: &'a mut CurrentFork,
+}
+
+impl<R: AsyncRead + Unpin> Fork<'_, R> {
+    pub fn header(&self) -> &ForkHeader {
+        &self.header
+    }
+    pub fn fork_type(&self) -> ForkType {
+        self.header.fork_type
+    }
+    /// The size of the fork in bytes.
+    pub fn size(&self) -> u64 {
+        self.current.size
+    }
+    /// Reads the whole of an INFO fork.
+    pub async fn read_info(mut self) -> Result<InfoFork, ProtocolError> {
+        if self.size() > MAX_INFO_SIZE {
+            return Err(ProtocolError::ForkTooLarge(self.fork_type()));
+        }
+        let mut buf = Vec::with_capacity(self.size() as usize);
+        self.read_to_end(&mut buf)
+            .await
+            .map_err(ProtocolError::from_io)?;
+        Ok(InfoFork::try_from(&buf[..])?)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Fork<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let wanted = buf.remaining() > 0;
+        ready!(Pin::new(&mut self.body).poll_read(cx, buf))?;
+        let remaining = self.body.limit();
+        self.current.remaining = remaining;
+        if wanted && buf.filled().len() == filled && remaining > 0 {
+            let e = self.current.truncated();
+            return Poll::Ready(Err(io::Error::new(ErrorKind::UnexpectedEof, e)));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deku::DekuContainerWrite as _;
+
+    fn flatten(forks: &[(ForkType, &[u8])]) -> Vec<u8> {
+        let header = FlattenedFileHeader {
+            version: 1,
+            fork_count: (forks.len() as i16).into(),
+        };
+        let mut bytes = header.to_bytes().unwrap();
+        for (fork_type, body) in forks {
+            let header = ForkHeader {
+                fork_type: *fork_type,
+                compression_type: Default::default(),
+                data_size: body.len().into(),
+            };
+            bytes.extend(header.to_bytes().unwrap());
+            bytes.extend(*body);
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_decoder_skips_unknown_forks() -> Result<(), ProtocolError> {
+        let bytes = flatten(&[
+            (ForkType::Data, b"data"),
+            (ForkType::Other(*b"XTRA"), b"extra"),
+            (ForkType::Resource, b"rsrc"),
+        ]);
+        let mut decoder = FlattenedFileDecoder::new(&bytes[..]).await?;
+        let mut forks = vec![];
+        while let Some(mut fork) = decoder.next_fork().await? {
+            let mut body = vec![];
+            if fork.fork_type() != ForkType::Other(*b"XTRA") {
+                fork.read_to_end(&mut body).await?;
+            }
+            forks.push((fork.fork_type(), body));
+        }
+        assert_eq!(
+            forks,
+            [
+                (ForkType::Data, b"data".to_vec()),
+                (ForkType::Other(*b"XTRA"), vec![]),
+                (ForkType::Resource, b"rsrc".to_vec()),
+            ]
+        );
+        assert!(decoder.into_inner().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_decoder_reports_truncation() -> Result<(), ProtocolError> {
+        let bytes = flatten(&[(ForkType::Data, b"data"), (ForkType::Resource, b"rsrc")]);
+        let truncated = &bytes[..bytes.len() - 1];
+        let mut decoder = FlattenedFileDecoder::new(truncated).await?;
+        decoder.next_fork().await?.unwrap();
+        let mut fork = decoder.next_fork().await?.unwrap();
+        let e = fork.read_to_end(&mut vec![]).await.unwrap_err();
+        assert!(matches!(
+            ProtocolError::from_io(e),
+            ProtocolError::Truncated {
+                fork: ForkType::Resource,
+                expected: 4,
+                received: 3,
+            }
+        ));
+
+        let mut decoder = FlattenedFileDecoder::new(truncated).await?;
+        decoder.next_fork().await?.unwrap();
+        decoder.next_fork().await?.unwrap();
+        assert!(matches!(
+            decoder.next_fork().await,
+            Err(ProtocolError::Truncated { received: 3, .. })
+        ));
+
+        let mut bad = bytes.clone();
+        bad[5] = 2;
+        assert!(matches!(
+            FlattenedFileDecoder::new(&bad[..]).await,
+            Err(ProtocolError::InvalidFileHeader(_))
+        ));
+        Ok(())
+    }
+}
@@ -63,11 +63,11 @@ impl DekuHotlineProtocol for TransferHandshake {}
 mod tests {
     use super::*;
 
-    static CLIENT_HANDSHAKE: &'static [u8] = &[
+    static CLIENT_HANDSHAKE: &[u8] = &[
         0x54, 0x52, 0x54, 0x50, 0x48, 0x4f, 0x54, 0x4c, 0x00, 0x01, 0x00, 0x02,
     ];
 
-    static SERVER_HANDSHAKE: &'static [u8] = &[0x54, 0x52, 0x54, 0x50, 0x00, 0x00, 0x00, 0x00];
+    static SERVER_HANDSHAKE: &[u8] = &[0x54, 0x52, 0x54, 0x50, 0x00, 0x00, 0x00, 0x00];
 
     #[test]
     fn parse_client_handshake() {
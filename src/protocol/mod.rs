@@ -1,11 +1,13 @@
+use async_compression::tokio::bufread::{ZlibDecoder, ZlibEncoder};
 use deku::prelude::*;
 use derive_more::{From, Into};
 use maplit::hashmap;
 use std::{collections::HashMap, num::NonZeroU32};
 use thiserror::Error;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncSeekExt as _, BufReader};
 
 mod date;
+mod flattened;
 mod handshake;
 mod parameters;
 mod transaction;
@@ -55,6 +57,32 @@ pub enum ProtocolError {
     UnsupportedTransaction(i16),
     #[error("system error")]
     SystemError,
+    #[error("invalid flattened file header {0:?}")]
+    InvalidFileHeader(FlattenedFileHeader),
+    #[error("{0:?} fork is too large")]
+    ForkTooLarge(ForkType),
+    #[error("the INFO fork is missing or follows the resource fork")]
+    MissingInfoFork,
+    #[error("unsupported fork compression {0:?}")]
+    UnsupportedCompression(CompressionType),
+    #[error("{fork:?} fork ended after {received} of {expected} bytes")]
+    Truncated {
+        fork: ForkType,
+        expected: u64,
+        received: u64,
+    },
+}
+
+impl ProtocolError {
+    /// Recovers an error which was passed through an I/O interface, such as
+    /// the truncation of a fork being read with [`AsyncRead`].
+    pub fn from_io(e: std::io::Error) -> Self {
+        if !e.get_ref().is_some_and(|inner| inner.is::<Self>()) {
+            return Self::IO(e);
+        }
+        let inner = e.into_inner().expect("checked above");
+        *inner.downcast::<Self>().expect("checked above")
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, From, Into, DekuRead, DekuWrite)]
@@ -73,14 +101,16 @@ impl Default for ErrorCode {
     }
 }
 
+pub use flattened::{FlattenedFileDecoder, Fork};
 pub use handshake::{
     ClientHandshakeRequest, ServerHandshakeReply, SubProtocolId, TransferHandshake,
 };
 pub use parameters::{
     ChatId, ChatOptions, ChatSubject, Creator, Credential, FileComment, FileCreatedAt,
-    FileCreatorString, FileModifiedAt, FileName, FilePath, FileSize, FileType, FileTypeString,
-    IconId, Message, Nickname, Password, ReferenceNumber, TransactionOptions, TransferSize,
-    UserAccess, UserFlags, UserId, UserLogin, UserNameWithInfo, WaitingCount,
+    FileCreatorString, FileModifiedAt, FileName, FilePath, FileSize, FileTransferOptions, FileType,
+    FileTypeString, FolderItemCount, IconId, Message, Nickname, Password, ReferenceNumber,
+    TransactionOptions, TransferSize, UserAccess, UserFlags, UserId, UserLogin, UserNameWithInfo,
+    WaitingCount,
 };
 pub use transaction::{
     DataSize, FieldId, Flags, Id, IntoFrameExt, IsReply, Parameter, TotalSize, TransactionBody,
@@ -212,6 +242,7 @@ impl TryFrom<&Parameter> for ServerAgreement {
     }
 }
 
+#[allow(dead_code)]
 enum ServerBannerType {
     Url,
     Data,
@@ -782,13 +813,14 @@ pub struct GetFileInfoReply {
     pub comment: FileComment,
     pub created_at: FileCreatedAt,
     pub modified_at: FileModifiedAt,
+    pub item_count: Option<FolderItemCount>,
 }
 
 impl From<GetFileInfoReply> for TransactionFrame {
     fn from(val: GetFileInfoReply) -> Self {
         let header = TransactionType::GetFileInfo.into();
         let type_string = FileTypeString::from(&val.type_code);
-        let body = vec![
+        let mut body = vec![
             val.filename.into(),
             val.type_code.into(),
             val.creator.into(),
@@ -797,8 +829,11 @@ impl From<GetFileInfoReply> for TransactionFrame {
             val.comment.into(),
             val.size.into(),
             type_string.into(),
-        ]
-        .into();
+        ];
+        if let Some(item_count) = val.item_count {
+            body.push(item_count.into());
+        }
+        let body = body.into();
         Self { header, body }
     }
 }
@@ -1660,7 +1695,7 @@ pub struct DownloadFile {
     pub filename: FileName,
     pub file_path: FilePath,
     // TODO: resume
-    // TODO: options
+    pub options: Option<FileTransferOptions>,
 }
 
 impl TryFrom<TransactionFrame> for DownloadFile {
@@ -1673,10 +1708,15 @@ impl TryFrom<TransactionFrame> for DownloadFile {
             .require_field(TransactionField::FileName)
             .map(FileName::from)?;
         let file_path = body.borrow_field(TransactionField::FilePath).try_into()?;
+        let options = body
+            .borrow_field(TransactionField::FileTransferOptions)
+            .map(FileTransferOptions::try_from)
+            .transpose()?;
 
         Ok(Self {
             filename,
             file_path,
+            options,
         })
     }
 }
@@ -1686,11 +1726,16 @@ impl From<DownloadFile> for TransactionFrame {
         let DownloadFile {
             filename,
             file_path,
+            options,
         } = val;
-        let body = [Some(filename.into()), file_path.into()]
-            .into_iter()
-            .flat_map(Option::into_iter)
-            .collect::<TransactionBody>();
+        let body = [
+            Some(filename.into()),
+            file_path.into(),
+            options.map(Into::into),
+        ]
+        .into_iter()
+        .flat_map(Option::into_iter)
+        .collect::<TransactionBody>();
         Self::new(TransactionType::DownloadFile, body)
     }
 }
@@ -1766,15 +1811,43 @@ pub struct FlattenedFileHeader {
     pub fork_count: ForkCount,
 }
 
-#[derive(From, Into)]
-pub struct AsyncDataSource(u64, Box<dyn AsyncRead + Unpin + Send>);
+pub struct AsyncDataSource(u64, Box<dyn AsyncRead + Unpin + Send>, Option<FileRange>);
 
 impl AsyncDataSource {
     pub fn new(len: u64, source: impl AsyncRead + Unpin + Send + 'static) -> Self {
-        Self(len, Box::new(source))
+        Self(len, Box::new(source), None)
+    }
+    /// A fork of `len` bytes read from `file`, starting where it is now.
+    ///
+    /// The fork remembers the file, so it may also be sent without reading
+    /// it through the source.
+    pub async fn from_file(len: u64, mut file: tokio::fs::File) -> std::io::Result<Self> {
+        let offset = file.stream_position().await?;
+        let range = FileRange {
+            file: file.try_clone().await?.into_std().await,
+            offset,
+        };
+        Ok(Self(len, Box::new(file), Some(range)))
+    }
+    /// The file the fork is read from, where it is an ordinary file.
+    pub fn file(&self) -> Option<&FileRange> {
+        self.2.as_ref()
     }
 }
 
+impl From<AsyncDataSource> for (u64, Box<dyn AsyncRead + Unpin + Send>) {
+    fn from(source: AsyncDataSource) -> Self {
+        (source.0, source.1)
+    }
+}
+
+/// Where a fork lies in a file.
+#[derive(Debug)]
+pub struct FileRange {
+    pub file: std::fs::File,
+    pub offset: u64,
+}
+
 pub struct FlattenedFileObject {
     pub version: crate::protocol::handshake::Version,
     pub info: InfoFork,
@@ -1837,18 +1910,41 @@ impl FlattenedFileObject {
     }
 }
 
-#[derive(Debug, Clone, DekuRead, DekuWrite)]
-#[deku(id_type = "u32")]
+#[derive(Debug, Default, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(id_type = "u32", endian = "big")]
 pub enum CompressionType {
+    #[default]
     #[deku(id = "0u32")]
     None,
+    /// A zlib stream, which is an extension of neolith.
+    #[deku(id = "0x7a6c6962u32")]
+    Zlib,
     #[deku(id_pat = "_")]
     Other(NonZeroU32),
 }
 
-impl Default for CompressionType {
-    fn default() -> Self {
-        Self::None
+impl CompressionType {
+    /// Compresses `fork` with this codec.
+    pub fn encode<'a>(
+        &self,
+        fork: impl AsyncRead + Unpin + Send + 'a,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + 'a>, ProtocolError> {
+        match self {
+            Self::None => Ok(Box::new(fork)),
+            Self::Zlib => Ok(Box::new(ZlibEncoder::new(BufReader::new(fork)))),
+            Self::Other(_) => Err(ProtocolError::UnsupportedCompression(self.clone())),
+        }
+    }
+    /// Decompresses `fork` with this codec.
+    pub fn decode<'a>(
+        &self,
+        fork: impl AsyncRead + Unpin + Send + 'a,
+    ) -> Result<Box<dyn AsyncRead + Unpin + Send + 'a>, ProtocolError> {
+        match self {
+            Self::None => Ok(Box::new(fork)),
+            Self::Zlib => Ok(Box::new(ZlibDecoder::new(BufReader::new(fork)))),
+            Self::Other(_) => Err(ProtocolError::UnsupportedCompression(self.clone())),
+        }
     }
 }
 
@@ -1927,6 +2023,8 @@ impl InfoFork {
 pub struct UploadFile {
     pub filename: FileName,
     pub file_path: FilePath,
+    /// The size of the file being uploaded, which newer clients send.
+    pub transfer_size: Option<TransferSize>,
 }
 
 impl TryFrom<TransactionFrame> for UploadFile {
@@ -1939,10 +2037,15 @@ impl TryFrom<TransactionFrame> for UploadFile {
             .require_field(TransactionField::FileName)
             .map(FileName::from)?;
         let file_path = body.borrow_field(TransactionField::FilePath).try_into()?;
+        let transfer_size = body
+            .borrow_field(TransactionField::TransferSize)
+            .map(TransferSize::try_from)
+            .transpose()?;
 
         Ok(Self {
             filename,
             file_path,
+            transfer_size,
         })
     }
 }
@@ -1952,11 +2055,16 @@ impl From<UploadFile> for TransactionFrame {
         let UploadFile {
             filename,
             file_path,
+            transfer_size,
         } = val;
-        let body = [Some(filename.into()), file_path.into()]
-            .into_iter()
-            .flat_map(Option::into_iter)
-            .collect::<TransactionBody>();
+        let body = [
+            Some(filename.into()),
+            file_path.into(),
+            transfer_size.map(Into::into),
+        ]
+        .into_iter()
+        .flat_map(Option::into_iter)
+        .collect::<TransactionBody>();
         Self::new(TransactionType::UploadFile, body)
     }
 }
@@ -2238,7 +2346,7 @@ mod tests {
         assert_eq!(
             login,
             LoginRequest {
-                login: UserLogin::try_from("jyelloz").ok(),
+                login: Some(UserLogin::from_cleartext(b"jyelloz")),
                 nickname: Nickname::try_from("jyelloz").ok(),
                 password: Some(Password::from_cleartext(b"123456")),
                 icon_id: Some(145.into()),
@@ -480,6 +480,27 @@ impl From<FileSize> for Parameter {
     }
 }
 
+#[derive(
+    Debug, Default, Clone, Copy, From, Into, PartialEq, Eq, PartialOrd, Ord, DekuRead, DekuWrite,
+)]
+#[deku(endian = "big")]
+pub struct FolderItemCount(u32);
+
+impl TryFrom<&Parameter> for FolderItemCount {
+    type Error = ProtocolError;
+    fn try_from(parameter: &Parameter) -> Result<Self, Self::Error> {
+        parameter
+            .read_deku()
+            .map_err(|_| ProtocolError::MalformedData(TransactionField::FolderItemCount))
+    }
+}
+
+impl From<FolderItemCount> for Parameter {
+    fn from(val: FolderItemCount) -> Self {
+        Self::new_deku(TransactionField::FolderItemCount, val)
+    }
+}
+
 #[derive(Debug, DekuRead, DekuWrite, PartialEq, Eq, Clone)]
 struct DekuFilePath {
     #[deku(update = "self.components.len()")]
@@ -511,8 +532,9 @@ impl DekuFilePathComponent {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub enum FilePath {
+    #[default]
     Root,
     Directory(Vec<Vec<u8>>),
 }
@@ -540,12 +562,6 @@ impl FilePath {
     }
 }
 
-impl Default for FilePath {
-    fn default() -> Self {
-        Self::Root
-    }
-}
-
 impl fmt::Debug for FilePath {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -719,6 +735,13 @@ impl From<SystemTime> for FileCreatedAt {
     }
 }
 
+impl TryFrom<FileCreatedAt> for SystemTime {
+    type Error = ProtocolError;
+    fn try_from(value: FileCreatedAt) -> Result<Self, Self::Error> {
+        value.0.try_into()
+    }
+}
+
 impl TryFrom<&Parameter> for FileCreatedAt {
     type Error = ProtocolError;
     fn try_from(parameter: &Parameter) -> Result<Self, Self::Error> {
@@ -743,6 +766,13 @@ impl From<SystemTime> for FileModifiedAt {
     }
 }
 
+impl TryFrom<FileModifiedAt> for SystemTime {
+    type Error = ProtocolError;
+    fn try_from(value: FileModifiedAt) -> Result<Self, Self::Error> {
+        value.0.try_into()
+    }
+}
+
 impl TryFrom<&Parameter> for FileModifiedAt {
     type Error = ProtocolError;
     fn try_from(parameter: &Parameter) -> Result<Self, Self::Error> {
@@ -863,6 +893,34 @@ impl From<WaitingCount> for Parameter {
     }
 }
 
+/// Options a client sends along with a file transfer request.
+#[derive(Debug, Default, Clone, Copy, From, Into, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileTransferOptions(i32);
+
+impl FileTransferOptions {
+    /// Set by clients which accept forks compressed with zlib, an extension
+    /// of neolith which other servers ignore.
+    pub const ZLIB: i32 = 0x100;
+    pub fn accepts_zlib(&self) -> bool {
+        self.0 & Self::ZLIB != 0
+    }
+}
+
+impl TryFrom<&Parameter> for FileTransferOptions {
+    type Error = ProtocolError;
+    fn try_from(parameter: &Parameter) -> Result<Self, Self::Error> {
+        let malformed = ProtocolError::MalformedData(TransactionField::FileTransferOptions);
+        let int = i64::from(parameter.int().ok_or(malformed)?);
+        Ok(Self(int as i32))
+    }
+}
+
+impl From<FileTransferOptions> for Parameter {
+    fn from(val: FileTransferOptions) -> Self {
+        Self::new_int(TransactionField::FileTransferOptions, val.0)
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, From, Into, DekuRead, DekuWrite)]
 #[deku(endian = "big")]
 pub struct TransactionOptions(i32);
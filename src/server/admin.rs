@@ -0,0 +1,82 @@
+//! Commands which administrators type in public chat.
+//!
+//! A chat line from an administrator which starts with `/` and names one of
+//! these commands is answered with a server message to that administrator
+//! instead of being sent to the room. Any other line is ordinary chat.
+
+use encoding_rs::MACINTOSH;
+
+use super::progress::TransferProgress;
+use crate::protocol::ReferenceNumber;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// `/transfers` lists the running transfers.
+    Transfers,
+    /// `/cancel <reference>` stops a running transfer.
+    Cancel(ReferenceNumber),
+}
+
+impl AdminCommand {
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let (line, _) = MACINTOSH.decode_without_bom_handling(message);
+        let mut words = line.trim().strip_prefix('/')?.split_whitespace();
+        let command = match (words.next()?, words.next()) {
+            ("transfers", None) => Self::Transfers,
+            ("cancel", Some(reference)) => Self::Cancel(reference.parse::<u32>().ok()?.into()),
+            _ => return None,
+        };
+        words.next().is_none().then_some(command)
+    }
+}
+
+/// The answer to `/transfers`.
+pub fn transfers(transfers: &[TransferProgress]) -> String {
+    if transfers.is_empty() {
+        return "No transfers are running.".into();
+    }
+    transfers
+        .iter()
+        .map(|transfer| {
+            let login = transfer.login.as_deref().unwrap_or("a guest");
+            format!("#{} for {login}: {transfer}", transfer.reference)
+        })
+        .collect::<Vec<_>>()
+        .join("\r")
+}
+
+/// The answer to `/cancel`.
+pub fn cancelled(reference: ReferenceNumber, found: bool) -> String {
+    if found {
+        format!("Cancelled transfer #{reference}.")
+    } else {
+        format!("There is no transfer #{reference}.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            AdminCommand::parse(b"/transfers"),
+            Some(AdminCommand::Transfers)
+        );
+        assert_eq!(
+            AdminCommand::parse(b" /cancel 42 "),
+            Some(AdminCommand::Cancel(42.into()))
+        );
+        for line in [
+            &b"transfers"[..],
+            b"/transfers now",
+            b"/cancel",
+            b"/cancel all",
+            b"/cancel 1 2",
+            b"/shrug",
+        ] {
+            assert_eq!(AdminCommand::parse(line), None);
+        }
+    }
+}
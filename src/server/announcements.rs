@@ -0,0 +1,235 @@
+//! Announcing finished uploads in public chat and the news.
+//!
+//! Only uploads into the configured folders are announced, and never those
+//! into the home folder of an account, even when a configured folder holds
+//! it. After each announcement, uploads finishing within the interval are
+//! gathered into a single one, so that uploading a whole folder doesn't
+//! flood the room.
+
+use encoding_rs::MACINTOSH;
+use futures::StreamExt as _;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+use super::{
+    bus::{Bus, Notification, Notifications},
+    chat::ChatsService,
+    files::homes::HomeFolders,
+    news::NewsService,
+    UploadComplete,
+};
+use crate::protocol as proto;
+
+/// The most uploads named in one announcement.
+const MAX_NAMED: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnnouncementsConfig {
+    /// Announce uploads with a line in public chat.
+    pub chat: bool,
+    /// Announce uploads with a news article.
+    pub news: bool,
+    /// Folders, such as `/Uploads`, whose uploads are announced along with
+    /// those of their subfolders. `/` covers the whole file area.
+    pub folders: Vec<PathBuf>,
+    /// The fewest seconds between announcements.
+    pub interval: u64,
+}
+
+impl Default for AnnouncementsConfig {
+    fn default() -> Self {
+        Self {
+            chat: false,
+            news: false,
+            folders: vec![],
+            interval: 60,
+        }
+    }
+}
+
+impl AnnouncementsConfig {
+    fn covers(&self, path: &Path) -> bool {
+        self.folders.iter().any(|folder| {
+            let folder: PathBuf = folder
+                .components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .collect();
+            path.starts_with(folder)
+        })
+    }
+}
+
+/// Announces the uploads published on the bus.
+pub struct Announcer {
+    config: AnnouncementsConfig,
+    homes: HomeFolders,
+    notifications: Notifications,
+    chats: ChatsService,
+    news: NewsService,
+}
+
+impl Announcer {
+    pub fn new(
+        config: AnnouncementsConfig,
+        homes: HomeFolders,
+        bus: &Bus,
+        chats: ChatsService,
+        news: NewsService,
+    ) -> Self {
+        Self {
+            config,
+            homes,
+            notifications: bus.subscribe(),
+            chats,
+            news,
+        }
+    }
+    #[tracing::instrument(name = "Announcer", skip(self))]
+    pub async fn run(self) {
+        let Self {
+            config,
+            homes,
+            notifications,
+            mut chats,
+            mut news,
+        } = self;
+        if !(config.chat || config.news) || config.folders.is_empty() {
+            return;
+        }
+        let interval = Duration::from_secs(config.interval);
+        let mut notifications = notifications.incoming().boxed();
+        let mut uploads = vec![];
+        let mut next = Instant::now();
+        loop {
+            tokio::select! {
+                notification = notifications.next() => match notification {
+                    Some(Notification::UploadComplete(upload))
+                        if config.covers(&upload.0) && !homes.is_private(&upload.0) =>
+                    {
+                        debug!("will announce {upload:?}");
+                        uploads.push(upload);
+                    }
+                    Some(_) => {}
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(next), if !uploads.is_empty() => {
+                    let text = announcement(&uploads);
+                    uploads.clear();
+                    next = Instant::now() + interval;
+                    let text = MACINTOSH.encode(&text).0.into_owned();
+                    if config.chat {
+                        let message = [&b"\r "[..], &text[..]].concat();
+                        let chat = proto::ChatMessage {
+                            chat_id: None,
+                            message,
+                        };
+                        if let Err(e) = chats.chat(chat).await {
+                            error!("failed to announce uploads in chat: {e:?}");
+                        }
+                    }
+                    if config.news {
+                        news.post(text).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The text announcing `uploads`, naming the first few of them.
+fn announcement(uploads: &[UploadComplete]) -> String {
+    let named = uploads
+        .iter()
+        .take(MAX_NAMED)
+        .map(|UploadComplete(path, login)| {
+            let login = login.as_deref().unwrap_or("a guest");
+            format!("{} (from {login})", path.display())
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    match uploads.len() {
+        1 => format!("New upload: {named}"),
+        n if n <= MAX_NAMED => format!("{n} new uploads: {named}"),
+        n => format!("{n} new uploads, including {named}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        application::{HomeFolder, UserAccount, UserAccountIdentity},
+        hooks::Hooks,
+    };
+    use futures::stream::BoxStream;
+
+    async fn next_chat(notifications: &mut BoxStream<'_, Notification>) -> String {
+        loop {
+            if let Some(Notification::Chat(chat)) = notifications.next().await {
+                return MACINTOSH.decode(&chat.message).0.into_owned();
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_uploads_are_gathered() {
+        let bus = Bus::new();
+        let (chats, _) = ChatsService::new(bus.clone());
+        let (news, news_processor) = NewsService::new(MACINTOSH, bus.clone(), Hooks::default());
+        let articles = news_processor.subscribe();
+        tokio::spawn(news_processor.run());
+        let config = AnnouncementsConfig {
+            chat: true,
+            news: true,
+            folders: vec!["/Uploads".into()],
+            ..Default::default()
+        };
+        let mut chat = bus.subscribe().incoming().boxed();
+        let bob = UserAccount {
+            identity: UserAccountIdentity {
+                login: "bob".into(),
+                ..Default::default()
+            },
+            home: Some(HomeFolder {
+                path: "Uploads/Bob".into(),
+                is_root: false,
+            }),
+            ..Default::default()
+        };
+        let homes = HomeFolders::new([&bob]);
+        tokio::spawn(Announcer::new(config, homes, &bus, chats, news).run());
+        tokio::task::yield_now().await;
+
+        let upload = |path: &str| {
+            let upload = UploadComplete(path.into(), Some("alice".into()));
+            bus.publish(Notification::UploadComplete(upload));
+        };
+
+        upload("Uploads/first.sit");
+        assert_eq!(
+            next_chat(&mut chat).await,
+            "\r New upload: Uploads/first.sit (from alice)"
+        );
+        upload("Software/elsewhere.sit");
+        upload("Uploads/Bob/private.sit");
+        for n in 0..7 {
+            upload(&format!("Uploads/Folder/{n}.sit"));
+            tokio::task::yield_now().await;
+        }
+        let start = Instant::now();
+        let text = next_chat(&mut chat).await;
+        assert!(start.elapsed() >= Duration::from_secs(59));
+        assert!(text.starts_with("\r 7 new uploads, including Uploads/Folder/0.sit"));
+        assert!(!text.contains("elsewhere") && !text.contains("5.sit"));
+        assert!(!text.contains("private"));
+        tokio::task::yield_now().await;
+        let news = String::from_utf8(articles.borrow().all()).unwrap();
+        assert!(news.contains("7 new uploads") && news.contains("New upload: "));
+    }
+}
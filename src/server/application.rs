@@ -1,13 +1,24 @@
 use derive_more::{From, Into};
 use enumset::{enum_set, EnumSet, EnumSetIter, EnumSetType};
+use futures::future::BoxFuture;
 use serde::{de::Visitor, ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
-use std::{fmt, future::Future, marker::PhantomData, pin::Pin};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
 use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
+use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::protocol as proto;
+use crate::protocol::{self as proto, FlattenedFileObject};
+use crate::server::files::{checksum::Checksum, DirEntry, FileInfo};
 
 type Pbdf<O> = Pin<Box<dyn Future<Output = O>>>;
 type Ppdfr<O> = Pbdf<Result<O, Error>>;
+pub type FilesFuture<'a, O> = BoxFuture<'a, io::Result<O>>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -48,6 +59,17 @@ pub enum FileOperation {
     SetFolderComment = 29,
     ViewDropBox = 30,
     CreateAlias = 31,
+    /// Review uploads held for moderation, which is not a Hotline privilege.
+    /// It is only kept in the server's account files and never takes a bit
+    /// of the access bitmap clients see.
+    ModerateUploads = 60,
+}
+
+impl FileOperation {
+    /// Whether the operation has a bit in the Hotline access bitmap.
+    fn in_bitmap(self) -> bool {
+        !matches!(self, Self::ModerateUploads)
+    }
 }
 
 #[derive(
@@ -197,7 +219,7 @@ impl FromIterator<FileOperation> for FilePermissions {
 impl From<i64> for FilePermissions {
     fn from(bits: i64) -> Self {
         let mut flags = FlagSet::empty();
-        for op in FileOperation::iter() {
+        for op in FileOperation::iter().filter(|op| op.in_bitmap()) {
             let bit = 1 & (bits >> (63 - (op as u8))) == 1;
             if bit {
                 flags.0.insert(op);
@@ -210,7 +232,7 @@ impl From<i64> for FilePermissions {
 impl From<FilePermissions> for i64 {
     fn from(val: FilePermissions) -> Self {
         let mut bits = 0;
-        for op in val.into_iter() {
+        for op in val.into_iter().filter(|op| op.in_bitmap()) {
             bits |= 1 << (63 - (op as u8));
         }
         bits
@@ -509,16 +531,39 @@ pub struct UserAccountIdentity {
     pub password: Password,
 }
 
+/// A folder of the file area which only its account and administrators can
+/// reach.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct HomeFolder {
+    pub path: PathBuf,
+    /// Whether the account sees its home folder as the root of the file area.
+    #[serde(default)]
+    pub is_root: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub struct UserAccount {
     pub identity: UserAccountIdentity,
     pub permissions: UserAccountPermissions,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<HomeFolder>,
+    /// The most bytes the account may have uploaded, overriding the default
+    /// quota.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
 }
 
 impl UserAccount {
     pub fn is_admin(&self) -> bool {
         self.permissions.user.can(UserOperation::CanDisconnectUsers)
     }
+    /// Whether uploads from the account may replace existing files.
+    pub fn can_overwrite_files(&self) -> bool {
+        self.permissions.file.can(FileOperation::DeleteFile)
+    }
+    pub fn can_moderate_uploads(&self) -> bool {
+        self.permissions.file.can(FileOperation::ModerateUploads)
+    }
 }
 
 impl TryFrom<UserAccount> for proto::GetUserReply {
@@ -564,12 +609,57 @@ pub trait Users {
     fn authenticate(&self, credentials: &Credentials) -> Ppdfr<bool>;
     fn authorize<I: Identity>(&self, identity: &I) -> Ppdfr<bool>;
 }
-pub trait Files {}
+/// A file area which clients browse, download from and upload to.
+///
+/// Paths are relative to the root of the area and made of host names, as
+/// produced by [`crate::server::files::names`].
+pub trait Files: fmt::Debug + Send + Sync {
+    fn list<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Vec<DirEntry>>;
+    fn get_info<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, FileInfo>;
+    fn read<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, FlattenedFileObject>;
+    /// Opens the data fork of `path` for writing from `offset`, replacing its
+    /// contents when `offset` is zero.
+    fn write<'a>(
+        &'a self,
+        path: &'a Path,
+        offset: u64,
+    ) -> FilesFuture<'a, Box<dyn AsyncWrite + Unpin + Send>>;
+    /// Stores the Finder info, comment and dates from `info` along with a
+    /// resource fork of `len` bytes read from `fork`, which may be empty.
+    fn write_metadata<'a>(
+        &'a self,
+        path: &'a Path,
+        info: &'a proto::InfoFork,
+        fork: &'a mut (dyn AsyncRead + Unpin + Send),
+        len: u64,
+    ) -> FilesFuture<'a, ()>;
+    /// Applies the dates from `info` to the data fork of `path`.
+    fn set_times<'a>(&'a self, path: &'a Path, info: &'a proto::InfoFork) -> FilesFuture<'a, ()>;
+    fn set_comment<'a>(&'a self, path: &'a Path, comment: Vec<u8>) -> FilesFuture<'a, ()>;
+    /// Records the checksum of the data fork of `path`, which is kept until
+    /// its metadata is next written.
+    fn set_checksum<'a>(&'a self, path: &'a Path, checksum: Checksum) -> FilesFuture<'a, ()>;
+    /// Renames `path` to the host name of the Mac name `new_name`.
+    fn rename<'a>(&'a self, path: &'a Path, new_name: &'a [u8]) -> FilesFuture<'a, ()>;
+    /// Removes the file `path` and its metadata, which discards incomplete
+    /// uploads.
+    fn remove<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, ()>;
+    /// Moves the file `from` and its metadata to `to`, which puts finished
+    /// uploads into place. A file already at `to` is replaced if `replace`
+    /// is set, and otherwise left alone, failing with `AlreadyExists`.
+    fn replace<'a>(&'a self, from: &'a Path, to: &'a Path, replace: bool) -> FilesFuture<'a, ()>;
+    /// The bytes which may still be written next to `path`, when known.
+    fn available_space<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Option<u64>>;
+    /// Where the data fork of `path` is on the host, for handing to local
+    /// commands, or `None` if it is not kept in a host file.
+    fn host_path<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Option<PathBuf>>;
+}
 pub trait News {}
 pub trait Messages {}
 
 impl Unpin for UserList {}
 
+#[allow(dead_code)]
 pub struct Application<U: Users, F: Files, N: News, M: Messages> {
     users: U,
     files: F,
@@ -577,6 +667,7 @@ pub struct Application<U: Users, F: Files, N: News, M: Messages> {
     messages: M,
 }
 
+#[allow(dead_code)]
 impl<U: Users, F: Files, N: News, M: Messages> Application<U, F, N, M> {
     async fn login(&self, credentials: &Credentials) -> Result<(), Error> {
         let result = self.users.authenticate(credentials).await?;
@@ -605,11 +696,10 @@ impl<U: Users, F: Files, N: News, M: Messages> Application<U, F, N, M> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::files::memory::MemoryFiles;
     use anyhow::Result;
     use std::future;
 
-    struct EmptyFiles;
-    impl Files for EmptyFiles {}
     struct EmptyNews;
     impl News for EmptyNews {}
     struct EmptyMessages;
@@ -656,7 +746,7 @@ mod tests {
     async fn test_who() -> Result<()> {
         let application = Application {
             users: TestUsers,
-            files: EmptyFiles,
+            files: MemoryFiles::new(),
             news: EmptyNews,
             messages: EmptyMessages,
         };
@@ -669,7 +759,7 @@ mod tests {
     async fn test_info() -> Result<()> {
         let application = Application {
             users: TestUsers,
-            files: EmptyFiles,
+            files: MemoryFiles::new(),
             news: EmptyNews,
             messages: EmptyMessages,
         };
@@ -698,6 +788,19 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_moderation_is_not_in_bitmap() {
+        let permissions: FilePermissions =
+            [FileOperation::Download, FileOperation::ModerateUploads]
+                .into_iter()
+                .collect();
+        let bits = i64::from(permissions);
+        assert_eq!(bits, 1 << (63 - FileOperation::Download as u8));
+        let permissions = FilePermissions::from(bits | 1 << 3);
+        assert!(permissions.can(FileOperation::Download));
+        assert!(!permissions.can(FileOperation::ModerateUploads));
+    }
+
     #[test]
     fn deserialize_news_permissions() -> Result<()> {
         let d = NewsPermissions::default();
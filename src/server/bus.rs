@@ -4,7 +4,8 @@ use derive_more::{From, Into};
 
 use super::{
     Article, Broadcast, ChatMessage, ChatRoomInvite, ChatRoomLeave, ChatRoomPresence,
-    ChatRoomSubject, DownloadInfo, InstantMessage, User,
+    ChatRoomSubject, DownloadInfo, InstantMessage, ServerNotice, TransfersProgress, UploadComplete,
+    UploadPending, User,
 };
 
 #[derive(Debug, Clone)]
@@ -17,6 +18,10 @@ pub enum Notification {
     ChatRoomLeave(ChatRoomLeave),
     Broadcast(Broadcast),
     DownloadInfo(DownloadInfo),
+    ServerNotice(ServerNotice),
+    TransfersProgress(TransfersProgress),
+    UploadPending(UploadPending),
+    UploadComplete(UploadComplete),
     News(Article),
     InstantMessage(InstantMessage),
     UserConnect(User),
@@ -1,7 +1,8 @@
 use crate::{
     protocol::{self as proto, ChatId, UserId},
     server::{
-        bus::Bus, ChatRoomCreationRequest, ChatRoomPresence, ChatRoomSubject, InstantMessage,
+        bus::{Bus, Notification},
+        ChatRoomCreationRequest, ChatRoomPresence, ChatRoomSubject, InstantMessage, ServerNotice,
     },
 };
 
@@ -207,6 +208,11 @@ impl ChatsService {
         bus.publish(message.into());
         Ok(())
     }
+    pub async fn notice(&mut self, notice: ServerNotice) -> Result<()> {
+        let Self(_, bus) = self;
+        bus.publish(Notification::ServerNotice(notice));
+        Ok(())
+    }
     pub async fn leave_all(&mut self, request: UserId) -> Result<Vec<ChatId>> {
         let (tx, rx) = oneshot::channel();
         self.0.send(Command::UserLeaveAll(request, tx)).await?;
@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use super::announcements::AnnouncementsConfig;
+use super::files::{
+    hidden, metadata::MetadataStorage, moderation::ModerationConfig, mounts::MountConfig,
+    quotas::QuotaConfig, types::TypeMapping,
+};
+use super::hooks::HooksConfig;
+use super::transfers::TransfersConfig;
+
+/// Where the server and its tools look for their settings.
+pub const DEFAULT_PATH: &str = "neolith.toml";
+
+/// Server settings, read from a TOML file.
+///
+/// Every setting has a default so that a missing file, or a file that only
+/// overrides a few settings, is valid.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub files: FilesConfig,
+    /// Limits on concurrent file transfers.
+    pub transfers: TransfersConfig,
+    /// Local commands to run when things happen.
+    pub hooks: HooksConfig,
+    /// Where finished uploads are announced.
+    pub announcements: AnnouncementsConfig,
+}
+
+impl Config {
+    pub async fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !fs::try_exists(path).await? {
+            return Ok(Self::default());
+        }
+        let data = fs::read_to_string(path).await?;
+        let config = toml::from_str(&data)?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilesConfig {
+    pub root: PathBuf,
+    pub metadata: MetadataStorage,
+    /// Types and creators for files without metadata, consulted before the
+    /// built-in mappings and libmagic.
+    pub types: Vec<TypeMapping>,
+    /// Glob patterns for names which clients never see.
+    pub hide: Vec<String>,
+    /// Host directories shown within the file area, on top of `root`.
+    pub mounts: Vec<MountConfig>,
+    /// Limits on the space taken by uploads.
+    pub quota: QuotaConfig,
+    /// Holding uploads until a moderator approves them.
+    pub moderation: ModerationConfig,
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self {
+            root: "files".into(),
+            metadata: MetadataStorage::default(),
+            types: vec![],
+            hide: hidden::DEFAULT_PATTERNS
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            mounts: vec![],
+            quota: QuotaConfig::default(),
+            moderation: ModerationConfig::default(),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Caching of the metadata shown in listings and file info.
+//!
+//! Entries are kept per directory and are only reused while the entry and
+//! its AppleDouble file, if any, have the same size, modification and change
+//! times. The change time also covers extended attributes.
+//!
+//! The cache holds a bounded number of entries. Once it is full, the
+//! directories looked at least recently are dropped first.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::Metadata,
+    io,
+    os::unix::fs::MetadataExt as _,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::ExtendedMetadata;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Times {
+    len: u64,
+    modified: (i64, i64),
+    changed: (i64, i64),
+}
+
+impl From<&Metadata> for Times {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: (metadata.mtime(), metadata.mtime_nsec()),
+            changed: (metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+/// Identifies one version of an entry and its sidecar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    entry: Times,
+    sidecar: Option<Times>,
+}
+
+impl Stamp {
+    pub fn new(metadata: &Metadata, sidecar_path: Option<&Path>) -> Self {
+        let sidecar = sidecar_path
+            .and_then(|path| std::fs::metadata(path).ok())
+            .map(|metadata| Times::from(&metadata));
+        Self {
+            entry: metadata.into(),
+            sidecar,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Cached {
+    pub stamp: Stamp,
+    pub visible: bool,
+    pub metadata: ExtendedMetadata,
+}
+
+/// How many entries are cached unless configured otherwise.
+pub const DEFAULT_CAPACITY: usize = 65_536;
+
+#[derive(Debug, Default)]
+struct Directory {
+    entries: HashMap<OsString, Cached>,
+    /// When the directory was last looked at, by the cache's clock.
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Directories {
+    directories: HashMap<PathBuf, Directory>,
+    clock: u64,
+    len: usize,
+}
+
+impl Directories {
+    fn touch(&mut self, directory: &Path) -> Option<&mut Directory> {
+        self.clock += 1;
+        let clock = self.clock;
+        let directory = self.directories.get_mut(directory)?;
+        directory.used = clock;
+        Some(directory)
+    }
+    /// Drops the least recently used directories other than `keep` until
+    /// there are at most `capacity` entries.
+    fn evict(&mut self, capacity: usize, keep: &Path) {
+        while self.len > capacity {
+            let oldest = self
+                .directories
+                .iter()
+                .filter(|(path, _)| *path != keep)
+                .min_by_key(|(_, directory)| directory.used)
+                .map(|(path, _)| path.clone());
+            let Some(oldest) = oldest else {
+                break;
+            };
+            if let Some(directory) = self.directories.remove(&oldest) {
+                self.len -= directory.entries.len();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MetadataCache {
+    directories: Mutex<Directories>,
+    capacity: usize,
+}
+
+impl Default for MetadataCache {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl MetadataCache {
+    /// A cache of at most `capacity` entries, apart from those of the
+    /// directory looked at last.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            directories: Default::default(),
+            capacity,
+        }
+    }
+    fn split(path: &Path) -> io::Result<(&Path, &std::ffi::OsStr)> {
+        let directory = path.parent().ok_or(io::ErrorKind::InvalidInput)?;
+        let name = path.file_name().ok_or(io::ErrorKind::InvalidInput)?;
+        Ok((directory, name))
+    }
+    pub(super) fn get(&self, path: &Path, stamp: &Stamp) -> Option<Cached> {
+        let (directory, name) = Self::split(path).ok()?;
+        let mut directories = self.directories.lock().unwrap();
+        let cached = directories.touch(directory)?.entries.get(name)?;
+        (cached.stamp == *stamp).then(|| cached.clone())
+    }
+    pub(super) fn insert(&self, path: &Path, cached: Cached) {
+        let Ok((directory, name)) = Self::split(path) else {
+            return;
+        };
+        let mut directories = self.directories.lock().unwrap();
+        if directories.touch(directory).is_none() {
+            let used = directories.clock;
+            let entries = Directory {
+                used,
+                ..Default::default()
+            };
+            directories
+                .directories
+                .insert(directory.to_path_buf(), entries);
+        }
+        let entries = &mut directories.directories.get_mut(directory).unwrap().entries;
+        if entries.insert(name.to_os_string(), cached).is_none() {
+            directories.len += 1;
+        }
+        directories.evict(self.capacity, directory);
+    }
+    /// Drops the entries of `directory` which no longer exist.
+    pub(super) fn retain(&self, directory: &Path, names: &HashSet<OsString>) {
+        let mut directories = self.directories.lock().unwrap();
+        if let Some(entries) = directories.directories.get_mut(directory) {
+            let before = entries.entries.len();
+            entries.entries.retain(|name, _| names.contains(name));
+            let dropped = before - entries.entries.len();
+            directories.len -= dropped;
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.directories.lock().unwrap().len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::files::scratch::scratch_dir;
+    use std::fs;
+
+    #[test]
+    fn test_stale_entries_are_not_returned() -> io::Result<()> {
+        let root = scratch_dir("cache")?;
+        let path = root.join("file.txt");
+        fs::write(&path, b"one")?;
+
+        let cache = MetadataCache::default();
+        let stamp = Stamp::new(&fs::metadata(&path)?, None);
+        let cached = Cached {
+            stamp,
+            visible: true,
+            metadata: ExtendedMetadata::directory(0),
+        };
+        cache.insert(&path, cached);
+        assert!(cache.get(&path, &stamp).is_some());
+
+        fs::write(&path, b"three")?;
+        let stamp = Stamp::new(&fs::metadata(&path)?, None);
+        assert!(cache.get(&path, &stamp).is_none());
+
+        cache.retain(&root, &HashSet::new());
+        assert!(cache.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_least_recently_used_directories_are_evicted() {
+        let root = std::env::temp_dir();
+        let stamp = Stamp::new(&fs::metadata(&root).unwrap(), None);
+        let cached = Cached {
+            stamp,
+            visible: true,
+            metadata: ExtendedMetadata::directory(0),
+        };
+        let cache = MetadataCache::with_capacity(4);
+        for directory in ["a", "b", "c"] {
+            for name in ["1", "2"] {
+                cache.insert(&root.join(directory).join(name), cached.clone());
+            }
+            cache.get(&root.join("a/1"), &stamp).unwrap();
+        }
+        assert_eq!(cache.len(), 4);
+        assert!(cache.get(&root.join("a/2"), &stamp).is_some());
+        assert!(cache.get(&root.join("b/1"), &stamp).is_none());
+        assert!(cache.get(&root.join("c/1"), &stamp).is_some());
+    }
+}
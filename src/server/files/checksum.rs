@@ -0,0 +1,158 @@
+//! SHA-256 checksums of data forks, taken as files are uploaded and kept
+//! with the rest of their metadata so the archive can be checked later.
+
+use derive_more::{From, Into};
+use sha2::{Digest as _, Sha256};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::debug;
+
+use super::metadata::{is_sidecar, MetadataStorage};
+
+/// The SHA-256 digest of a data fork.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, From, Into)]
+pub struct Checksum([u8; 32]);
+
+impl Checksum {
+    /// Reads `reader` to the end and returns the checksum of what it held.
+    pub fn of(mut reader: impl io::Read) -> io::Result<Self> {
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 << 10];
+        loop {
+            let len = reader.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+        Ok(Self(hasher.finalize().into()))
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+/// A reader which takes the checksum of everything read through it.
+pub struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> Hashing<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+    /// The checksum of what has been read so far.
+    pub fn checksum(&self) -> Checksum {
+        Checksum(self.hasher.clone().finalize().into())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Hashing<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.hasher.update(&buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The outcome of checking every file below a root.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Files whose data fork matches their checksum.
+    pub verified: u64,
+    /// Files without a checksum.
+    pub unchecked: u64,
+    /// Files whose data fork no longer matches their checksum.
+    pub mismatched: Vec<PathBuf>,
+}
+
+/// Checks the data fork of every file below `root` against the checksum in
+/// its metadata.
+pub fn verify(root: &Path, storage: MetadataStorage) -> io::Result<Verification> {
+    let mut verification = Verification::default();
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if is_sidecar(&entry.file_name()) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                directories.push(path);
+                continue;
+            }
+            let expected = storage.read(&path)?.and_then(|sidecar| sidecar.checksum);
+            let Some(expected) = expected else {
+                verification.unchecked += 1;
+                continue;
+            };
+            let actual = Checksum::of(fs::File::open(&path)?)?;
+            if actual == expected {
+                verification.verified += 1;
+            } else {
+                debug!("{path:?} has checksum {actual}, expected {expected}");
+                verification.mismatched.push(path);
+            }
+        }
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::files::{metadata::Sidecar, scratch::scratch_dir};
+    use tokio::io::AsyncReadExt as _;
+
+    #[tokio::test]
+    async fn test_verify() -> io::Result<()> {
+        let root = scratch_dir("verify")?;
+        fs::create_dir_all(root.join("folder"))?;
+        let mut hashing = Hashing::new(&b"data fork"[..]);
+        hashing.read_to_end(&mut vec![]).await?;
+        let checksum = hashing.checksum();
+        assert_eq!(checksum, Checksum::of(&b"data fork"[..])?);
+        assert_eq!(
+            Checksum::of(io::empty())?.to_string(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let storage = MetadataStorage::AppleDouble;
+        let sidecar = Sidecar {
+            checksum: Some(checksum),
+            ..Default::default()
+        };
+        for name in ["intact", "folder/damaged"] {
+            let path = root.join(name);
+            fs::write(&path, b"data fork")?;
+            storage.write(&path, &sidecar)?;
+        }
+        fs::write(root.join("folder/damaged"), b"data fork!")?;
+        fs::write(root.join("unchecked"), b"data fork")?;
+
+        let verification = verify(&root, storage)?;
+        assert_eq!(verification.verified, 1);
+        assert_eq!(verification.unchecked, 1);
+        assert_eq!(verification.mismatched, [root.join("folder/damaged")]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Rules for keeping host files out of sight of clients.
+
+use glob::{MatchOptions, Pattern, PatternError};
+use std::ffi::OsStr;
+
+use super::metadata;
+
+/// Names hidden unless configured otherwise: Unix dotfiles, which include
+/// netatalk's `.AppleDB` and `.AppleDesktop`, custom folder icons and the
+/// folders classic Mac OS and netatalk keep at the root of a volume.
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    ".*",
+    "Icon\r",
+    "Network Trash Folder",
+    "Temporary Items",
+    "TheVolumeSettingsFolder",
+];
+
+/// The start of the names uploads are kept under until all of them has
+/// arrived.
+pub const STAGING_PREFIX: &str = ".upload-";
+
+/// Decides which names are kept out of listings, item counts and downloads.
+///
+/// Files whose Finder info has the invisible flag set are hidden as well,
+/// which [`super::OsFiles`] checks separately since it needs their metadata.
+#[derive(Debug, Clone)]
+pub struct HideRules {
+    patterns: Vec<Pattern>,
+}
+
+impl Default for HideRules {
+    fn default() -> Self {
+        Self::new(DEFAULT_PATTERNS).expect("default patterns must be valid")
+    }
+}
+
+impl HideRules {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, PatternError> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| Pattern::new(pattern.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+    /// Whether `name` is an AppleDouble sidecar, an upload in progress or
+    /// matches a pattern.
+    pub fn hides(&self, name: &OsStr) -> bool {
+        if metadata::is_sidecar(name) {
+            return true;
+        }
+        let name = name.to_string_lossy();
+        if name.starts_with(STAGING_PREFIX) {
+            return true;
+        }
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches_with(&name, options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_rules() {
+        let rules = HideRules::default();
+        for name in [
+            ".profile",
+            ".AppleDB",
+            "._file",
+            "Icon\r",
+            "Temporary Items",
+        ] {
+            assert!(rules.hides(OsStr::new(name)), "{name:?} must be hidden");
+        }
+        for name in ["Icon", "file.txt", ":2eprofile"] {
+            assert!(!rules.hides(OsStr::new(name)), "{name:?} must be shown");
+        }
+    }
+
+    #[test]
+    fn test_custom_rules() {
+        let rules = HideRules::new(&["*.bak"]).unwrap();
+        assert!(rules.hides(OsStr::new("notes.bak")));
+        assert!(rules.hides(OsStr::new(".AppleDouble")));
+        assert!(rules.hides(OsStr::new(".upload-0000abcd")));
+        assert!(!rules.hides(OsStr::new(".profile")));
+    }
+}
@@ -0,0 +1,113 @@
+//! Private folders of user accounts.
+
+use std::path::{Component, Path, PathBuf};
+
+use super::names;
+use crate::server::application::UserAccount;
+
+/// Compares paths by the Mac names clients use for them, since that is how
+/// [`super::OsFiles`] looks up components which do not exist verbatim.
+fn mac_components(path: &Path) -> Vec<Vec<u8>> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(names::host_to_mac(name)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(super) fn contains(home: &Path, path: &Path) -> bool {
+    let (home, path) = (mac_components(home), mac_components(path));
+    path.starts_with(&home)
+}
+
+/// The home folders of all accounts, which keeps each account out of the
+/// others' homes unless it is an administrator.
+#[derive(Debug, Clone, Default)]
+pub struct HomeFolders {
+    homes: Vec<(String, PathBuf)>,
+}
+
+impl HomeFolders {
+    pub fn new<'a>(accounts: impl IntoIterator<Item = &'a UserAccount>) -> Self {
+        let homes = accounts
+            .into_iter()
+            .filter_map(|account| {
+                let home = account.home.as_ref()?;
+                Some((account.identity.login.clone(), home.path.clone()))
+            })
+            .collect();
+        Self { homes }
+    }
+    /// Maps a path as seen by `account` onto the file area, which only
+    /// changes it for accounts whose home is their root.
+    pub fn resolve(account: Option<&UserAccount>, path: &Path) -> PathBuf {
+        match account.and_then(|account| account.home.as_ref()) {
+            Some(home) if home.is_root => home.path.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+    /// Whether `path` is within the home folder of any account.
+    pub fn is_private(&self, path: &Path) -> bool {
+        self.homes.iter().any(|(_, home)| contains(home, path))
+    }
+    /// Whether `account` may reach `path`, a path within the file area.
+    pub fn allows(&self, account: Option<&UserAccount>, path: &Path) -> bool {
+        if account.is_some_and(UserAccount::is_admin) {
+            return true;
+        }
+        let login = account.map(|account| account.identity.login.as_str());
+        self.homes
+            .iter()
+            .filter(|(_, home)| contains(home, path))
+            .all(|(owner, _)| Some(owner.as_str()) == login)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::application::{
+        HomeFolder, UserAccountIdentity, UserAccountPermissions, UserOperation,
+    };
+
+    fn account(login: &str, home: Option<&str>, is_root: bool) -> UserAccount {
+        UserAccount {
+            identity: UserAccountIdentity {
+                login: login.into(),
+                ..Default::default()
+            },
+            home: home.map(|path| HomeFolder {
+                path: path.into(),
+                is_root,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_homes_are_private() {
+        let alice = account("alice", Some("Homes/alice"), false);
+        let bob = account("bob", Some("Homes/bob"), true);
+        let mut admin = account("admin", None, false);
+        admin.permissions = UserAccountPermissions {
+            user: [UserOperation::CanDisconnectUsers].into_iter().collect(),
+            ..Default::default()
+        };
+        let homes = HomeFolders::new([&alice, &bob, &admin]);
+
+        let file = Path::new("Homes/alice/notes.txt");
+        assert!(homes.allows(Some(&alice), file));
+        assert!(homes.allows(Some(&admin), file));
+        assert!(!homes.allows(Some(&bob), file));
+        assert!(!homes.allows(None, file));
+        assert!(homes.allows(Some(&bob), Path::new("Homes")));
+        assert!(homes.allows(None, Path::new("Public/file")));
+
+        let resolved = HomeFolders::resolve(Some(&bob), Path::new("file"));
+        assert_eq!(resolved, Path::new("Homes/bob/file"));
+        assert!(homes.allows(Some(&bob), &resolved));
+        let resolved = HomeFolders::resolve(Some(&alice), Path::new("file"));
+        assert_eq!(resolved, Path::new("file"));
+    }
+}
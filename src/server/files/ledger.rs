@@ -0,0 +1,88 @@
+//! Records kept in memory and saved to a TOML file after each change, such
+//! as the uploads charged to quotas and the uploads waiting for moderators.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::fs;
+use tracing::error;
+
+/// A ledger shared by its clones.
+#[derive(Debug, Default)]
+pub struct TomlLedger<T> {
+    entries: Arc<Mutex<T>>,
+    /// Where to save the ledger, or nowhere for a ledger kept in memory.
+    path: Option<PathBuf>,
+}
+
+impl<T> Clone for TomlLedger<T> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            path: self.path.clone(),
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Default> TomlLedger<T> {
+    /// Reads the ledger at `path`, starting an empty one if it is missing.
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let entries = if fs::try_exists(path).await? {
+            toml::from_str(&fs::read_to_string(path).await?)?
+        } else {
+            T::default()
+        };
+        Ok(Self {
+            entries: Arc::new(Mutex::new(entries)),
+            path: Some(path.to_path_buf()),
+        })
+    }
+    /// An empty ledger which is never saved.
+    pub fn in_memory() -> Self {
+        Self {
+            entries: Default::default(),
+            path: None,
+        }
+    }
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.entries.lock().unwrap()
+    }
+    /// Writes the ledger to its file, logging any failure, since the
+    /// change it records has already been made.
+    pub async fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let data = toml::to_string(&*self.lock());
+        let result = match data {
+            Ok(data) => fs::write(path, data).await.map_err(Into::into),
+            Err(e) => Err(anyhow::Error::from(e)),
+        };
+        if let Err(e) = result {
+            error!("failed to save ledger {path:?}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::files::scratch::scratch_dir;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_saved_ledger_is_loaded() -> anyhow::Result<()> {
+        let dir = scratch_dir("ledger")?;
+        let path = dir.join("ledger.toml");
+        let ledger = TomlLedger::<BTreeMap<String, u64>>::load(&path).await?;
+        assert!(ledger.lock().is_empty());
+        ledger.clone().lock().insert("file".into(), 4);
+        ledger.save().await;
+
+        let loaded = TomlLedger::<BTreeMap<String, u64>>::load(&path).await?;
+        assert_eq!(loaded.lock().get("file"), Some(&4));
+        Ok(())
+    }
+}
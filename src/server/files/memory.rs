@@ -0,0 +1,464 @@
+//! A file area kept entirely in memory, so that listing and transfer logic
+//! can be exercised without touching disk.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Cursor, ErrorKind},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use futures::FutureExt as _;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite};
+
+use super::{
+    checksum::Checksum, file_dates, finder_info, info_fork, names, Creator, DirEntry, FileInfo,
+    FileType,
+};
+use crate::{
+    apple,
+    protocol::{self as proto, AsyncDataSource, FlattenedFileObject},
+    server::application::{Files, FilesFuture},
+};
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// The forks of a file, or `None` for a directory.
+    forks: Option<Forks>,
+    finder_info: apple::FinderInfo,
+    comment: Vec<u8>,
+    checksum: Option<Checksum>,
+    created_at: SystemTime,
+    modified_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Forks {
+    data: Vec<u8>,
+    rsrc: Vec<u8>,
+}
+
+impl Node {
+    fn new(forks: Option<Forks>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            forks,
+            finder_info: apple::FinderInfo::windows_file(),
+            comment: vec![],
+            checksum: None,
+            created_at: now,
+            modified_at: now,
+        }
+    }
+}
+
+/// A [`Files`] implementation backed by a map of paths to nodes.
+///
+/// Clones share the same contents.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFiles {
+    nodes: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+}
+
+impl MemoryFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Creates the directory `path`, whose parent must exist.
+    pub fn create_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = Self::normalize(path.as_ref())?;
+        let mut nodes = self.nodes.lock().unwrap();
+        Self::require_parent(&nodes, &path)?;
+        if path.as_os_str().is_empty() || nodes.contains_key(&path) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        nodes.insert(path, Node::new(None));
+        Ok(())
+    }
+    /// Creates or replaces the file `path` with the data fork `data`.
+    pub fn insert_file<P: AsRef<Path>>(&self, path: P, data: impl Into<Vec<u8>>) -> io::Result<()> {
+        let path = Self::normalize(path.as_ref())?;
+        let mut nodes = self.nodes.lock().unwrap();
+        Self::require_parent(&nodes, &path)?;
+        let forks = Forks {
+            data: data.into(),
+            ..Default::default()
+        };
+        nodes.insert(path, Node::new(Some(forks)));
+        Ok(())
+    }
+    /// The data fork of the file `path`.
+    pub fn data<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        let nodes = self.nodes.lock().unwrap();
+        let forks = nodes.get(path.as_ref())?.forks.as_ref()?;
+        Some(forks.data.clone())
+    }
+    /// The resource fork of the file `path`.
+    pub fn rsrc<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        let nodes = self.nodes.lock().unwrap();
+        let forks = nodes.get(path.as_ref())?.forks.as_ref()?;
+        Some(forks.rsrc.clone())
+    }
+    fn normalize(path: &Path) -> io::Result<PathBuf> {
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => normalized.push(name),
+                Component::CurDir | Component::RootDir => {}
+                _ => return Err(ErrorKind::InvalidInput.into()),
+            }
+        }
+        Ok(normalized)
+    }
+    fn is_dir(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> bool {
+        path.as_os_str().is_empty() || nodes.get(path).is_some_and(|node| node.forks.is_none())
+    }
+    fn require_parent(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if Self::is_dir(nodes, parent) => Ok(()),
+            _ => Err(ErrorKind::NotFound.into()),
+        }
+    }
+    fn children<'a>(
+        nodes: &'a BTreeMap<PathBuf, Node>,
+        directory: &'a Path,
+    ) -> impl Iterator<Item = (&'a PathBuf, &'a Node)> {
+        nodes
+            .iter()
+            .filter(move |(path, _)| path.parent() == Some(directory))
+    }
+    /// Runs `f` on the file `path`, creating an empty one first if needed.
+    fn with_file<T>(&self, path: &Path, f: impl FnOnce(&mut Node) -> T) -> io::Result<T> {
+        let path = Self::normalize(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if !nodes.contains_key(&path) {
+            Self::require_parent(&nodes, &path)?;
+        }
+        let node = nodes
+            .entry(path)
+            .or_insert_with(|| Node::new(Some(Forks::default())));
+        if node.forks.is_none() {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        Ok(f(node))
+    }
+    fn list_sync(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let path = Self::normalize(path)?;
+        let nodes = self.nodes.lock().unwrap();
+        if !Self::is_dir(&nodes, &path) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        let entries = Self::children(&nodes, &path)
+            .map(|(child, node)| {
+                let (type_code, creator_code, item_count) = match node.forks {
+                    Some(_) => (
+                        FileType((&node.finder_info.file_type.0 .0).into()),
+                        Creator((&node.finder_info.creator.0 .0).into()),
+                        None,
+                    ),
+                    None => {
+                        let count = Self::children(&nodes, child).count() as u32;
+                        (FileType::directory(), Creator::of_directory(), Some(count))
+                    }
+                };
+                let forks = node.forks.clone().unwrap_or_default();
+                DirEntry {
+                    path: child.clone(),
+                    data_len: forks.data.len() as u64,
+                    rsrc_len: forks.rsrc.len() as u64,
+                    type_code,
+                    creator_code,
+                    item_count,
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+    fn get_info_sync(&self, path: &Path) -> io::Result<FileInfo> {
+        let path = Self::normalize(path)?;
+        let parent = path.parent().ok_or(ErrorKind::InvalidInput)?;
+        self.list_sync(parent)?
+            .into_iter()
+            .find(|entry| entry.path == path)
+            .map(|entry| {
+                let nodes = self.nodes.lock().unwrap();
+                let node = &nodes[&path];
+                FileInfo {
+                    path: entry.path,
+                    data_len: entry.data_len,
+                    rsrc_len: entry.rsrc_len,
+                    file_type: entry.type_code,
+                    creator: entry.creator_code,
+                    comment: node.comment.clone(),
+                    created_at: node.created_at,
+                    modified_at: node.modified_at,
+                    item_count: entry.item_count,
+                    checksum: node.checksum,
+                }
+            })
+            .ok_or(ErrorKind::NotFound.into())
+    }
+    fn read_sync(&self, path: &Path) -> io::Result<FlattenedFileObject> {
+        let path = Self::normalize(path)?;
+        let nodes = self.nodes.lock().unwrap();
+        let node = nodes.get(&path).ok_or(ErrorKind::NotFound)?;
+        let Some(Forks { data, rsrc }) = node.forks.clone() else {
+            return Err(ErrorKind::IsADirectory.into());
+        };
+        let info = info_fork(
+            &path,
+            proto::PlatformType::AppleMac,
+            node.finder_info,
+            node.comment.clone(),
+            (node.created_at, node.modified_at),
+        )?;
+        let data = AsyncDataSource::new(data.len() as u64, Cursor::new(data));
+        let file = if rsrc.is_empty() {
+            FlattenedFileObject::with_data(info, data)
+        } else {
+            let rsrc = AsyncDataSource::new(rsrc.len() as u64, Cursor::new(rsrc));
+            FlattenedFileObject::with_forks(info, data, rsrc)
+        };
+        Ok(file)
+    }
+    fn rename_sync(&self, path: &Path, new_name: &[u8]) -> io::Result<()> {
+        let path = Self::normalize(path)?;
+        if path.as_os_str().is_empty() || new_name.is_empty() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let new_path = path.with_file_name(names::mac_to_host(new_name));
+        if new_path == path {
+            return Ok(());
+        }
+        let mut nodes = self.nodes.lock().unwrap();
+        if !nodes.contains_key(&path) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if nodes.contains_key(&new_path) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        let moved = nodes
+            .keys()
+            .filter(|key| key.starts_with(&path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for old in moved {
+            let node = nodes.remove(&old).unwrap();
+            let suffix = old.strip_prefix(&path).unwrap();
+            nodes.insert(new_path.join(suffix), node);
+        }
+        Ok(())
+    }
+}
+
+impl Files for MemoryFiles {
+    fn list<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Vec<DirEntry>> {
+        futures::future::ready(self.list_sync(path)).boxed()
+    }
+    fn get_info<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, FileInfo> {
+        futures::future::ready(self.get_info_sync(path)).boxed()
+    }
+    fn read<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, FlattenedFileObject> {
+        futures::future::ready(self.read_sync(path)).boxed()
+    }
+    fn write<'a>(
+        &'a self,
+        path: &'a Path,
+        offset: u64,
+    ) -> FilesFuture<'a, Box<dyn AsyncWrite + Unpin + Send>> {
+        let writer = self
+            .with_file(path, |node| {
+                let forks = node.forks.as_mut().unwrap();
+                if offset == 0 {
+                    forks.data.clear();
+                }
+                (offset as usize) <= forks.data.len()
+            })
+            .and_then(|in_range| {
+                if !in_range {
+                    return Err(ErrorKind::InvalidInput.into());
+                }
+                let writer = MemoryWriter {
+                    nodes: self.nodes.clone(),
+                    path: Self::normalize(path)?,
+                    position: offset as usize,
+                };
+                Ok(Box::new(writer) as Box<dyn AsyncWrite + Unpin + Send>)
+            });
+        futures::future::ready(writer).boxed()
+    }
+    fn write_metadata<'a>(
+        &'a self,
+        path: &'a Path,
+        info: &'a proto::InfoFork,
+        fork: &'a mut (dyn AsyncRead + Unpin + Send),
+        len: u64,
+    ) -> FilesFuture<'a, ()> {
+        async move {
+            let mut rsrc = Vec::with_capacity(len as usize);
+            fork.take(len).read_to_end(&mut rsrc).await?;
+            let dates = file_dates(info);
+            self.with_file(path, |node| {
+                node.forks.as_mut().unwrap().rsrc = rsrc;
+                node.finder_info = finder_info(info);
+                node.comment = info.comment.clone();
+                node.checksum = None;
+                if let Some(created_at) = dates.created_at() {
+                    node.created_at = created_at;
+                }
+                if let Some(modified_at) = dates.modified_at() {
+                    node.modified_at = modified_at;
+                }
+            })
+        }
+        .boxed()
+    }
+    fn set_times<'a>(&'a self, path: &'a Path, info: &'a proto::InfoFork) -> FilesFuture<'a, ()> {
+        let result = match SystemTime::try_from(info.modified_at) {
+            Ok(modified_at) => self.with_file(path, |node| node.modified_at = modified_at),
+            Err(_) => Ok(()),
+        };
+        futures::future::ready(result).boxed()
+    }
+    fn set_comment<'a>(&'a self, path: &'a Path, comment: Vec<u8>) -> FilesFuture<'a, ()> {
+        let result = Self::normalize(path).and_then(|path| {
+            let mut nodes = self.nodes.lock().unwrap();
+            let node = nodes.get_mut(&path).ok_or(ErrorKind::NotFound)?;
+            node.comment = comment;
+            Ok(())
+        });
+        futures::future::ready(result).boxed()
+    }
+    fn set_checksum<'a>(&'a self, path: &'a Path, checksum: Checksum) -> FilesFuture<'a, ()> {
+        let result = self.with_file(path, |node| node.checksum = Some(checksum));
+        futures::future::ready(result).boxed()
+    }
+    fn rename<'a>(&'a self, path: &'a Path, new_name: &'a [u8]) -> FilesFuture<'a, ()> {
+        futures::future::ready(self.rename_sync(path, new_name)).boxed()
+    }
+    fn remove<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, ()> {
+        let result = Self::normalize(path).and_then(|path| {
+            let mut nodes = self.nodes.lock().unwrap();
+            match nodes.get(&path) {
+                Some(node) if node.forks.is_some() => {
+                    nodes.remove(&path);
+                    Ok(())
+                }
+                Some(_) => Err(ErrorKind::IsADirectory.into()),
+                None => Err(ErrorKind::NotFound.into()),
+            }
+        });
+        futures::future::ready(result).boxed()
+    }
+    fn replace<'a>(&'a self, from: &'a Path, to: &'a Path, replace: bool) -> FilesFuture<'a, ()> {
+        let result = Self::normalize(from).and_then(|from| {
+            let to = Self::normalize(to)?;
+            let mut nodes = self.nodes.lock().unwrap();
+            if Self::is_dir(&nodes, &to) {
+                return Err(ErrorKind::IsADirectory.into());
+            }
+            if !replace && nodes.contains_key(&to) {
+                return Err(ErrorKind::AlreadyExists.into());
+            }
+            let node = nodes.remove(&from).ok_or(ErrorKind::NotFound)?;
+            nodes.insert(to, node);
+            Ok(())
+        });
+        futures::future::ready(result).boxed()
+    }
+    fn available_space<'a>(&'a self, _: &'a Path) -> FilesFuture<'a, Option<u64>> {
+        futures::future::ready(Ok(None)).boxed()
+    }
+    fn host_path<'a>(&'a self, _: &'a Path) -> FilesFuture<'a, Option<PathBuf>> {
+        futures::future::ready(Ok(None)).boxed()
+    }
+}
+
+/// Writes into the data fork of a [`MemoryFiles`] file as bytes arrive.
+struct MemoryWriter {
+    nodes: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+    path: PathBuf,
+    position: usize,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let Some(forks) = nodes
+            .get_mut(&self.path)
+            .and_then(|node| node.forks.as_mut())
+        else {
+            return Poll::Ready(Err(ErrorKind::NotFound.into()));
+        };
+        let end = self.position + buf.len();
+        if forks.data.len() < end {
+            forks.data.resize(end, 0);
+        }
+        forks.data[self.position..end].copy_from_slice(buf);
+        drop(nodes);
+        self.position = end;
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt as _;
+
+    #[tokio::test]
+    async fn test_list_and_info() -> io::Result<()> {
+        let files = MemoryFiles::new();
+        files.create_dir("Uploads")?;
+        files.insert_file("Uploads/notes.txt", "hello")?;
+        files.insert_file("README", "read me")?;
+
+        let mut root = files.list(Path::new("")).await?;
+        root.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(root.len(), 2);
+        assert_eq!(root[0].path, Path::new("README"));
+        assert_eq!(root[0].data_len, 7);
+        assert_eq!(root[1].item_count, Some(1));
+        assert_eq!(root[1].type_code.bytes(), b"fldr");
+
+        let info = files.get_info(Path::new("Uploads/notes.txt")).await?;
+        assert_eq!(info.data_len, 5);
+        assert_eq!(info.item_count, None);
+
+        let missing = files.get_info(Path::new("Uploads/missing")).await;
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_and_rename() -> io::Result<()> {
+        let files = MemoryFiles::new();
+        files.create_dir("Folder")?;
+        let mut writer = files.write(Path::new("Folder/file"), 0).await?;
+        writer.write_all(b"hello").await?;
+        let mut writer = files.write(Path::new("Folder/file"), 5).await?;
+        writer.write_all(b", world").await?;
+        assert_eq!(files.data("Folder/file").unwrap(), b"hello, world");
+
+        files.rename(Path::new("Folder"), b"Renamed").await?;
+        assert!(files.data("Folder/file").is_none());
+        assert_eq!(files.data("Renamed/file").unwrap(), b"hello, world");
+
+        let orphan = files.write(Path::new("Missing/file"), 0).await;
+        assert!(orphan.is_err());
+        Ok(())
+    }
+}
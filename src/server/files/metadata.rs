@@ -0,0 +1,452 @@
+//! Storage of Macintosh metadata (Finder info, comments and resource forks)
+//! alongside plain files on the host.
+
+use super::checksum::Checksum;
+use crate::apple;
+use deku::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, prelude::*, ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+};
+use strum::{Display, EnumString};
+use tracing::debug;
+
+pub const XATTR_FINDER_INFO: &str = "user.com.apple.FinderInfo";
+pub const XATTR_RESOURCE_FORK: &str = "user.com.apple.ResourceFork";
+pub const XATTR_COMMENT: &str = "user.neolith.Comment";
+pub const XATTR_FILE_DATES: &str = "user.neolith.FileDates";
+pub const XATTR_SHA256: &str = "user.neolith.Sha256";
+
+const APPLEDOUBLE_PREFIX: &str = "._";
+const NETATALK_DIRECTORY: &str = ".AppleDouble";
+
+/// Where the metadata of the files below a root is kept.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display, EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum MetadataStorage {
+    /// `._name` AppleDouble files next to each file.
+    #[default]
+    AppleDouble,
+    /// `.AppleDouble/name` AppleDouble files in a hidden folder next to each
+    /// file, as written by netatalk 2.
+    Netatalk,
+    /// `user.com.apple.*` extended attributes on each file, as used by
+    /// netatalk and by macOS on Linux shares.
+    ///
+    /// Most Linux filesystems limit attribute values to 64 KiB, so large
+    /// resource forks cannot be stored this way.
+    ExtendedAttributes,
+}
+
+/// The metadata of a single file, independent of how it is stored.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Sidecar {
+    pub finder_info: Option<apple::FinderInfo>,
+    pub comment: Vec<u8>,
+    pub dates: Option<apple::FileDatesInfo>,
+    /// The checksum of the data fork as it was uploaded.
+    pub checksum: Option<Checksum>,
+    pub resource_fork: Vec<u8>,
+}
+
+impl Sidecar {
+    pub fn is_empty(&self) -> bool {
+        self.finder_info.is_none()
+            && self.comment.is_empty()
+            && self.dates.is_none()
+            && self.checksum.is_none()
+            && self.resource_fork.is_empty()
+    }
+}
+
+/// Finds the AppleDouble file holding the metadata of a file.
+pub trait SidecarLocator {
+    fn sidecar_path(&self, path: &Path) -> PathBuf;
+    /// Whether a directory entry called `name` belongs to this layout and
+    /// must be kept out of listings.
+    fn is_sidecar(&self, name: &OsStr) -> bool;
+}
+
+/// `._name` next to `name`, as written by macOS on foreign filesystems.
+#[derive(Debug, Clone, Copy)]
+pub struct DotUnderscore;
+
+impl SidecarLocator for DotUnderscore {
+    fn sidecar_path(&self, path: &Path) -> PathBuf {
+        let basename = path.file_name().and_then(|p| p.to_str()).unwrap();
+        let appledouble_basename = format!("{APPLEDOUBLE_PREFIX}{basename}");
+        Path::join(path.parent().unwrap(), appledouble_basename)
+    }
+    fn is_sidecar(&self, name: &OsStr) -> bool {
+        name.to_str()
+            .is_some_and(|name| name.starts_with(APPLEDOUBLE_PREFIX))
+    }
+}
+
+/// `.AppleDouble/name` next to `name`, as written by netatalk 2.
+#[derive(Debug, Clone, Copy)]
+pub struct NetatalkDirectory;
+
+impl SidecarLocator for NetatalkDirectory {
+    fn sidecar_path(&self, path: &Path) -> PathBuf {
+        let basename = path.file_name().unwrap();
+        path.parent()
+            .unwrap()
+            .join(NETATALK_DIRECTORY)
+            .join(basename)
+    }
+    fn is_sidecar(&self, name: &OsStr) -> bool {
+        name == NETATALK_DIRECTORY
+    }
+}
+
+/// Every known AppleDouble layout, whichever one is in use.
+pub const LOCATORS: [&dyn SidecarLocator; 2] = [&DotUnderscore, &NetatalkDirectory];
+
+/// Whether `name` is the sidecar of some other entry in any known layout.
+///
+/// Trees are often copied between servers using different layouts, so all of
+/// them are hidden regardless of the configured storage.
+pub fn is_sidecar(name: &OsStr) -> bool {
+    LOCATORS.iter().any(|locator| locator.is_sidecar(name))
+}
+
+impl MetadataStorage {
+    /// The layout of the AppleDouble files, or `None` if metadata is not kept
+    /// in AppleDouble files.
+    pub fn locator(&self) -> Option<&'static dyn SidecarLocator> {
+        match self {
+            Self::AppleDouble => Some(&DotUnderscore),
+            Self::Netatalk => Some(&NetatalkDirectory),
+            Self::ExtendedAttributes => None,
+        }
+    }
+    /// Reads the metadata of `path`, or `None` if it has none.
+    pub fn read(&self, path: &Path) -> io::Result<Option<Sidecar>> {
+        match self.locator() {
+            Some(locator) => Self::read_appledouble(&locator.sidecar_path(path)),
+            None => Self::read_xattrs(path),
+        }
+    }
+    /// Reads only the Finder info of `path`, skipping its resource fork.
+    pub fn read_finder_info(&self, path: &Path) -> io::Result<Option<apple::FinderInfo>> {
+        let Some(locator) = self.locator() else {
+            let finder_info = xattr::get(path, XATTR_FINDER_INFO)?;
+            return Self::parse_finder_info(&finder_info.unwrap_or_default());
+        };
+        let mut file = match fs::File::open(locator.sidecar_path(path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (_, header) = apple::AppleSingleHeader::from_reader((&mut file, 0))?;
+        let Some(entry) = header.finder_info() else {
+            return Ok(None);
+        };
+        file.seek(SeekFrom::Start(entry.offset as u64))?;
+        let (_, finder_info) = apple::FinderInfo::from_reader((&mut file, 0))?;
+        Ok(Some(finder_info))
+    }
+    pub fn write(&self, path: &Path, sidecar: &Sidecar) -> io::Result<()> {
+        match self.locator() {
+            Some(locator) => Self::write_appledouble(&locator.sidecar_path(path), sidecar),
+            None => Self::write_xattrs(path, sidecar),
+        }
+    }
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        match self.locator() {
+            Some(locator) => match fs::remove_file(locator.sidecar_path(path)) {
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                result => result,
+            },
+            None => {
+                for name in [
+                    XATTR_FINDER_INFO,
+                    XATTR_COMMENT,
+                    XATTR_FILE_DATES,
+                    XATTR_SHA256,
+                    XATTR_RESOURCE_FORK,
+                ] {
+                    if xattr::get(path, name)?.is_some() {
+                        xattr::remove(path, name)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+    fn read_appledouble(appledouble_path: &Path) -> io::Result<Option<Sidecar>> {
+        let mut file = match fs::File::open(appledouble_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (_, header) = apple::AppleSingleHeader::from_reader((&mut file, 0))?;
+        let mut read_entry = |id: apple::EntryId| -> io::Result<Vec<u8>> {
+            let Some(entry) = header.entry(id) else {
+                return Ok(vec![]);
+            };
+            file.seek(SeekFrom::Start(entry.offset as u64))?;
+            let mut data = vec![0u8; entry.length as usize];
+            file.read_exact(&mut data)?;
+            Ok(data)
+        };
+        let finder_info = read_entry(apple::EntryId::FinderInfo)?;
+        let comment = read_entry(apple::EntryId::Comment)?;
+        let dates = read_entry(apple::EntryId::FileDatesInfo)?;
+        let checksum = read_entry(apple::EntryId::Sha256)?;
+        let resource_fork = read_entry(apple::EntryId::ResourceFork)?;
+        let finder_info = Self::parse_finder_info(&finder_info)?;
+        let dates = Self::parse_dates(&dates)?;
+        Ok(Some(Sidecar {
+            finder_info,
+            comment,
+            dates,
+            checksum: Self::parse_checksum(&checksum),
+            resource_fork,
+        }))
+    }
+    fn write_appledouble(appledouble_path: &Path, sidecar: &Sidecar) -> io::Result<()> {
+        let Sidecar {
+            finder_info,
+            comment,
+            dates,
+            checksum,
+            resource_fork,
+        } = sidecar;
+        let finder_info = finder_info.unwrap_or_else(apple::FinderInfo::windows_file);
+        let mut writer = apple::AppleDoubleWriter::new(finder_info)
+            .comment(comment.clone())
+            .resource_fork_len(resource_fork.len() as u32);
+        if let Some(dates) = dates {
+            writer = writer.dates(*dates);
+        }
+        if let Some(checksum) = checksum {
+            writer = writer.sha256((*checksum).into());
+        }
+        let prefix = writer.prefix().map_err(io::Error::other)?;
+        if let Some(parent) = appledouble_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::File::create(appledouble_path)?;
+        file.write_all(&prefix)?;
+        file.write_all(resource_fork)?;
+        Ok(())
+    }
+    fn read_xattrs(path: &Path) -> io::Result<Option<Sidecar>> {
+        let Some((mut sidecar, rsrc_len)) = Self::read_xattrs_without_fork(path)? else {
+            return Ok(None);
+        };
+        if rsrc_len > 0 {
+            sidecar.resource_fork = xattr::get(path, XATTR_RESOURCE_FORK)?.unwrap_or_default();
+        }
+        Ok(Some(sidecar))
+    }
+    /// Reads the metadata kept in the extended attributes of `path`, leaving
+    /// out the resource fork, along with the length of the resource fork.
+    pub fn read_xattrs_without_fork(path: &Path) -> io::Result<Option<(Sidecar, u64)>> {
+        let finder_info = xattr::get(path, XATTR_FINDER_INFO)?;
+        let comment = xattr::get(path, XATTR_COMMENT)?;
+        let dates = xattr::get(path, XATTR_FILE_DATES)?;
+        let checksum = xattr::get(path, XATTR_SHA256)?;
+        let rsrc_len = Self::xattr_len(path, XATTR_RESOURCE_FORK)?;
+        if finder_info.is_none()
+            && comment.is_none()
+            && dates.is_none()
+            && checksum.is_none()
+            && rsrc_len.is_none()
+        {
+            return Ok(None);
+        }
+        let finder_info = Self::parse_finder_info(&finder_info.unwrap_or_default())?;
+        let dates = Self::parse_dates(&dates.unwrap_or_default())?;
+        let sidecar = Sidecar {
+            finder_info,
+            comment: comment.unwrap_or_default(),
+            dates,
+            checksum: Self::parse_checksum(&checksum.unwrap_or_default()),
+            resource_fork: vec![],
+        };
+        Ok(Some((sidecar, rsrc_len.unwrap_or(0))))
+    }
+    /// The size of the extended attribute `name` of `path`, found without
+    /// reading its value.
+    fn xattr_len(path: &Path, name: &str) -> io::Result<Option<u64>> {
+        #[cfg(target_vendor = "apple")]
+        const MISSING: rustix::io::Errno = rustix::io::Errno::NOATTR;
+        #[cfg(not(target_vendor = "apple"))]
+        const MISSING: rustix::io::Errno = rustix::io::Errno::NODATA;
+        match rustix::fs::getxattr(path, name, &mut [0u8; 0][..]) {
+            Ok(len) => Ok(Some(len as u64)),
+            Err(MISSING) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn write_xattrs(path: &Path, sidecar: &Sidecar) -> io::Result<()> {
+        let Sidecar {
+            finder_info,
+            comment,
+            dates,
+            checksum,
+            resource_fork,
+        } = sidecar;
+        if let Some(finder_info) = finder_info {
+            let finder_info = finder_info.to_bytes().map_err(io::Error::other)?;
+            xattr::set(path, XATTR_FINDER_INFO, &finder_info)?;
+        }
+        if let Some(dates) = dates {
+            let dates = dates.to_bytes().map_err(io::Error::other)?;
+            xattr::set(path, XATTR_FILE_DATES, &dates)?;
+        }
+        let checksum = checksum.map(<[u8; 32]>::from);
+        for (name, value) in [
+            (XATTR_COMMENT, &comment[..]),
+            (XATTR_SHA256, checksum.as_ref().map_or(&[][..], |c| &c[..])),
+            (XATTR_RESOURCE_FORK, &resource_fork[..]),
+        ] {
+            if !value.is_empty() {
+                xattr::set(path, name, value)?;
+            } else if xattr::get(path, name)?.is_some() {
+                xattr::remove(path, name)?;
+            }
+        }
+        Ok(())
+    }
+    fn parse_dates(data: &[u8]) -> io::Result<Option<apple::FileDatesInfo>> {
+        if data.len() < apple::FileDatesInfo::calculate_size() {
+            return Ok(None);
+        }
+        let dates = apple::FileDatesInfo::try_from(data)?;
+        Ok(Some(dates))
+    }
+    fn parse_checksum(data: &[u8]) -> Option<Checksum> {
+        let checksum: [u8; 32] = data.try_into().ok()?;
+        Some(checksum.into())
+    }
+    fn parse_finder_info(data: &[u8]) -> io::Result<Option<apple::FinderInfo>> {
+        if data.len() < apple::FinderInfo::calculate_size() {
+            return Ok(None);
+        }
+        let finder_info = apple::FinderInfo::try_from(data)?;
+        Ok(Some(finder_info))
+    }
+}
+
+/// Moves the metadata of every file below `root` from one storage layout to
+/// another, returning the number of files converted.
+pub fn convert(root: &Path, from: MetadataStorage, to: MetadataStorage) -> io::Result<u64> {
+    if from == to {
+        return Ok(0);
+    }
+    let mut converted = 0;
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if is_sidecar(&entry.file_name()) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                directories.push(path);
+                continue;
+            }
+            let Some(sidecar) = from.read(&path)? else {
+                continue;
+            };
+            debug!("converting {path:?} from {from} to {to}");
+            to.write(&path, &sidecar)?;
+            from.remove(&path)?;
+            converted += 1;
+        }
+    }
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::files::scratch::scratch_dir;
+
+    fn sidecar() -> Sidecar {
+        let mut finder_info = apple::FinderInfo::windows_file();
+        finder_info.file_type = apple::FileType(apple::FourCC(*b"SIT!"));
+        finder_info.creator = apple::Creator(apple::FourCC(*b"SIT!"));
+        Sidecar {
+            finder_info: Some(finder_info),
+            comment: b"a comment".to_vec(),
+            dates: Some(apple::FileDatesInfo {
+                created: 1,
+                modified: 2,
+                ..Default::default()
+            }),
+            checksum: Some([0x5a; 32].into()),
+            resource_fork: vec![0xa5; 300],
+        }
+    }
+
+    #[test]
+    fn test_convert_round_trip() -> io::Result<()> {
+        let root = scratch_dir("convert")?;
+        let path = root.join("archive.sit");
+        fs::write(&path, b"data fork")?;
+        MetadataStorage::AppleDouble.write(&path, &sidecar())?;
+
+        let converted = convert(
+            &root,
+            MetadataStorage::AppleDouble,
+            MetadataStorage::ExtendedAttributes,
+        )?;
+        assert_eq!(converted, 1);
+        assert!(!DotUnderscore.sidecar_path(&path).exists());
+        let read = MetadataStorage::ExtendedAttributes.read(&path)?;
+        assert_eq!(read, Some(sidecar()));
+        let (without_fork, rsrc_len) = MetadataStorage::read_xattrs_without_fork(&path)?.unwrap();
+        assert_eq!(rsrc_len, 300);
+        assert!(without_fork.resource_fork.is_empty());
+        assert_eq!(without_fork.comment, sidecar().comment);
+
+        convert(
+            &root,
+            MetadataStorage::ExtendedAttributes,
+            MetadataStorage::AppleDouble,
+        )?;
+        assert_eq!(MetadataStorage::ExtendedAttributes.read(&path)?, None);
+        assert_eq!(MetadataStorage::AppleDouble.read(&path)?, Some(sidecar()));
+        assert_eq!(fs::read(&path)?, b"data fork");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_netatalk_layout() -> io::Result<()> {
+        let root = scratch_dir("netatalk")?;
+        let path = root.join("archive.sit");
+        fs::write(&path, b"data fork")?;
+        MetadataStorage::Netatalk.write(&path, &sidecar())?;
+
+        let sidecar_path = root.join(".AppleDouble").join("archive.sit");
+        assert_eq!(NetatalkDirectory.sidecar_path(&path), sidecar_path);
+        assert!(sidecar_path.is_file());
+        assert!(is_sidecar(OsStr::new(".AppleDouble")));
+        assert!(is_sidecar(OsStr::new("._archive.sit")));
+        assert!(!is_sidecar(OsStr::new("archive.sit")));
+
+        let converted = convert(
+            &root,
+            MetadataStorage::Netatalk,
+            MetadataStorage::AppleDouble,
+        )?;
+        assert_eq!(converted, 1);
+        assert!(!sidecar_path.exists());
+        assert_eq!(MetadataStorage::AppleDouble.read(&path)?, Some(sidecar()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1291 @@
+use crate::{
+    apple,
+    protocol::{self as proto, AsyncDataSource, FlattenedFileObject},
+    server::application::{Files, FilesFuture},
+};
+use deku::prelude::*;
+use derive_more::Into;
+use four_cc::FourCC;
+use futures::{future::try_join_all, FutureExt as _};
+use magic::Cookie;
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs::{FileTimes, Metadata},
+    io::{self, prelude::*, ErrorKind, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::{fs, sync::Semaphore};
+use tracing::{trace, warn};
+
+pub mod cache;
+pub mod checksum;
+pub mod hidden;
+pub mod homes;
+pub mod ledger;
+pub mod memory;
+pub mod metadata;
+pub mod moderation;
+pub mod mounts;
+pub mod names;
+pub mod quotas;
+#[cfg(test)]
+pub mod scratch;
+pub mod types;
+
+use cache::{Cached, MetadataCache, Stamp};
+use checksum::Checksum;
+use hidden::HideRules;
+use metadata::{MetadataStorage, Sidecar};
+use types::TypeMap;
+
+#[derive(Debug, Clone)]
+pub struct FileType(FourCC);
+
+impl FileType {
+    pub fn alias() -> Self {
+        Self(b"alis".into())
+    }
+    pub fn directory() -> Self {
+        Self(b"fldr".into())
+    }
+    pub fn bytes(&self) -> &[u8; 4] {
+        &self.0 .0
+    }
+}
+
+impl Default for FileType {
+    fn default() -> Self {
+        Self(b"TEXT".into())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Creator(four_cc::FourCC);
+
+impl Creator {
+    pub fn of_alias() -> Self {
+        Self::default()
+    }
+    pub fn of_directory() -> Self {
+        Self::default()
+    }
+    pub fn bytes(&self) -> &[u8; 4] {
+        &self.0 .0
+    }
+}
+
+impl Default for Creator {
+    fn default() -> Self {
+        Self(b"\0\0\0\0".into())
+    }
+}
+
+#[derive(Debug)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    pub data_len: u64,
+    pub rsrc_len: u64,
+    pub type_code: FileType,
+    pub creator_code: Creator,
+    /// The number of visible entries of a directory.
+    pub item_count: Option<u32>,
+}
+
+impl DirEntry {
+    pub fn total_size(&self) -> u64 {
+        self.data_len + self.rsrc_len
+    }
+}
+
+impl TryFrom<DirEntry> for proto::FileNameWithInfo {
+    type Error = io::Error;
+    fn try_from(value: DirEntry) -> io::Result<Self> {
+        let file_size = match value.item_count {
+            Some(item_count) => item_count.into(),
+            None => value
+                .total_size()
+                .try_into()
+                .ok()
+                .ok_or::<Self::Error>(io::ErrorKind::FileTooLarge.into())?,
+        };
+        let DirEntry {
+            creator_code,
+            type_code,
+            path,
+            ..
+        } = value;
+        let file_name = path
+            .file_name()
+            .map(names::host_to_mac)
+            .ok_or::<Self::Error>(ErrorKind::InvalidData.into())?;
+        let file_name_size = file_name.len() as i16;
+        Ok(proto::FileNameWithInfo {
+            file_name_size,
+            file_name,
+            file_size,
+            creator: (*creator_code.bytes()).into(),
+            file_type: (*type_code.bytes()).into(),
+            name_script: 0.into(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub data_len: u64,
+    pub rsrc_len: u64,
+    pub file_type: FileType,
+    pub creator: Creator,
+    pub comment: Vec<u8>,
+    pub created_at: SystemTime,
+    pub modified_at: SystemTime,
+    /// The number of visible entries of a directory.
+    pub item_count: Option<u32>,
+    /// The checksum of the data fork taken when it was uploaded.
+    pub checksum: Option<Checksum>,
+}
+
+impl FileInfo {
+    pub fn total_size(&self) -> u64 {
+        self.data_len + self.rsrc_len
+    }
+    pub fn is_folder(&self) -> bool {
+        self.file_type.bytes() == FileType::directory().bytes()
+    }
+}
+
+impl TryFrom<(PathBuf, Metadata, ExtendedMetadata)> for FileInfo {
+    type Error = std::io::Error;
+    fn try_from(
+        (path, metadata, magic): (PathBuf, Metadata, ExtendedMetadata),
+    ) -> io::Result<Self> {
+        let ExtendedMetadata {
+            data_len,
+            rsrc_len,
+            file_type,
+            creator,
+            comment,
+            dates,
+            item_count,
+            checksum,
+        } = magic;
+        let (created_at, modified_at) = file_times(&metadata, dates);
+        Ok(Self {
+            data_len,
+            rsrc_len,
+            path,
+            modified_at,
+            created_at,
+            file_type,
+            creator,
+            comment,
+            item_count,
+            checksum,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ExtendedMetadata {
+    data_len: u64,
+    rsrc_len: u64,
+    file_type: FileType,
+    creator: Creator,
+    comment: Vec<u8>,
+    dates: Option<apple::FileDatesInfo>,
+    item_count: Option<u32>,
+    checksum: Option<Checksum>,
+}
+
+impl ExtendedMetadata {
+    pub fn directory(item_count: u32) -> Self {
+        Self {
+            data_len: 0,
+            rsrc_len: 0,
+            file_type: FileType::directory(),
+            creator: Creator::of_directory(),
+            comment: vec![],
+            dates: None,
+            item_count: Some(item_count),
+            checksum: None,
+        }
+    }
+}
+
+thread_local! {
+    static MAGIC: RefCell<Cookie<magic::cookie::Load>> = Cookie::open(magic::cookie::Flags::APPLE)
+        .or::<io::Error>(Err(ErrorKind::Other.into()))
+        .unwrap()
+        .load(&Default::default())
+        .or::<io::Error>(Err(ErrorKind::Other.into()))
+        .map(RefCell::new)
+        .unwrap();
+    static MAGIC_MIME: RefCell<Cookie<magic::cookie::Load>> = Cookie::open(magic::cookie::Flags::MIME_TYPE)
+        .or::<io::Error>(Err(ErrorKind::Other.into()))
+        .unwrap()
+        .load(&Default::default())
+        .or::<io::Error>(Err(ErrorKind::Other.into()))
+        .map(RefCell::new)
+        .unwrap();
+}
+
+#[derive(Debug, Clone)]
+pub struct OsFiles {
+    root: PathBuf,
+    storage: MetadataStorage,
+    types: Arc<TypeMap>,
+    hidden: Arc<HideRules>,
+    cache: Arc<MetadataCache>,
+    lookups: Arc<Semaphore>,
+}
+
+/// How many metadata lookups may occupy blocking threads at once.
+const MAX_BLOCKING_LOOKUPS: usize = 8;
+
+impl OsFiles {
+    pub async fn with_root<P: Into<PathBuf>>(root: P) -> io::Result<Self> {
+        let root = root.into().canonicalize()?;
+        let metadata = fs::metadata(&root).await?;
+        if metadata.is_dir() {
+            Ok(Self {
+                root,
+                storage: MetadataStorage::default(),
+                types: Default::default(),
+                hidden: Default::default(),
+                cache: Default::default(),
+                lookups: Arc::new(Semaphore::new(MAX_BLOCKING_LOOKUPS)),
+            })
+        } else {
+            Err(ErrorKind::InvalidInput.into())
+        }
+    }
+    pub fn with_metadata_storage(self, storage: MetadataStorage) -> Self {
+        Self { storage, ..self }
+    }
+    pub fn metadata_storage(&self) -> MetadataStorage {
+        self.storage
+    }
+    pub fn with_type_map(self, types: TypeMap) -> Self {
+        Self {
+            types: Arc::new(types),
+            ..self
+        }
+    }
+    pub fn with_hide_rules(self, hidden: HideRules) -> Self {
+        Self {
+            hidden: Arc::new(hidden),
+            ..self
+        }
+    }
+    /// Runs blocking filesystem and libmagic work off the async runtime.
+    async fn blocking<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self) -> io::Result<T> + Send + 'static,
+    {
+        let files = self.clone();
+        tokio::task::spawn_blocking(move || f(&files)).await?
+    }
+    /// The visibility and metadata of `path`, from the cache if neither it
+    /// nor its sidecar changed since they were last looked at.
+    ///
+    /// At most [`MAX_BLOCKING_LOOKUPS`] lookups run at the same time.
+    async fn describe(&self, path: PathBuf) -> io::Result<Cached> {
+        let permit = self
+            .lookups
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(io::Error::other)?;
+        self.blocking(move |files| {
+            let described = files.describe_blocking(&path);
+            drop(permit);
+            described
+        })
+        .await
+    }
+    fn describe_blocking(&self, path: &Path) -> io::Result<Cached> {
+        let metadata = std::fs::metadata(path)?;
+        let sidecar_path = self.storage.locator().map(|l| l.sidecar_path(path));
+        let stamp = Stamp::new(&metadata, sidecar_path.as_deref());
+        if let Some(cached) = self.cache.get(path, &stamp) {
+            return Ok(cached);
+        }
+        let visible = self.is_visible(path);
+        let info = if metadata.is_dir() {
+            let sidecar = self.storage.read(path).ok().flatten().unwrap_or_default();
+            ExtendedMetadata {
+                comment: sidecar.comment,
+                dates: sidecar.dates,
+                ..ExtendedMetadata::directory(self.item_count(path))
+            }
+        } else {
+            self.sidecar_magic(path, &metadata)
+                .or_else(|_| self.guessed_magic(path, &metadata))?
+        };
+        let cached = Cached {
+            stamp,
+            visible,
+            metadata: info,
+        };
+        self.cache.insert(path, cached.clone());
+        Ok(cached)
+    }
+    /// Whether `path` may be seen by clients, according to its name and the
+    /// invisible bit of its Finder flags.
+    fn is_visible(&self, path: &Path) -> bool {
+        if path == self.root {
+            return true;
+        }
+        let Some(name) = path.file_name() else {
+            return false;
+        };
+        if self.hidden.hides(name) {
+            return false;
+        }
+        let invisible = self
+            .storage
+            .read_finder_info(path)
+            .ok()
+            .flatten()
+            .is_some_and(|finf| finf.flags.is_invisible);
+        !invisible
+    }
+    /// Fails unless `path` and every folder between it and the root may be
+    /// seen by clients, so that naming an entry within a hidden folder does
+    /// not reach it either.
+    async fn require_visible(&self, path: &Path) -> io::Result<()> {
+        for ancestor in path
+            .ancestors()
+            .take_while(|&ancestor| ancestor != self.root)
+        {
+            if !self.describe(ancestor.to_path_buf()).await?.visible {
+                return Err(ErrorKind::NotFound.into());
+            }
+        }
+        Ok(())
+    }
+    /// Counts the entries of `directory` which [`OsFiles::list`] would return.
+    ///
+    /// A folder which can't be read counts as empty, rather than keeping its
+    /// parent from being listed.
+    fn item_count(&self, directory: &Path) -> u32 {
+        let listing = match std::fs::read_dir(directory) {
+            Ok(listing) => listing,
+            Err(e) => {
+                warn!("failed to count the entries of {directory:?}: {e}");
+                return 0;
+            }
+        };
+        let visible = listing
+            .filter_map(Result::ok)
+            .filter(|entry| self.is_visible(&entry.path()))
+            .count();
+        visible as u32
+    }
+    pub async fn list(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let path = self.resolve(path).await?;
+        self.require_visible(&path).await?;
+        let mut listing = fs::read_dir(&path).await?;
+        let mut names = HashSet::new();
+        let mut lookups = vec![];
+        while let Some(entry) = listing.next_entry().await? {
+            let name = entry.file_name();
+            if self.hidden.hides(&name) {
+                continue;
+            }
+            names.insert(name);
+            let path = entry.path();
+            lookups.push(async move {
+                let cached = self.describe(path.clone()).await?;
+                io::Result::Ok((path, cached))
+            });
+        }
+        let described = try_join_all(lookups).await?;
+        self.cache.retain(&path, &names);
+        let entries = described
+            .into_iter()
+            .filter(|(_, cached)| cached.visible)
+            .map(|(path, cached)| {
+                let ExtendedMetadata {
+                    data_len,
+                    rsrc_len,
+                    file_type: type_code,
+                    creator: creator_code,
+                    item_count,
+                    ..
+                } = cached.metadata;
+                DirEntry {
+                    path,
+                    data_len,
+                    rsrc_len,
+                    type_code,
+                    creator_code,
+                    item_count,
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+    pub async fn get_info(&self, path: &Path) -> io::Result<FileInfo> {
+        let path = self.resolve(path).await?;
+        self.require_visible(&path).await?;
+        let Cached { metadata, .. } = self.describe(path.clone()).await?;
+        let fs_metadata = fs::metadata(&path).await?;
+        (path, fs_metadata, metadata).try_into()
+    }
+    fn validate_path(path: &Path) -> io::Result<&Path> {
+        let complex = path.components().any(|p| p == Component::ParentDir);
+        if complex {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        Ok(path)
+    }
+    /// Maps a path of host names, as produced by [`names::mac_to_host`], onto
+    /// the filesystem.
+    ///
+    /// Components which do not exist verbatim are looked up by the Mac name
+    /// clients see for them, which takes care of mangled and non-canonical
+    /// names. Components which cannot be found are kept as-is so that new
+    /// files can be created.
+    async fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let Self { root, .. } = self;
+        let path = Self::validate_path(path)?;
+        let mut resolved = root.clone();
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                continue;
+            };
+            let candidate = resolved.join(name);
+            if fs::symlink_metadata(&candidate).await.is_ok() {
+                resolved = candidate;
+                continue;
+            }
+            resolved = match self.find_entry(&resolved, name).await {
+                Some(name) => resolved.join(name),
+                None => candidate,
+            };
+        }
+        Ok(resolved)
+    }
+    async fn find_entry(&self, directory: &Path, name: &OsStr) -> Option<OsString> {
+        let wanted = names::host_to_mac(name);
+        let mut listing = fs::read_dir(directory).await.ok()?;
+        while let Ok(Some(entry)) = listing.next_entry().await {
+            let candidate = entry.file_name();
+            if self.hidden.hides(&candidate) {
+                continue;
+            }
+            if names::host_to_mac(&candidate) == wanted {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+    fn sidecar_magic(&self, path: &Path, metadata: &Metadata) -> io::Result<ExtendedMetadata> {
+        match self.storage.locator() {
+            Some(locator) => self.appledouble_magic(&locator.sidecar_path(path), metadata),
+            None => self.xattr_magic(path, metadata),
+        }
+    }
+    fn appledouble_magic(
+        &self,
+        appledouble_path: &Path,
+        metadata: &Metadata,
+    ) -> io::Result<ExtendedMetadata> {
+        let mut ad_file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(false)
+            .create(false)
+            .append(false)
+            .open(appledouble_path)?;
+        let (_, header) = apple::AppleSingleHeader::from_reader((&mut ad_file, 0))?;
+        let finf = if let Some(finf_entry) = header.finder_info() {
+            ad_file.seek(SeekFrom::Start(finf_entry.offset as u64))?;
+            let (_, finf) = apple::FinderInfo::from_reader((&mut ad_file, 0))?;
+            finf
+        } else {
+            apple::FinderInfo::windows_file()
+        };
+        let comment = if let Some(comment_entry) = header.entry(apple::EntryId::Comment) {
+            ad_file.seek(SeekFrom::Start(comment_entry.offset as u64))?;
+            let len = comment_entry.length as usize;
+            let mut comment = vec![0u8; len];
+            ad_file.read_exact(&mut comment[..len])?;
+            comment
+        } else {
+            vec![]
+        };
+        let dates = if let Some(dates_entry) = header.entry(apple::EntryId::FileDatesInfo) {
+            ad_file.seek(SeekFrom::Start(dates_entry.offset as u64))?;
+            let (_, dates) = apple::FileDatesInfo::from_reader((&mut ad_file, 0))?;
+            Some(dates)
+        } else {
+            None
+        };
+        let checksum = match header.entry(apple::EntryId::Sha256) {
+            Some(entry) if entry.length == 32 => {
+                ad_file.seek(SeekFrom::Start(entry.offset as u64))?;
+                let mut checksum = [0u8; 32];
+                ad_file.read_exact(&mut checksum)?;
+                Some(checksum.into())
+            }
+            _ => None,
+        };
+        let rsrc_len = header.entry_len(apple::EntryId::ResourceFork).unwrap_or(0);
+
+        let info = ExtendedMetadata {
+            data_len: metadata.len(),
+            rsrc_len,
+            file_type: FileType((&finf.file_type.0 .0).into()),
+            creator: Creator((&finf.creator.0 .0).into()),
+            comment,
+            dates,
+            item_count: None,
+            checksum,
+        };
+        Ok(info)
+    }
+    fn xattr_magic(&self, path: &Path, metadata: &Metadata) -> io::Result<ExtendedMetadata> {
+        let (
+            Sidecar {
+                finder_info,
+                comment,
+                dates,
+                checksum,
+                ..
+            },
+            rsrc_len,
+        ) = MetadataStorage::read_xattrs_without_fork(path)?
+            .ok_or::<io::Error>(ErrorKind::NotFound.into())?;
+        let finf = finder_info.unwrap_or_else(apple::FinderInfo::windows_file);
+        let info = ExtendedMetadata {
+            data_len: metadata.len(),
+            rsrc_len,
+            file_type: FileType((&finf.file_type.0 .0).into()),
+            creator: Creator((&finf.creator.0 .0).into()),
+            comment,
+            dates,
+            item_count: None,
+            checksum,
+        };
+        Ok(info)
+    }
+    fn guessed_magic(&self, path: &Path, metadata: &Metadata) -> io::Result<ExtendedMetadata> {
+        let (file_type, creator) = self.guess_codes(path)?;
+        let info = ExtendedMetadata {
+            data_len: metadata.len(),
+            rsrc_len: 0,
+            file_type: FileType((&file_type).into()),
+            creator: Creator((&creator).into()),
+            comment: vec![],
+            dates: None,
+            item_count: None,
+            checksum: None,
+        };
+        Ok(info)
+    }
+    /// Guesses the type and creator of a file without metadata, trying the
+    /// configured mappings before libmagic's own guess.
+    fn guess_codes(&self, path: &Path) -> io::Result<([u8; 4], [u8; 4])> {
+        if let Some(mapping) = self.types.by_extension(path) {
+            return Ok(mapping.codes());
+        }
+        let mime = MAGIC_MIME
+            .with_borrow(|magic| magic.file(path))
+            .or::<io::Error>(Err(ErrorKind::Other.into()))?;
+        if let Some(mapping) = self.types.by_mime(&mime) {
+            return Ok(mapping.codes());
+        }
+        let magic = MAGIC
+            .with_borrow(|magic| magic.file(path))
+            .or::<io::Error>(Err(ErrorKind::Other.into()))?;
+        let magic = magic.as_bytes();
+        if magic.len() < 8 {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        let (creator, file_type) = (&magic[..4], &magic[4..8]);
+        Ok((file_type.try_into().unwrap(), creator.try_into().unwrap()))
+    }
+    pub fn root(&self) -> PathBuf {
+        self.root.clone()
+    }
+    pub async fn read(&self, path: &Path) -> io::Result<FlattenedFileObject> {
+        let path = self.resolve(path).await?;
+        self.require_visible(&path).await?;
+        let file = match self.storage.locator() {
+            Some(locator) => {
+                let appledouble_path = locator.sidecar_path(&path);
+                if appledouble_path.is_file() {
+                    let file = AppleDoubleFile::new(path, appledouble_path);
+                    file.read().await
+                } else {
+                    self.read_plain(path).await
+                }
+            }
+            None => {
+                if let Some(sidecar) = self.storage.read(&path)? {
+                    let file = XattrFile::new(path, sidecar);
+                    file.read().await
+                } else {
+                    self.read_plain(path).await
+                }
+            }
+        }?;
+        Ok(file)
+    }
+    async fn read_plain(&self, path: PathBuf) -> io::Result<FlattenedFileObject> {
+        let guess_path = path.clone();
+        let finder_info = self
+            .blocking(move |files| files.guess_finder_info(&guess_path))
+            .await
+            .ok()
+            .flatten();
+        PlainFile::new(path, finder_info).read().await
+    }
+    pub async fn write(
+        &self,
+        path: &Path,
+        offset: u64,
+    ) -> io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        let path = self.resolve(path).await?;
+        let file = if offset > 0 {
+            let mut file = fs::OpenOptions::new().write(true).open(path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            file
+        } else {
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .await?
+        };
+        Ok(Box::new(file))
+    }
+    /// Stores the Finder info, comment and dates from `info` along with a
+    /// resource fork of `len` bytes read from `fork`, which may be empty.
+    pub async fn write_metadata(
+        &self,
+        path: &Path,
+        info: &proto::InfoFork,
+        fork: impl AsyncRead + Unpin,
+        len: u64,
+    ) -> io::Result<()> {
+        let path = self.resolve(path).await?;
+        let finder_info = finder_info(info);
+        let comment = info.comment.as_slice();
+        let dates = file_dates(info);
+        let mut fork = fork.take(len);
+        match self.storage.locator() {
+            Some(locator) => {
+                let prefix = apple::AppleDoubleWriter::new(finder_info)
+                    .comment(comment.to_vec())
+                    .dates(dates)
+                    .resource_fork_len(len as u32)
+                    .prefix()
+                    .map_err(io::Error::other)?;
+                let appledouble_path = locator.sidecar_path(&path);
+                if let Some(parent) = appledouble_path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                let mut file = fs::File::create(appledouble_path).await?;
+                file.write_all(&prefix).await?;
+                tokio::io::copy(&mut fork, &mut file).await?;
+            }
+            None => {
+                let mut resource_fork = Vec::with_capacity(len as usize);
+                fork.read_to_end(&mut resource_fork).await?;
+                let sidecar = Sidecar {
+                    finder_info: Some(finder_info),
+                    comment: comment.to_vec(),
+                    dates: Some(dates),
+                    checksum: None,
+                    resource_fork,
+                };
+                let storage = self.storage;
+                tokio::task::spawn_blocking(move || storage.write(&path, &sidecar)).await??;
+            }
+        }
+        Ok(())
+    }
+    /// Applies the modification date from `info` to the data fork of `path`.
+    ///
+    /// Creation dates cannot be set on most hosts, so they are only kept in
+    /// the metadata written by [`OsFiles::write_metadata`].
+    pub async fn set_times(&self, path: &Path, info: &proto::InfoFork) -> io::Result<()> {
+        let Ok(modified_at) = SystemTime::try_from(info.modified_at) else {
+            return Ok(());
+        };
+        let path = self.resolve(path).await?;
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?
+            .into_std()
+            .await;
+        let times = FileTimes::new()
+            .set_accessed(modified_at)
+            .set_modified(modified_at);
+        tokio::task::spawn_blocking(move || file.set_times(times)).await?
+    }
+    /// Replaces the comment of `path`, keeping the rest of its metadata.
+    pub async fn set_comment(&self, path: &Path, comment: Vec<u8>) -> io::Result<()> {
+        let path = self.resolve(path).await?;
+        let files = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut sidecar = match files.storage.read(&path)? {
+                Some(sidecar) => sidecar,
+                None => Sidecar {
+                    finder_info: files.guess_finder_info(&path)?,
+                    ..Default::default()
+                },
+            };
+            sidecar.comment = comment;
+            files.storage.write(&path, &sidecar)
+        })
+        .await?
+    }
+    /// Records the checksum of `path`, keeping the rest of its metadata.
+    pub async fn set_checksum(&self, path: &Path, checksum: Checksum) -> io::Result<()> {
+        let path = self.resolve(path).await?;
+        let files = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut sidecar = match files.storage.read(&path)? {
+                Some(sidecar) => sidecar,
+                None => Sidecar {
+                    finder_info: files.guess_finder_info(&path)?,
+                    ..Default::default()
+                },
+            };
+            sidecar.checksum = Some(checksum);
+            files.storage.write(&path, &sidecar)
+        })
+        .await?
+    }
+    /// Renames `path` to the host name of the Mac name `new_name`, along with
+    /// its AppleDouble file.
+    pub async fn rename(&self, path: &Path, new_name: &[u8]) -> io::Result<()> {
+        let path = self.resolve(path).await?;
+        if path == self.root || new_name.is_empty() {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let new_path = path.with_file_name(names::mac_to_host(new_name));
+        if new_path == path {
+            return Ok(());
+        }
+        if fs::symlink_metadata(&new_path).await.is_ok() {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        fs::rename(&path, &new_path).await?;
+        if let Some(locator) = self.storage.locator() {
+            let from = locator.sidecar_path(&path);
+            let to = locator.sidecar_path(&new_path);
+            match fs::rename(from, to).await {
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+    /// Removes the file `path` along with its AppleDouble file.
+    pub async fn remove(&self, path: &Path) -> io::Result<()> {
+        let path = self.resolve(path).await?;
+        if let Some(locator) = self.storage.locator() {
+            match fs::remove_file(locator.sidecar_path(&path)).await {
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        fs::remove_file(path).await
+    }
+    /// Moves `from` over `to` along with its AppleDouble file, removing the
+    /// AppleDouble file of `to` if `from` has none.
+    pub async fn replace(&self, from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+        let from = self.resolve(from).await?;
+        let to = self.resolve(to).await?;
+        if to == self.root {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        if replace {
+            fs::rename(&from, &to).await?;
+        } else {
+            let (from, to) = (from.clone(), to.clone());
+            tokio::task::spawn_blocking(move || {
+                let cwd = rustix::fs::CWD;
+                rustix::fs::renameat_with(cwd, &from, cwd, &to, rustix::fs::RenameFlags::NOREPLACE)
+            })
+            .await??;
+        }
+        if let Some(locator) = self.storage.locator() {
+            let from = locator.sidecar_path(&from);
+            let to = locator.sidecar_path(&to);
+            match fs::rename(&from, &to).await {
+                Err(e) if e.kind() == ErrorKind::NotFound => match fs::remove_file(to).await {
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    result => result?,
+                },
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+    /// The space left for unprivileged users on the filesystem holding the
+    /// root.
+    pub async fn available_space(&self) -> io::Result<Option<u64>> {
+        let root = self.root.clone();
+        let stat = tokio::task::spawn_blocking(move || rustix::fs::statvfs(&root)).await??;
+        Ok(Some(stat.f_bavail.saturating_mul(stat.f_frsize)))
+    }
+    /// The Finder info to store for a file which has none yet, so that adding
+    /// metadata does not lose the type and creator guessed from its contents.
+    fn guess_finder_info(&self, path: &Path) -> io::Result<Option<apple::FinderInfo>> {
+        let metadata = std::fs::metadata(path)?;
+        if metadata.is_dir() {
+            return Ok(None);
+        }
+        let (file_type, creator) = self.guess_codes(path)?;
+        let finf = apple::FinderInfo {
+            file_type: apple::FileType(apple::FourCC(file_type)),
+            creator: apple::Creator(apple::FourCC(creator)),
+            ..apple::FinderInfo::windows_file()
+        };
+        Ok(Some(finf))
+    }
+}
+
+impl Files for OsFiles {
+    fn list<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Vec<DirEntry>> {
+        OsFiles::list(self, path).boxed()
+    }
+    fn get_info<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, FileInfo> {
+        OsFiles::get_info(self, path).boxed()
+    }
+    fn read<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, FlattenedFileObject> {
+        OsFiles::read(self, path).boxed()
+    }
+    fn write<'a>(
+        &'a self,
+        path: &'a Path,
+        offset: u64,
+    ) -> FilesFuture<'a, Box<dyn AsyncWrite + Unpin + Send>> {
+        OsFiles::write(self, path, offset).boxed()
+    }
+    fn write_metadata<'a>(
+        &'a self,
+        path: &'a Path,
+        info: &'a proto::InfoFork,
+        fork: &'a mut (dyn AsyncRead + Unpin + Send),
+        len: u64,
+    ) -> FilesFuture<'a, ()> {
+        OsFiles::write_metadata(self, path, info, fork, len).boxed()
+    }
+    fn set_times<'a>(&'a self, path: &'a Path, info: &'a proto::InfoFork) -> FilesFuture<'a, ()> {
+        OsFiles::set_times(self, path, info).boxed()
+    }
+    fn set_comment<'a>(&'a self, path: &'a Path, comment: Vec<u8>) -> FilesFuture<'a, ()> {
+        OsFiles::set_comment(self, path, comment).boxed()
+    }
+    fn set_checksum<'a>(&'a self, path: &'a Path, checksum: Checksum) -> FilesFuture<'a, ()> {
+        OsFiles::set_checksum(self, path, checksum).boxed()
+    }
+    fn rename<'a>(&'a self, path: &'a Path, new_name: &'a [u8]) -> FilesFuture<'a, ()> {
+        OsFiles::rename(self, path, new_name).boxed()
+    }
+    fn remove<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, ()> {
+        OsFiles::remove(self, path).boxed()
+    }
+    fn replace<'a>(&'a self, from: &'a Path, to: &'a Path, replace: bool) -> FilesFuture<'a, ()> {
+        OsFiles::replace(self, from, to, replace).boxed()
+    }
+    fn available_space<'a>(&'a self, _: &'a Path) -> FilesFuture<'a, Option<u64>> {
+        OsFiles::available_space(self).boxed()
+    }
+    fn host_path<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Option<PathBuf>> {
+        async move { Ok(Some(self.resolve(path).await?)) }.boxed()
+    }
+}
+
+fn finder_info(info: &proto::InfoFork) -> apple::FinderInfo {
+    let flags_bytes: u32 = info.platform_flags.into();
+    apple::FinderInfo {
+        file_type: apple::FileType(info.type_code.0.into()),
+        creator: apple::Creator(info.creator_code.0.into()),
+        flags: apple::FinderFlags::from(flags_bytes as u16),
+        location: Default::default(),
+        folder: Default::default(),
+    }
+}
+
+fn file_dates(info: &proto::InfoFork) -> apple::FileDatesInfo {
+    let encode = |time: Result<SystemTime, proto::ProtocolError>| {
+        time.map_or(apple::FileDatesInfo::UNKNOWN, apple::FileDatesInfo::encode)
+    };
+    apple::FileDatesInfo {
+        created: encode(info.created_at.try_into()),
+        modified: encode(info.modified_at.try_into()),
+        ..Default::default()
+    }
+}
+
+/// The creation and modification times of a file, preferring those recorded
+/// in its metadata over those of the host filesystem.
+fn file_times(
+    metadata: &Metadata,
+    dates: Option<apple::FileDatesInfo>,
+) -> (SystemTime, SystemTime) {
+    let modified_at = dates
+        .and_then(|dates| dates.modified_at())
+        .or_else(|| metadata.modified().ok())
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let created_at = dates
+        .and_then(|dates| dates.created_at())
+        .or_else(|| metadata.created().ok())
+        .unwrap_or(modified_at);
+    (created_at, modified_at)
+}
+
+fn info_fork(
+    path: &Path,
+    platform: proto::PlatformType,
+    finf: apple::FinderInfo,
+    comment: Vec<u8>,
+    (created_at, modified_at): (SystemTime, SystemTime),
+) -> io::Result<proto::InfoFork> {
+    let file_name = path
+        .file_name()
+        .map(names::host_to_mac)
+        .ok_or::<io::Error>(ErrorKind::InvalidInput.into())?;
+    let platform_flags = u16::from(finf.flags) as u32;
+    let fork = proto::InfoFork {
+        platform,
+        type_code: proto::FileType::from(finf.file_type),
+        creator_code: proto::Creator::from(finf.creator),
+        flags: Default::default(),
+        platform_flags: proto::PlatformFlags::from(platform_flags),
+        created_at: created_at.into(),
+        modified_at: modified_at.into(),
+        name_script: Default::default(),
+        name_len: file_name.len() as i16,
+        file_name,
+        comment_len: comment.len() as i16,
+        comment,
+    };
+    Ok(fork)
+}
+
+struct PlainFile {
+    path: PathBuf,
+    finder_info: Option<apple::FinderInfo>,
+}
+
+impl PlainFile {
+    pub fn new(path: PathBuf, finder_info: Option<apple::FinderInfo>) -> Self {
+        Self { path, finder_info }
+    }
+    async fn read_info_fork(&self) -> io::Result<proto::InfoFork> {
+        let (platform, finf) = match self.finder_info {
+            Some(finf) => (proto::PlatformType::AppleMac, finf),
+            None => (
+                proto::PlatformType::MicrosoftWin,
+                apple::FinderInfo::windows_file(),
+            ),
+        };
+        let metadata = fs::metadata(&self.path).await?;
+        let times = file_times(&metadata, None);
+        info_fork(&self.path, platform, finf, vec![], times)
+    }
+    async fn read_data_fork(&self) -> io::Result<AsyncDataSource> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        let meta = file.metadata().await?;
+        let len = meta.len() as u64;
+        AsyncDataSource::from_file(len, file).await
+    }
+    async fn read(self) -> io::Result<FlattenedFileObject> {
+        let info = self.read_info_fork().await?;
+        let data = self.read_data_fork().await?;
+        let file = FlattenedFileObject::with_data(info, data);
+        Ok(file)
+    }
+}
+
+#[derive(Into)]
+struct AppleDoubleFile {
+    path: PathBuf,
+    appledouble_path: PathBuf,
+}
+
+impl AppleDoubleFile {
+    pub fn new(path: PathBuf, appledouble_path: PathBuf) -> Self {
+        Self {
+            path,
+            appledouble_path,
+        }
+    }
+    async fn read_appledouble_header_stub(
+        mut reader: impl AsyncRead + Unpin,
+    ) -> io::Result<apple::AppleSingleHeaderStub> {
+        let mut buf = [0u8; apple::AppleSingleHeaderStub::calculate_size()];
+        reader.read_exact(&mut buf).await?;
+        let stub = apple::AppleSingleHeaderStub::try_from(&buf[..])?;
+        Ok(stub)
+    }
+    async fn seek_to(
+        mut reader: impl AsyncSeekExt + Unpin,
+        entry: apple::EntryDescriptor,
+    ) -> io::Result<()> {
+        reader.seek(SeekFrom::Start(entry.offset as u64)).await?;
+        Ok(())
+    }
+    async fn read_finf(
+        mut reader: impl AsyncRead + AsyncSeek + Unpin,
+        header: &apple::AppleSingleHeader,
+    ) -> io::Result<Option<apple::FinderInfo>> {
+        let Some(finf_entry) = header.finder_info() else {
+            return Ok(None);
+        };
+        Self::seek_to(&mut reader, finf_entry).await?;
+        let mut buf = [0u8; apple::FinderInfo::calculate_size()];
+        reader.read_exact(&mut buf).await?;
+        let finf = apple::FinderInfo::try_from(&buf[..])?;
+        Ok(Some(finf))
+    }
+    async fn read_dates(
+        mut reader: impl AsyncRead + AsyncSeek + Unpin,
+        header: &apple::AppleSingleHeader,
+    ) -> io::Result<Option<apple::FileDatesInfo>> {
+        let Some(dates_entry) = header.entry(apple::EntryId::FileDatesInfo) else {
+            return Ok(None);
+        };
+        Self::seek_to(&mut reader, dates_entry).await?;
+        let mut buf = [0u8; apple::FileDatesInfo::calculate_size()];
+        reader.read_exact(&mut buf).await?;
+        let dates = apple::FileDatesInfo::try_from(&buf[..])?;
+        Ok(Some(dates))
+    }
+    async fn read_appledouble_header(
+        mut reader: impl AsyncRead + Unpin,
+    ) -> io::Result<apple::AppleSingleHeader> {
+        let stub = Self::read_appledouble_header_stub(&mut reader).await?;
+        let mut entries = vec![];
+        for _ in 0..stub.n_descriptors {
+            let mut buf = [0u8; apple::EntryDescriptor::calculate_size()];
+            reader.read_exact(&mut buf).await?;
+            entries.push(apple::EntryDescriptor::try_from(&buf[..])?);
+        }
+        Ok(apple::AppleSingleHeader::new_double(entries))
+    }
+    async fn read_comment(
+        &self,
+        header: &apple::AppleSingleHeader,
+        mut reader: impl AsyncRead + AsyncSeek + Unpin,
+    ) -> io::Result<Vec<u8>> {
+        let Some(entry) = header.entry(apple::EntryId::Comment) else {
+            return Ok(vec![]);
+        };
+        Self::seek_to(&mut reader, entry).await?;
+        let len = entry.length as usize;
+        let mut comment = vec![0u8; len];
+        reader.read_exact(&mut comment[..len]).await?;
+        Ok(comment)
+    }
+    async fn read_info_fork(&self) -> io::Result<proto::InfoFork> {
+        let mut file = tokio::fs::File::open(&self.appledouble_path).await?;
+        let header = Self::read_appledouble_header(&mut file).await?;
+        let finf = Self::read_finf(&mut file, &header)
+            .await?
+            .unwrap_or_else(apple::FinderInfo::windows_file);
+        let comment = self.read_comment(&header, &mut file).await?;
+        let dates = Self::read_dates(&mut file, &header).await?;
+        let metadata = fs::metadata(&self.path).await?;
+        let times = file_times(&metadata, dates);
+        info_fork(
+            &self.path,
+            proto::PlatformType::AppleMac,
+            finf,
+            comment,
+            times,
+        )
+    }
+    async fn read_data_fork(&self) -> io::Result<AsyncDataSource> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        let meta = file.metadata().await?;
+        let len = meta.len() as u64;
+        AsyncDataSource::from_file(len, file).await
+    }
+    async fn read_rsrc_fork(&self) -> io::Result<Option<AsyncDataSource>> {
+        let mut file = tokio::fs::File::open(&self.appledouble_path).await?;
+        let header = Self::read_appledouble_header(&mut file).await?;
+        let Some(rsrc_entry) = header.resource_fork() else {
+            return Ok(None);
+        };
+        trace!("have rsrc entry {rsrc_entry:?}");
+        file.seek(SeekFrom::Start(rsrc_entry.offset as u64)).await?;
+        let len = rsrc_entry.length as u64;
+        Ok(Some(AsyncDataSource::from_file(len, file).await?))
+    }
+    async fn read(self) -> io::Result<FlattenedFileObject> {
+        let info = self.read_info_fork().await?;
+        let data = self.read_data_fork().await?;
+        let rsrc = self.read_rsrc_fork().await?;
+        let file = if let Some(rsrc) = rsrc {
+            FlattenedFileObject::with_forks(info, data, rsrc)
+        } else {
+            FlattenedFileObject::with_data(info, data)
+        };
+        Ok(file)
+    }
+}
+
+struct XattrFile {
+    path: PathBuf,
+    sidecar: Sidecar,
+}
+
+impl XattrFile {
+    pub fn new(path: PathBuf, sidecar: Sidecar) -> Self {
+        Self { path, sidecar }
+    }
+    async fn read_data_fork(&self) -> io::Result<AsyncDataSource> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        let meta = file.metadata().await?;
+        let len = meta.len();
+        AsyncDataSource::from_file(len, file).await
+    }
+    async fn read(self) -> io::Result<FlattenedFileObject> {
+        let data = self.read_data_fork().await?;
+        let metadata = fs::metadata(&self.path).await?;
+        let Self { path, sidecar } = self;
+        let Sidecar {
+            finder_info,
+            comment,
+            dates,
+            resource_fork,
+            ..
+        } = sidecar;
+        let finf = finder_info.unwrap_or_else(apple::FinderInfo::windows_file);
+        let times = file_times(&metadata, dates);
+        let info = info_fork(&path, proto::PlatformType::AppleMac, finf, comment, times)?;
+        let file = if resource_fork.is_empty() {
+            FlattenedFileObject::with_data(info, data)
+        } else {
+            let len = resource_fork.len() as u64;
+            let rsrc = AsyncDataSource::new(len, io::Cursor::new(resource_fork));
+            FlattenedFileObject::with_forks(info, data, rsrc)
+        };
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scratch::scratch_dir, *};
+    use std::os::unix::fs::PermissionsExt as _;
+
+    fn item_count(entries: &[DirEntry], name: &str) -> Option<u32> {
+        let entry = entries.iter().find(|entry| entry.path.ends_with(name));
+        entry.and_then(|entry| entry.item_count)
+    }
+
+    #[tokio::test]
+    async fn test_item_count_skips_hidden_entries() -> io::Result<()> {
+        let root = scratch_dir("item-count")?;
+        std::fs::create_dir_all(root.join("Folder/Subfolder"))?;
+        std::fs::write(root.join("Folder/file.txt"), b"data")?;
+        std::fs::write(root.join("Folder/.DS_Store"), b"junk")?;
+        std::fs::write(root.join("Folder/._file.txt"), b"sidecar")?;
+        let files = OsFiles::with_root(&*root).await?;
+
+        let entries = files.list(Path::new("")).await?;
+        assert_eq!(item_count(&entries, "Folder"), Some(2));
+        let info = files.get_info(Path::new("Folder")).await?;
+        assert_eq!(info.item_count, Some(2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replace_only_when_asked() -> io::Result<()> {
+        let root = scratch_dir("replace")?;
+        std::fs::write(root.join("first"), b"first")?;
+        std::fs::write(root.join("second"), b"second")?;
+        let files = OsFiles::with_root(&*root).await?;
+
+        let refused = files.replace(Path::new("second"), Path::new("first"), false);
+        let kind = refused.await.err().map(|e| e.kind());
+        assert_eq!(kind, Some(ErrorKind::AlreadyExists));
+        assert_eq!(std::fs::read(root.join("first"))?, b"first");
+
+        files
+            .replace(Path::new("second"), Path::new("third"), false)
+            .await?;
+        files
+            .replace(Path::new("third"), Path::new("first"), true)
+            .await?;
+        assert_eq!(std::fs::read(root.join("first"))?, b"second");
+        assert!(!root.join("second").exists() && !root.join("third").exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hidden_folders_hide_their_entries() -> io::Result<()> {
+        let root = scratch_dir("hidden-folders")?;
+        let folder = root.join("Invisible");
+        std::fs::create_dir_all(&folder)?;
+        std::fs::write(folder.join("file.txt"), b"data")?;
+        std::fs::create_dir_all(root.join(".git"))?;
+        std::fs::write(root.join(".git/config"), b"data")?;
+        let mut finder_info = apple::FinderInfo::windows_file();
+        finder_info.flags.is_invisible = true;
+        let sidecar = Sidecar {
+            finder_info: Some(finder_info),
+            ..Default::default()
+        };
+        MetadataStorage::AppleDouble.write(&folder, &sidecar)?;
+        let files = OsFiles::with_root(&*root).await?;
+
+        for path in ["Invisible/file.txt", ".git/config"] {
+            let path = Path::new(path);
+            let info = files.get_info(path).await;
+            assert_eq!(info.unwrap_err().kind(), ErrorKind::NotFound);
+            let read = files.read(path).await.err();
+            assert_eq!(read.map(|e| e.kind()), Some(ErrorKind::NotFound));
+        }
+        let listing = files.list(Path::new("Invisible")).await;
+        assert_eq!(listing.unwrap_err().kind(), ErrorKind::NotFound);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_folder_is_listed() -> io::Result<()> {
+        let root = scratch_dir("unreadable")?;
+        let locked = root.join("Locked");
+        std::fs::create_dir_all(&locked)?;
+        std::fs::write(locked.join("secret.txt"), b"data")?;
+        std::fs::write(root.join("file.txt"), b"data")?;
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000))?;
+        // Privileged users can read the folder regardless of its mode.
+        let readable = std::fs::read_dir(&locked).is_ok();
+        let files = OsFiles::with_root(&*root).await?;
+
+        let entries = files.list(Path::new("")).await;
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755))?;
+        let entries = entries?;
+        assert_eq!(entries.len(), 2);
+        let expected = if readable { 1 } else { 0 };
+        assert_eq!(item_count(&entries, "Locked"), Some(expected));
+        assert_eq!(files.item_count(&root.join("Missing")), 0);
+
+        Ok(())
+    }
+}
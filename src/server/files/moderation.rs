@@ -0,0 +1,212 @@
+//! Holding uploads for review by moderators.
+//!
+//! While moderation is enabled, uploads from accounts which cannot moderate
+//! land in a pending folder which only moderators see. Each pending upload
+//! is recorded in a ledger along with where it was meant to go and who sent
+//! it. Moderators approve an upload by moving it out of the pending folder
+//! and reject it by deleting it.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use super::{homes, ledger::TomlLedger};
+use crate::server::application::{Files, UserAccount};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    pub enabled: bool,
+    /// The folder of the root area holding uploads until they are approved.
+    pub folder: PathBuf,
+    /// Where the ledger of pending uploads is kept.
+    pub ledger: PathBuf,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            folder: "Pending Uploads".into(),
+            ledger: "pending.toml".into(),
+        }
+    }
+}
+
+/// An upload waiting for a moderator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingUpload {
+    /// The path the upload was sent to.
+    pub destination: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+type Ledger = BTreeMap<PathBuf, PendingUpload>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Moderation {
+    config: ModerationConfig,
+    ledger: TomlLedger<Ledger>,
+}
+
+impl Moderation {
+    /// Reads the ledger named by `config`, starting an empty one if it is
+    /// missing.
+    pub async fn load(config: ModerationConfig) -> anyhow::Result<Self> {
+        let ledger = TomlLedger::load(&config.ledger).await?;
+        Ok(Self { config, ledger })
+    }
+    /// Moderation whose ledger is never saved.
+    pub fn in_memory(config: ModerationConfig) -> Self {
+        Self {
+            config,
+            ledger: TomlLedger::in_memory(),
+        }
+    }
+    pub fn folder(&self) -> &Path {
+        &self.config.folder
+    }
+    /// Whether uploads from `account` wait for a moderator.
+    pub fn holds(&self, account: Option<&UserAccount>) -> bool {
+        self.config.enabled && !account.is_some_and(UserAccount::can_moderate_uploads)
+    }
+    /// Whether `account` may reach `path`, which only moderators may do
+    /// within the pending folder.
+    pub fn allows(&self, account: Option<&UserAccount>, path: &Path) -> bool {
+        !self.config.enabled
+            || account.is_some_and(UserAccount::can_moderate_uploads)
+            || !homes::contains(&self.config.folder, path)
+    }
+    /// The pending upload stored at `path`, if any.
+    pub fn pending(&self, path: &Path) -> Option<PendingUpload> {
+        self.ledger.lock().get(path).cloned()
+    }
+    /// Reserves a name in the pending folder for an upload to `destination`,
+    /// numbering it if the name is taken.
+    pub async fn hold(
+        &self,
+        files: &dyn Files,
+        destination: &Path,
+        account: Option<&UserAccount>,
+    ) -> io::Result<PathBuf> {
+        let name = destination.file_name().ok_or(ErrorKind::InvalidInput)?;
+        let pending = PendingUpload {
+            destination: destination.to_path_buf(),
+            account: account.map(|account| account.identity.login.clone()),
+        };
+        for n in 1.. {
+            let mut candidate = name.to_os_string();
+            if n > 1 {
+                candidate.push(OsString::from(format!(" {n}")));
+            }
+            let path = self.config.folder.join(candidate);
+            if files.get_info(&path).await.is_err() && self.reserve(&path, &pending) {
+                self.ledger.save().await;
+                return Ok(path);
+            }
+        }
+        unreachable!()
+    }
+    fn reserve(&self, path: &Path, pending: &PendingUpload) -> bool {
+        let mut ledger = self.ledger.lock();
+        if ledger.contains_key(path) {
+            return false;
+        }
+        ledger.insert(path.to_path_buf(), pending.clone());
+        true
+    }
+    /// Forgets an upload which never made it into the pending folder.
+    pub async fn release(&self, path: &Path) {
+        if self.ledger.lock().remove(path).is_some() {
+            self.ledger.save().await;
+        }
+    }
+    /// Moves the pending upload at `path` into `folder`.
+    pub async fn approve(
+        &self,
+        files: &dyn Files,
+        path: &Path,
+        folder: &Path,
+    ) -> io::Result<PathBuf> {
+        let pending = self.pending(path).ok_or(ErrorKind::NotFound)?;
+        let name = pending
+            .destination
+            .file_name()
+            .ok_or(ErrorKind::InvalidInput)?;
+        let destination = folder.join(name);
+        files.replace(path, &destination, false).await?;
+        self.release(path).await;
+        Ok(destination)
+    }
+    /// Deletes the pending upload at `path`.
+    pub async fn reject(&self, files: &dyn Files, path: &Path) -> io::Result<()> {
+        if self.pending(path).is_none() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        files.remove(path).await?;
+        self.release(path).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        application::{FileOperation, UserAccountPermissions},
+        files::memory::MemoryFiles,
+    };
+
+    #[tokio::test]
+    async fn test_approve_and_reject() -> io::Result<()> {
+        let files = MemoryFiles::new();
+        files.create_dir("Pending Uploads")?;
+        files.create_dir("Public")?;
+        files.insert_file("Pending Uploads/file", "taken")?;
+        let moderation = Moderation::in_memory(ModerationConfig {
+            enabled: true,
+            ..Default::default()
+        });
+        let moderator = UserAccount {
+            permissions: UserAccountPermissions {
+                file: [FileOperation::ModerateUploads].into_iter().collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(moderation.holds(None));
+        assert!(!moderation.holds(Some(&moderator)));
+        let pending = Path::new("Pending Uploads/file 2");
+        assert!(!moderation.allows(None, pending));
+        assert!(moderation.allows(Some(&moderator), pending));
+
+        let held = moderation
+            .hold(&files, Path::new("Public/file"), None)
+            .await?;
+        assert_eq!(held, pending);
+        files.insert_file(&held, "upload")?;
+        let approved = moderation
+            .approve(&files, &held, Path::new("Public"))
+            .await?;
+        assert_eq!(approved, Path::new("Public/file"));
+        assert_eq!(files.data("Public/file").unwrap(), b"upload");
+        assert!(moderation.pending(&held).is_none());
+
+        let held = moderation
+            .hold(&files, Path::new("Public/other"), None)
+            .await?;
+        files.insert_file(&held, "spam")?;
+        moderation.reject(&files, &held).await?;
+        assert!(files.data(&held).is_none());
+        let taken = moderation
+            .reject(&files, Path::new("Pending Uploads/file"))
+            .await;
+        assert!(taken.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+//! Virtual file areas assembled from several host directories.
+
+use futures::{future::try_join_all, FutureExt as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{checksum::Checksum, Creator, DirEntry, FileInfo, FileType};
+use crate::{
+    protocol::{self as proto, FlattenedFileObject},
+    server::application::{FileOperation, FilePermissions, Files, FilesFuture, Permissions},
+};
+
+/// A host directory shown to clients at `path`, as configured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountConfig {
+    /// Where the mount appears, such as `/Software`.
+    pub path: PathBuf,
+    /// The host directory holding its contents.
+    pub root: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+    /// The operations allowed within the mount; all of them when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<FilePermissions>,
+}
+
+#[derive(Debug)]
+pub struct Mount {
+    path: PathBuf,
+    files: Arc<dyn Files>,
+    read_only: bool,
+    permissions: Option<FilePermissions>,
+}
+
+impl Mount {
+    pub fn new<P: AsRef<Path>>(path: P, files: Arc<dyn Files>) -> Self {
+        let path = path
+            .as_ref()
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        Self {
+            path,
+            files,
+            read_only: false,
+            permissions: None,
+        }
+    }
+    pub fn read_only(self, read_only: bool) -> Self {
+        Self { read_only, ..self }
+    }
+    pub fn with_permissions(self, permissions: Option<FilePermissions>) -> Self {
+        Self {
+            permissions,
+            ..self
+        }
+    }
+    fn allows(&self, operation: FileOperation) -> bool {
+        let writes = !matches!(operation, FileOperation::Download);
+        if writes && self.read_only {
+            return false;
+        }
+        self.permissions
+            .as_ref()
+            .is_none_or(|permissions| permissions.can(operation))
+    }
+}
+
+/// Routes paths to the file area mounted at their longest matching prefix,
+/// falling back to the root area.
+///
+/// Folders leading up to a mount are made up if the root area lacks them.
+#[derive(Debug)]
+pub struct Mounts {
+    root: Arc<dyn Files>,
+    mounts: Vec<Mount>,
+}
+
+struct Route<'a> {
+    files: &'a dyn Files,
+    path: PathBuf,
+    mount: Option<&'a Mount>,
+}
+
+impl Mounts {
+    pub fn new(root: Arc<dyn Files>) -> Self {
+        Self {
+            root,
+            mounts: vec![],
+        }
+    }
+    pub fn with_mount(mut self, mount: Mount) -> Self {
+        self.mounts.push(mount);
+        self.mounts
+            .sort_by_key(|mount| std::cmp::Reverse(mount.path.components().count()));
+        self
+    }
+    fn route(&self, path: &Path) -> Route<'_> {
+        for mount in &self.mounts {
+            if let Ok(rest) = path.strip_prefix(&mount.path) {
+                return Route {
+                    files: mount.files.as_ref(),
+                    path: rest.to_path_buf(),
+                    mount: Some(mount),
+                };
+            }
+        }
+        Route {
+            files: self.root.as_ref(),
+            path: path.to_path_buf(),
+            mount: None,
+        }
+    }
+    /// Checks that `operation` may be applied to `path`, which must not be a
+    /// mount point or a folder leading up to one.
+    fn authorize(&self, path: &Path, operation: FileOperation) -> io::Result<Route<'_>> {
+        let route = self.route(path);
+        if let Some(mount) = route.mount {
+            if !mount.allows(operation) {
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+        }
+        let writes = !matches!(operation, FileOperation::Download);
+        if writes && (self.is_mount_point(path) || self.is_virtual(path)) {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        Ok(route)
+    }
+    fn is_mount_point(&self, path: &Path) -> bool {
+        self.mounts.iter().any(|mount| mount.path == path)
+    }
+    /// Whether `path` is a folder leading up to a mount.
+    fn is_virtual(&self, path: &Path) -> bool {
+        self.mounts
+            .iter()
+            .any(|mount| mount.path != path && mount.path.starts_with(path))
+    }
+    /// The names of the mounts and of the folders leading up to them which
+    /// appear in `directory`.
+    fn virtual_names(&self, directory: &Path) -> BTreeSet<OsString> {
+        self.mounts
+            .iter()
+            .filter_map(|mount| mount.path.strip_prefix(directory).ok())
+            .filter_map(|rest| rest.components().next())
+            .map(|component| component.as_os_str().to_os_string())
+            .collect()
+    }
+    async fn list_virtual(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let route = self.route(path);
+        let listed = route.files.list(&route.path).await;
+        let mut entries = match listed {
+            Err(e) if e.kind() == ErrorKind::NotFound && self.is_virtual(path) => vec![],
+            listed => listed?,
+        };
+        let names = self.virtual_names(path);
+        entries.retain(|entry| {
+            let name = entry.path.file_name().unwrap_or_default();
+            !names.contains(name)
+        });
+        let folders = names.into_iter().map(|name| async move {
+            let path = path.join(name);
+            let item_count = Files::list(self, &path).await?.len() as u32;
+            io::Result::Ok(DirEntry {
+                path,
+                data_len: 0,
+                rsrc_len: 0,
+                type_code: FileType::directory(),
+                creator_code: Creator::of_directory(),
+                item_count: Some(item_count),
+            })
+        });
+        entries.extend(try_join_all(folders).await?);
+        Ok(entries)
+    }
+    async fn get_info_virtual(&self, path: &Path) -> io::Result<FileInfo> {
+        let route = self.route(path);
+        if !self.is_mount_point(path) && !self.is_virtual(path) {
+            return route.files.get_info(&route.path).await;
+        }
+        let item_count = Some(Files::list(self, path).await?.len() as u32);
+        let info = match route.files.get_info(&route.path).await {
+            Ok(info) => FileInfo {
+                path: path.to_path_buf(),
+                item_count,
+                ..info
+            },
+            Err(_) => FileInfo {
+                path: path.to_path_buf(),
+                data_len: 0,
+                rsrc_len: 0,
+                file_type: FileType::directory(),
+                creator: Creator::of_directory(),
+                comment: vec![],
+                created_at: SystemTime::UNIX_EPOCH,
+                modified_at: SystemTime::UNIX_EPOCH,
+                item_count,
+                checksum: None,
+            },
+        };
+        Ok(info)
+    }
+    /// Picks between the file and folder flavours of an operation.
+    async fn operation_on(
+        &self,
+        path: &Path,
+        file: FileOperation,
+        folder: FileOperation,
+    ) -> io::Result<FileOperation> {
+        if self.is_mount_point(path) || self.is_virtual(path) {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        let info = self.get_info_virtual(path).await?;
+        Ok(if info.item_count.is_some() {
+            folder
+        } else {
+            file
+        })
+    }
+}
+
+impl Files for Mounts {
+    fn list<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Vec<DirEntry>> {
+        self.list_virtual(path).boxed()
+    }
+    fn get_info<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, FileInfo> {
+        self.get_info_virtual(path).boxed()
+    }
+    fn read<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, FlattenedFileObject> {
+        async move {
+            let route = self.authorize(path, FileOperation::Download)?;
+            route.files.read(&route.path).await
+        }
+        .boxed()
+    }
+    fn write<'a>(
+        &'a self,
+        path: &'a Path,
+        offset: u64,
+    ) -> FilesFuture<'a, Box<dyn AsyncWrite + Unpin + Send>> {
+        async move {
+            let route = self.authorize(path, FileOperation::UploadToFolder)?;
+            route.files.write(&route.path, offset).await
+        }
+        .boxed()
+    }
+    fn write_metadata<'a>(
+        &'a self,
+        path: &'a Path,
+        info: &'a proto::InfoFork,
+        fork: &'a mut (dyn AsyncRead + Unpin + Send),
+        len: u64,
+    ) -> FilesFuture<'a, ()> {
+        async move {
+            let route = self.authorize(path, FileOperation::UploadToFolder)?;
+            route
+                .files
+                .write_metadata(&route.path, info, fork, len)
+                .await
+        }
+        .boxed()
+    }
+    fn set_times<'a>(&'a self, path: &'a Path, info: &'a proto::InfoFork) -> FilesFuture<'a, ()> {
+        async move {
+            let route = self.authorize(path, FileOperation::UploadToFolder)?;
+            route.files.set_times(&route.path, info).await
+        }
+        .boxed()
+    }
+    fn set_comment<'a>(&'a self, path: &'a Path, comment: Vec<u8>) -> FilesFuture<'a, ()> {
+        async move {
+            let operation = self
+                .operation_on(
+                    path,
+                    FileOperation::SetFileComment,
+                    FileOperation::SetFolderComment,
+                )
+                .await?;
+            let route = self.authorize(path, operation)?;
+            route.files.set_comment(&route.path, comment).await
+        }
+        .boxed()
+    }
+    fn set_checksum<'a>(&'a self, path: &'a Path, checksum: Checksum) -> FilesFuture<'a, ()> {
+        async move {
+            let route = self.authorize(path, FileOperation::UploadToFolder)?;
+            route.files.set_checksum(&route.path, checksum).await
+        }
+        .boxed()
+    }
+    fn rename<'a>(&'a self, path: &'a Path, new_name: &'a [u8]) -> FilesFuture<'a, ()> {
+        async move {
+            let operation = self
+                .operation_on(path, FileOperation::RenameFile, FileOperation::RenameFolder)
+                .await?;
+            let route = self.authorize(path, operation)?;
+            route.files.rename(&route.path, new_name).await
+        }
+        .boxed()
+    }
+    fn remove<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, ()> {
+        async move {
+            let route = self.authorize(path, FileOperation::UploadToFolder)?;
+            route.files.remove(&route.path).await
+        }
+        .boxed()
+    }
+    fn replace<'a>(&'a self, from: &'a Path, to: &'a Path, replace: bool) -> FilesFuture<'a, ()> {
+        async move {
+            let source = self.authorize(from, FileOperation::UploadToFolder)?;
+            let route = self.authorize(to, FileOperation::UploadToFolder)?;
+            if !std::ptr::addr_eq(source.files, route.files) {
+                return Err(ErrorKind::InvalidInput.into());
+            }
+            route
+                .files
+                .replace(&source.path, &route.path, replace)
+                .await
+        }
+        .boxed()
+    }
+    fn available_space<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Option<u64>> {
+        async move {
+            let route = self.route(path);
+            route.files.available_space(&route.path).await
+        }
+        .boxed()
+    }
+    fn host_path<'a>(&'a self, path: &'a Path) -> FilesFuture<'a, Option<PathBuf>> {
+        async move {
+            let route = self.route(path);
+            route.files.host_path(&route.path).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::files::memory::MemoryFiles;
+
+    fn names(entries: &[DirEntry]) -> Vec<&Path> {
+        let mut names = entries
+            .iter()
+            .map(|entry| Path::new(entry.path.file_name().unwrap()))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn mounts() -> io::Result<(MemoryFiles, MemoryFiles, Mounts)> {
+        let root = MemoryFiles::new();
+        root.insert_file("README", "read me")?;
+        let software = MemoryFiles::new();
+        software.insert_file("App", "app")?;
+        let uploads = MemoryFiles::new();
+        let mounts = Mounts::new(Arc::new(root))
+            .with_mount(Mount::new("/Software", Arc::new(software.clone())).read_only(true))
+            .with_mount(Mount::new("/Archive/Uploads", Arc::new(uploads.clone())));
+        Ok((software, uploads, mounts))
+    }
+
+    #[tokio::test]
+    async fn test_listing() -> io::Result<()> {
+        let (_, uploads, mounts) = mounts()?;
+        uploads.insert_file("Upload", "upload")?;
+
+        let root = mounts.list(Path::new("")).await?;
+        assert_eq!(names(&root), ["Archive", "README", "Software"]);
+        let software = root
+            .iter()
+            .find(|entry| entry.path == Path::new("Software"));
+        assert_eq!(software.unwrap().item_count, Some(1));
+
+        let archive = mounts.list(Path::new("Archive")).await?;
+        assert_eq!(names(&archive), ["Uploads"]);
+        let info = mounts.get_info(Path::new("Archive/Uploads/Upload")).await?;
+        assert_eq!(info.data_len, 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_routing_and_permissions() -> io::Result<()> {
+        let (software, uploads, mounts) = mounts()?;
+
+        let denied = mounts.write(Path::new("Software/App"), 0).await;
+        assert_eq!(denied.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        let denied = mounts.rename(Path::new("Software"), b"Apps").await;
+        assert_eq!(denied.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(mounts.read(Path::new("Software/App")).await.is_ok());
+
+        mounts
+            .write(Path::new("Archive/Uploads/New"), 0)
+            .await
+            .map(drop)?;
+        assert!(uploads.data("New").is_some());
+        assert!(software.data("New").is_none());
+        Ok(())
+    }
+}
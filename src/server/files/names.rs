@@ -0,0 +1,176 @@
+//! Reversible mapping between Hotline (MacRoman) file names and host file
+//! names.
+//!
+//! Mac names are stored on the host with netatalk-style escapes: characters
+//! which cannot appear in a host file name are written as `:xx`, where `xx` is
+//! the lowercase hexadecimal value of the character. A leading `.` is escaped
+//! as well so that Mac names never turn into hidden files or `..`.
+//!
+//! Host names which cannot be represented as a Mac name, either because they
+//! contain characters outside of MacRoman or because they are too long, are
+//! presented to clients with a mangled name of the form `prefix#HASH.ext`. A
+//! mangled name cannot be reversed on its own, so [`super::OsFiles`] resolves
+//! it by comparing against the mapped names of the directory entries.
+
+use encoding_rs::MACINTOSH;
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt as _};
+
+/// The longest name, in MacRoman bytes, that is sent to clients unmangled.
+pub const MAX_MAC_NAME_LEN: usize = 31;
+
+const ESCAPE: char = ':';
+const MANGLE_MARKER: char = '#';
+const MAX_EXTENSION_LEN: usize = 6;
+
+fn needs_escape(c: char, position: usize) -> bool {
+    matches!(c, '/' | ESCAPE | '\0') || (position == 0 && c == '.')
+}
+
+/// Converts a MacRoman file name into a host file name.
+pub fn mac_to_host(mac: &[u8]) -> String {
+    let (name, _, _) = MACINTOSH.decode(mac);
+    let mut host = String::with_capacity(name.len());
+    for (position, c) in name.chars().enumerate() {
+        if needs_escape(c, position) {
+            host.push_str(&format!("{ESCAPE}{:02x}", c as u32));
+        } else {
+            host.push(c);
+        }
+    }
+    host
+}
+
+/// Converts a host file name into the MacRoman name shown to clients.
+///
+/// This never fails: names which do not survive the round trip are mangled.
+pub fn host_to_mac(host: &OsStr) -> Vec<u8> {
+    let name = unescape(&host.to_string_lossy());
+    let (mac, _, unmappable) = MACINTOSH.encode(&name);
+    if unmappable || mac.len() > MAX_MAC_NAME_LEN {
+        mangle(host, &name)
+    } else {
+        mac.into_owned()
+    }
+}
+
+fn unescape(host: &str) -> String {
+    let mut name = String::with_capacity(host.len());
+    let mut rest = host;
+    while let Some(index) = rest.find(ESCAPE) {
+        name.push_str(&rest[..index]);
+        let escaped = rest
+            .get(index + 1..index + 3)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(u8::is_ascii);
+        if let Some(byte) = escaped {
+            name.push(byte as char);
+            rest = &rest[index + 3..];
+        } else {
+            name.push(ESCAPE);
+            rest = &rest[index + 1..];
+        }
+    }
+    name.push_str(rest);
+    name
+}
+
+fn encodable(c: char) -> bool {
+    let mut buf = [0u8; 4];
+    let (_, _, unmappable) = MACINTOSH.encode(c.encode_utf8(&mut buf));
+    !unmappable && !c.is_control()
+}
+
+fn sanitize(s: &str) -> Vec<u8> {
+    let s: String = s
+        .chars()
+        .map(|c| if encodable(c) { c } else { '_' })
+        .collect();
+    MACINTOSH.encode(&s).0.into_owned()
+}
+
+/// A stable hash of the host name, so that mangled names survive restarts.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn mangle(host: &OsStr, name: &str) -> Vec<u8> {
+    let (stem, extension) = match name.rfind('.') {
+        Some(index) if index > 0 && name.len() - index <= MAX_EXTENSION_LEN => name.split_at(index),
+        _ => (name, ""),
+    };
+    let extension = sanitize(extension);
+    let suffix = format!("{MANGLE_MARKER}{:08X}", fnv1a(host.as_bytes()));
+    let room = MAX_MAC_NAME_LEN - suffix.len() - extension.len();
+    let mut prefix = sanitize(stem);
+    prefix.truncate(room);
+    [&prefix[..], suffix.as_bytes(), &extension[..]].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(mac: &[u8]) -> Vec<u8> {
+        let host = mac_to_host(mac);
+        host_to_mac(OsStr::new(&host))
+    }
+
+    #[test]
+    fn test_slash_is_escaped() {
+        assert_eq!(mac_to_host(b"System 7/8"), "System 7:2f8");
+        assert_eq!(round_trip(b"System 7/8"), b"System 7/8");
+    }
+
+    #[test]
+    fn test_escape_is_escaped() {
+        assert_eq!(mac_to_host(b"a:2fb"), "a:3a2fb");
+        assert_eq!(round_trip(b"a:2fb"), b"a:2fb");
+    }
+
+    #[test]
+    fn test_leading_dot_is_escaped() {
+        assert_eq!(mac_to_host(b".."), ":2e.");
+        assert_eq!(round_trip(b".."), b"..");
+    }
+
+    #[test]
+    fn test_macroman_round_trip() {
+        let mac = MACINTOSH.encode("R\u{e9}sum\u{e9} \u{2122}").0.into_owned();
+        assert_eq!(round_trip(&mac), mac);
+    }
+
+    #[test]
+    fn test_unencodable_name_is_mangled() {
+        let mac = host_to_mac(OsStr::new("\u{1f600} smile.txt"));
+        assert!(mac.len() <= MAX_MAC_NAME_LEN);
+        assert!(mac.starts_with(b"_ smile#"));
+        assert!(mac.ends_with(b".txt"));
+    }
+
+    #[test]
+    fn test_long_name_is_mangled() {
+        let host = "a very long file name that does not fit in HFS.sit";
+        let mac = host_to_mac(OsStr::new(host));
+        assert_eq!(mac.len(), MAX_MAC_NAME_LEN);
+        assert!(mac.ends_with(b".sit"));
+        assert_eq!(mac, host_to_mac(OsStr::new(host)));
+    }
+
+    #[test]
+    fn test_invalid_names_are_told_apart() {
+        use std::os::unix::ffi::OsStrExt as _;
+        let a = host_to_mac(OsStr::from_bytes(b"file\xff"));
+        let b = host_to_mac(OsStr::from_bytes(b"file\xfe"));
+        assert!(a.starts_with(b"file_#"));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_mangled_name_is_stable_on_host() {
+        let mac = host_to_mac(OsStr::new("\u{1f600}"));
+        assert_eq!(host_to_mac(OsStr::new(&mac_to_host(&mac))), mac);
+    }
+}
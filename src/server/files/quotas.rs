@@ -0,0 +1,274 @@
+//! Limits on the space taken by uploads.
+//!
+//! Every completed upload is recorded in a ledger, by path, along with the
+//! account which sent it and its size. Uploading over an existing path
+//! replaces its entry, so an account is charged for what it currently has
+//! stored rather than for everything it ever sent.
+//!
+//! An upload is checked against the same limits with whatever size is known
+//! at each step:
+//!
+//! - when `UploadFile` is requested, with the transfer size the transaction
+//!   declares, or nothing if it declares none, so that the client is told why
+//!   before it connects;
+//! - when the transfer connection opens, with the size of the whole
+//!   flattened file from its `TransferHandshake`;
+//! - before each fork is stored, with the fork sizes declared so far;
+//! - while the forks are copied, with the bytes actually received.
+//!
+//! A failed upload only discards what it stored itself, so a file it was
+//! about to replace is left as it was.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use super::ledger::TomlLedger;
+use crate::server::application::{Files, UserAccount};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// The most bytes all accounts together may have uploaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    /// The most bytes an account may have uploaded, unless the account sets
+    /// its own quota.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<u64>,
+    /// The free space uploads must leave on the disk holding the file area.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_free_space: Option<u64>,
+    /// Where the ledger of uploads is kept.
+    pub ledger: PathBuf,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            total: None,
+            account: None,
+            min_free_space: None,
+            ledger: "uploads.toml".into(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QuotaError {
+    #[error("This upload would exceed your quota of {limit} bytes, of which {used} are in use.")]
+    Account { limit: u64, used: u64 },
+    #[error("This upload would exceed the server's quota of {limit} bytes.")]
+    Total { limit: u64 },
+    #[error("There is not enough free disk space for this upload.")]
+    FreeSpace,
+    #[error("i/o error")]
+    IO(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Upload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    account: Option<String>,
+    size: u64,
+}
+
+type Ledger = BTreeMap<PathBuf, Upload>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Quotas {
+    config: QuotaConfig,
+    ledger: TomlLedger<Ledger>,
+}
+
+impl Quotas {
+    /// Reads the ledger named by `config`, starting an empty one if it is
+    /// missing.
+    pub async fn load(config: QuotaConfig) -> anyhow::Result<Self> {
+        let ledger = TomlLedger::load(&config.ledger).await?;
+        Ok(Self { config, ledger })
+    }
+    /// Quotas which are never saved.
+    pub fn in_memory(config: QuotaConfig) -> Self {
+        Self {
+            config,
+            ledger: TomlLedger::in_memory(),
+        }
+    }
+    fn limit(&self, account: Option<&UserAccount>) -> Option<u64> {
+        account
+            .and_then(|account| account.quota)
+            .or(self.config.account)
+    }
+    /// Bytes charged to `account` and to everyone, leaving out `path`, which
+    /// an upload is about to replace.
+    fn usage(&self, account: Option<&UserAccount>, path: &Path) -> (u64, u64) {
+        let login = account.map(|account| account.identity.login.as_str());
+        let ledger = self.ledger.lock();
+        ledger
+            .iter()
+            .filter(|(uploaded, _)| uploaded.as_path() != path)
+            .fold((0, 0), |(own, total), (_, upload)| {
+                let own = if upload.account.as_deref() == login {
+                    own + upload.size
+                } else {
+                    own
+                };
+                (own, total + upload.size)
+            })
+    }
+    /// Checks that `account` may store `size` bytes at `path`.
+    pub async fn check(
+        &self,
+        files: &dyn Files,
+        account: Option<&UserAccount>,
+        path: &Path,
+        size: u64,
+    ) -> Result<(), QuotaError> {
+        let (own, total) = self.usage(account, path);
+        if let Some(limit) = self.limit(account) {
+            if own.saturating_add(size) > limit {
+                return Err(QuotaError::Account { limit, used: own });
+            }
+        }
+        if let Some(limit) = self.config.total {
+            if total.saturating_add(size) > limit {
+                return Err(QuotaError::Total { limit });
+            }
+        }
+        if let Some(min_free_space) = self.config.min_free_space {
+            let available = files.available_space(path).await?;
+            if available.is_some_and(|available| available < size.saturating_add(min_free_space)) {
+                return Err(QuotaError::FreeSpace);
+            }
+        }
+        Ok(())
+    }
+    /// The most bytes `account` may store at `path`, or `None` if nothing
+    /// limits it.
+    pub async fn remaining(
+        &self,
+        files: &dyn Files,
+        account: Option<&UserAccount>,
+        path: &Path,
+    ) -> Result<Option<u64>, QuotaError> {
+        let (own, total) = self.usage(account, path);
+        let own = self.limit(account).map(|limit| limit.saturating_sub(own));
+        let total = self.config.total.map(|limit| limit.saturating_sub(total));
+        let space = match self.config.min_free_space {
+            Some(min_free_space) => files
+                .available_space(path)
+                .await?
+                .map(|available| available.saturating_sub(min_free_space)),
+            None => None,
+        };
+        Ok([own, total, space].into_iter().flatten().min())
+    }
+    /// Charges `account` for the `size` bytes it uploaded to `path`.
+    pub async fn record(&self, account: Option<&UserAccount>, path: &Path, size: u64) {
+        let upload = Upload {
+            account: account.map(|account| account.identity.login.clone()),
+            size,
+        };
+        self.ledger.lock().insert(path.to_path_buf(), upload);
+        self.ledger.save().await;
+    }
+    /// Moves the charge for `from`, and anything within it, to `to`.
+    pub async fn rename(&self, from: &Path, to: &Path) {
+        {
+            let mut ledger = self.ledger.lock();
+            let moved = ledger
+                .keys()
+                .filter(|path| path.starts_with(from))
+                .cloned()
+                .collect::<Vec<_>>();
+            if moved.is_empty() {
+                return;
+            }
+            for path in moved {
+                let upload = ledger.remove(&path).unwrap();
+                let rest = path.strip_prefix(from).unwrap();
+                ledger.insert(to.join(rest), upload);
+            }
+        }
+        self.ledger.save().await;
+    }
+    /// Stops charging anyone for `path`, which has been deleted.
+    pub async fn forget(&self, path: &Path) {
+        if self.ledger.lock().remove(path).is_some() {
+            self.ledger.save().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{application::UserAccountIdentity, files::memory::MemoryFiles};
+
+    fn account(login: &str, quota: Option<u64>) -> UserAccount {
+        UserAccount {
+            identity: UserAccountIdentity {
+                login: login.into(),
+                ..Default::default()
+            },
+            quota,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quotas() -> anyhow::Result<()> {
+        let files = MemoryFiles::new();
+        let quotas = Quotas::in_memory(QuotaConfig {
+            total: Some(100),
+            account: Some(50),
+            ..Default::default()
+        });
+        let alice = account("alice", None);
+        let bob = account("bob", Some(80));
+        let (alice, bob) = (Some(&alice), Some(&bob));
+
+        quotas.check(&files, alice, Path::new("a"), 50).await?;
+        quotas.record(alice, Path::new("a"), 50).await;
+        let over = quotas.check(&files, alice, Path::new("b"), 1).await;
+        assert!(matches!(
+            over,
+            Err(QuotaError::Account {
+                limit: 50,
+                used: 50
+            })
+        ));
+        quotas.check(&files, alice, Path::new("a"), 40).await?;
+        let remaining = quotas.remaining(&files, alice, Path::new("b")).await?;
+        assert_eq!(remaining, Some(0));
+        let remaining = quotas.remaining(&files, alice, Path::new("a")).await?;
+        assert_eq!(remaining, Some(50));
+
+        quotas.check(&files, bob, Path::new("c"), 50).await?;
+        quotas.record(bob, Path::new("c"), 50).await;
+        let over = quotas.check(&files, bob, Path::new("d"), 10).await;
+        assert!(matches!(over, Err(QuotaError::Total { limit: 100 })));
+        let remaining = quotas.remaining(&files, bob, Path::new("d")).await?;
+        assert_eq!(remaining, Some(0));
+        let unlimited = Quotas::in_memory(QuotaConfig::default());
+        assert_eq!(
+            unlimited.remaining(&files, bob, Path::new("d")).await?,
+            Some(80)
+        );
+        assert_eq!(
+            unlimited.remaining(&files, None, Path::new("d")).await?,
+            None
+        );
+
+        quotas.rename(Path::new("c"), Path::new("e")).await;
+        let over = quotas.check(&files, bob, Path::new("c"), 10).await;
+        assert!(matches!(over, Err(QuotaError::Total { .. })));
+        quotas.check(&files, bob, Path::new("e"), 50).await?;
+        Ok(())
+    }
+}
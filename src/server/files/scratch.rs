@@ -0,0 +1,41 @@
+//! Folders on the host for tests which need a real file system.
+
+use std::{
+    fs, io,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A folder removed with everything in it when dropped, so that it is
+/// cleaned up even when a test fails.
+#[derive(Debug)]
+pub struct ScratchDir(PathBuf);
+
+impl Deref for ScratchDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ScratchDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Creates an empty folder for the test called `name`.
+pub fn scratch_dir(name: &str) -> io::Result<ScratchDir> {
+    let dir = std::env::temp_dir().join(format!("neolith-{name}-{}", std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(ScratchDir(dir))
+}
@@ -0,0 +1,174 @@
+//! Guessing Macintosh file types and creators for files without metadata.
+
+use encoding_rs::MACINTOSH;
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::Path};
+
+/// A four character type or creator code, written in configuration files as
+/// a Mac Roman string such as `"SIT!"`.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FourCharCode(pub [u8; 4]);
+
+impl TryFrom<String> for FourCharCode {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (bytes, _, failed) = MACINTOSH.encode(&value);
+        if failed {
+            return Err(format!("{value:?} is not valid Mac Roman"));
+        }
+        let code = bytes[..]
+            .try_into()
+            .map_err(|_| format!("{value:?} is not four characters long"))?;
+        Ok(Self(code))
+    }
+}
+
+impl From<FourCharCode> for String {
+    fn from(value: FourCharCode) -> Self {
+        let (text, _) = MACINTOSH.decode_without_bom_handling(&value.0);
+        text.into_owned()
+    }
+}
+
+impl fmt::Debug for FourCharCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FourCharCode")
+            .field(&String::from(*self))
+            .finish()
+    }
+}
+
+/// Assigns a type and creator to files by extension or by MIME type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeMapping {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    #[serde(rename = "type")]
+    pub file_type: FourCharCode,
+    pub creator: FourCharCode,
+}
+
+impl TypeMapping {
+    fn extension(extension: &str, file_type: &[u8; 4], creator: &[u8; 4]) -> Self {
+        Self {
+            extension: Some(extension.to_string()),
+            mime: None,
+            file_type: FourCharCode(*file_type),
+            creator: FourCharCode(*creator),
+        }
+    }
+    fn mime(mime: &str, file_type: &[u8; 4], creator: &[u8; 4]) -> Self {
+        Self {
+            extension: None,
+            mime: Some(mime.to_string()),
+            file_type: FourCharCode(*file_type),
+            creator: FourCharCode(*creator),
+        }
+    }
+    pub fn codes(&self) -> ([u8; 4], [u8; 4]) {
+        (self.file_type.0, self.creator.0)
+    }
+}
+
+/// The mappings consulted, in order, before asking libmagic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeMap {
+    mappings: Vec<TypeMapping>,
+}
+
+impl Default for TypeMap {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl TypeMap {
+    /// Puts `mappings` ahead of the built-in defaults.
+    pub fn new(mappings: Vec<TypeMapping>) -> Self {
+        let mappings = mappings.into_iter().chain(Self::defaults()).collect();
+        Self { mappings }
+    }
+    fn defaults() -> Vec<TypeMapping> {
+        vec![
+            TypeMapping::extension("sit", b"SIT!", b"SIT!"),
+            TypeMapping::extension("sea", b"APPL", b"aust"),
+            TypeMapping::extension("hqx", b"TEXT", b"BnHq"),
+            TypeMapping::extension("cpt", b"PACT", b"CPCT"),
+            TypeMapping::extension("bin", b"BINA", b"SITx"),
+            TypeMapping::extension("zip", b"ZIP ", b"SITx"),
+            TypeMapping::extension("gz", b"Gzip", b"SITx"),
+            TypeMapping::extension("tar", b"TARF", b"SITx"),
+            TypeMapping::extension("img", b"dImg", b"dCpy"),
+            TypeMapping::extension("dsk", b"dImg", b"dCpy"),
+            TypeMapping::extension("txt", b"TEXT", b"ttxt"),
+            TypeMapping::extension("htm", b"TEXT", b"MOSS"),
+            TypeMapping::extension("html", b"TEXT", b"MOSS"),
+            TypeMapping::extension("jpg", b"JPEG", b"ogle"),
+            TypeMapping::extension("jpeg", b"JPEG", b"ogle"),
+            TypeMapping::extension("gif", b"GIFf", b"ogle"),
+            TypeMapping::extension("png", b"PNGf", b"ogle"),
+            TypeMapping::extension("pdf", b"PDF ", b"CARO"),
+            TypeMapping::extension("mp3", b"MPG3", b"TVOD"),
+            TypeMapping::extension("mov", b"MooV", b"TVOD"),
+            TypeMapping::mime("text/plain", b"TEXT", b"ttxt"),
+            TypeMapping::mime("text/html", b"TEXT", b"MOSS"),
+            TypeMapping::mime("image/jpeg", b"JPEG", b"ogle"),
+            TypeMapping::mime("image/gif", b"GIFf", b"ogle"),
+            TypeMapping::mime("image/png", b"PNGf", b"ogle"),
+            TypeMapping::mime("application/pdf", b"PDF ", b"CARO"),
+            TypeMapping::mime("application/zip", b"ZIP ", b"SITx"),
+            TypeMapping::mime("application/gzip", b"Gzip", b"SITx"),
+            TypeMapping::mime("audio/mpeg", b"MPG3", b"TVOD"),
+        ]
+    }
+    pub fn by_extension(&self, path: &Path) -> Option<&TypeMapping> {
+        let extension = path.extension()?.to_str()?;
+        self.mappings.iter().find(|mapping| {
+            mapping
+                .extension
+                .as_deref()
+                .is_some_and(|e| e.eq_ignore_ascii_case(extension))
+        })
+    }
+    pub fn by_mime(&self, mime: &str) -> Option<&TypeMapping> {
+        self.mappings.iter().find(|mapping| {
+            mapping
+                .mime
+                .as_deref()
+                .is_some_and(|m| m.eq_ignore_ascii_case(mime))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let types = TypeMap::default();
+        let sit = types.by_extension(Path::new("Archive.SIT")).unwrap();
+        assert_eq!(sit.codes(), (*b"SIT!", *b"SIT!"));
+        let png = types.by_mime("image/png").unwrap();
+        assert_eq!(png.codes(), (*b"PNGf", *b"ogle"));
+        assert!(types.by_extension(Path::new("README")).is_none());
+    }
+
+    #[test]
+    fn test_configured_mappings_win() {
+        let configured: TypeMapping =
+            toml::from_str("extension = \"txt\"\ntype = \"TEXT\"\ncreator = \"R*ch\"").unwrap();
+        let types = TypeMap::new(vec![configured]);
+        let txt = types.by_extension(Path::new("notes.txt")).unwrap();
+        assert_eq!(txt.codes(), (*b"TEXT", *b"R*ch"));
+    }
+
+    #[test]
+    fn test_invalid_code() {
+        let parsed = toml::from_str::<TypeMapping>("type = \"TEXTS\"\ncreator = \"ttxt\"");
+        assert!(parsed.is_err());
+    }
+}
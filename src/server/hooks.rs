@@ -0,0 +1,260 @@
+//! Local commands run when things happen on the server, such as scanning
+//! uploads for viruses, indexing the file area or sending notifications.
+//!
+//! Each hook is an executable given the event as JSON on its standard input.
+//! Hooks for an upload run before the upload is put into place, and any of
+//! them failing or running out of time discards it.
+
+use encoding_rs::MACINTOSH;
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt as _, process::Command};
+use tracing::{debug, error, warn};
+
+use crate::{protocol::UserNameWithInfo, server::application::UserAccount};
+
+/// The executables to run for each event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// How long a hook may run before it is killed, in seconds.
+    pub timeout: u64,
+    /// Run before an upload is put into place, which they may refuse by
+    /// failing.
+    pub upload_complete: Vec<PathBuf>,
+    pub download_complete: Vec<PathBuf>,
+    pub login: Vec<PathBuf>,
+    pub logout: Vec<PathBuf>,
+    pub news_post: Vec<PathBuf>,
+    /// Run when an account is created or edited with the account tools.
+    pub account_change: Vec<PathBuf>,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 30,
+            upload_complete: vec![],
+            download_complete: vec![],
+            login: vec![],
+            logout: vec![],
+            news_post: vec![],
+            account_change: vec![],
+        }
+    }
+}
+
+/// What a hook is told about.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    UploadComplete {
+        /// Where the file will be put in the file area.
+        path: PathBuf,
+        /// Where the file is on the host until it is put into place, if it
+        /// is on the host at all.
+        host_path: Option<PathBuf>,
+        login: Option<String>,
+        size: u64,
+        sha256: Option<String>,
+    },
+    DownloadComplete {
+        path: PathBuf,
+        login: Option<String>,
+    },
+    Login {
+        user_id: i16,
+        nickname: String,
+    },
+    Logout {
+        user_id: i16,
+        nickname: String,
+    },
+    NewsPost {
+        article: String,
+    },
+    AccountChange {
+        login: String,
+        name: String,
+        /// Whether the account is new rather than edited.
+        created: bool,
+    },
+}
+
+impl Event {
+    pub fn login(user: &UserNameWithInfo) -> Self {
+        Self::Login {
+            user_id: user.user_id.into(),
+            nickname: user.username.to_string(),
+        }
+    }
+    pub fn logout(user: &UserNameWithInfo) -> Self {
+        Self::Logout {
+            user_id: user.user_id.into(),
+            nickname: user.username.to_string(),
+        }
+    }
+    pub fn news_post(article: &[u8]) -> Self {
+        let (article, _, _) = MACINTOSH.decode(article);
+        Self::NewsPost {
+            article: article.replace('\r', "\n"),
+        }
+    }
+    pub fn account_change(account: &UserAccount, created: bool) -> Self {
+        Self::AccountChange {
+            login: account.identity.login.clone(),
+            name: account.identity.name.clone(),
+            created,
+        }
+    }
+}
+
+/// Runs the configured hooks. Clones share the same configuration.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    config: Arc<HooksConfig>,
+}
+
+impl Hooks {
+    pub fn new(config: HooksConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+    fn commands(&self, event: &Event) -> &[PathBuf] {
+        let config = &self.config;
+        match event {
+            Event::UploadComplete { .. } => &config.upload_complete,
+            Event::DownloadComplete { .. } => &config.download_complete,
+            Event::Login { .. } => &config.login,
+            Event::Logout { .. } => &config.logout,
+            Event::NewsPost { .. } => &config.news_post,
+            Event::AccountChange { .. } => &config.account_change,
+        }
+    }
+    /// Runs the hooks for `event` in the background.
+    pub fn notify(&self, event: Event) {
+        if self.commands(&event).is_empty() {
+            return;
+        }
+        let hooks = self.clone();
+        tokio::spawn(async move { hooks.check(&event).await });
+    }
+    /// Runs the hooks for `event` one after another and returns whether all
+    /// of them succeeded.
+    pub async fn check(&self, event: &Event) -> bool {
+        let commands = self.commands(event);
+        if commands.is_empty() {
+            return true;
+        }
+        let payload = match serde_json::to_vec(event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("failed to encode {event:?}: {e}");
+                return false;
+            }
+        };
+        let timeout = Duration::from_secs(self.config.timeout);
+        for command in commands {
+            match tokio::time::timeout(timeout, Self::run(command, &payload)).await {
+                Ok(Ok(true)) => debug!("hook {command:?} succeeded"),
+                Ok(Ok(false)) => {
+                    warn!("hook {command:?} failed for {event:?}");
+                    return false;
+                }
+                Ok(Err(e)) => {
+                    error!("failed to run hook {command:?}: {e}");
+                    return false;
+                }
+                Err(_) => {
+                    warn!("hook {command:?} timed out for {event:?}");
+                    return false;
+                }
+            }
+        }
+        true
+    }
+    async fn run(command: &PathBuf, payload: &[u8]) -> std::io::Result<bool> {
+        let mut child = Command::new(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // Hooks which don't care about the details may exit without
+            // reading them.
+            if let Err(e) = stdin.write_all(payload).await {
+                debug!("hook {command:?} did not read its input: {e}");
+            }
+        }
+        let status = child.wait().await?;
+        Ok(status.success())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::files::scratch::scratch_dir;
+    use std::path::Path;
+
+    fn script(dir: &Path, name: &str, body: &str) -> std::io::Result<PathBuf> {
+        use std::os::unix::fs::PermissionsExt as _;
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{body}\n"))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(path)
+    }
+
+    #[tokio::test]
+    async fn test_hooks_veto_uploads() -> std::io::Result<()> {
+        let dir = scratch_dir("hooks")?;
+        let out = dir.join("event.json");
+        let record = script(&dir, "record", &format!("cat > {}", out.display()))?;
+        let refuse = script(&dir, "refuse", "exit 1")?;
+        let hang = script(&dir, "hang", "sleep 10")?;
+        let event = Event::UploadComplete {
+            path: "Uploads/file.sit".into(),
+            host_path: None,
+            login: Some("guest".into()),
+            size: 4,
+            sha256: None,
+        };
+
+        let hooks = Hooks::new(HooksConfig {
+            upload_complete: vec![record.clone()],
+            ..Default::default()
+        });
+        assert!(hooks.check(&event).await);
+        let recorded = std::fs::read_to_string(&out)?;
+        assert!(recorded.starts_with(r#"{"event":"upload-complete","path":"Uploads/file.sit""#));
+
+        let hooks = Hooks::new(HooksConfig {
+            upload_complete: vec![record.clone(), refuse],
+            ..Default::default()
+        });
+        assert!(!hooks.check(&event).await);
+
+        let hooks = Hooks::new(HooksConfig {
+            timeout: 0,
+            upload_complete: vec![hang],
+            ..Default::default()
+        });
+        assert!(!hooks.check(&event).await);
+
+        assert!(Hooks::default().check(&event).await);
+        Ok(())
+    }
+
+    #[test]
+    fn test_account_change_payload() {
+        let mut account = UserAccount::default();
+        account.identity.login = "alice".into();
+        account.identity.name = "Alice".into();
+        let event = Event::account_change(&account, true);
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"account-change","login":"alice","name":"Alice","created":true}"#
+        );
+    }
+}
@@ -1,8 +1,17 @@
 use self::{
+    admin::AdminCommand,
+    application::{FileOperation, Files, Permissions as _, UserAccount},
     bus::{Notification, Notifications},
     chat::{Chats, ChatsService},
-    files::OsFiles,
+    files::{
+        checksum::Checksum,
+        homes::HomeFolders,
+        moderation::{Moderation, PendingUpload},
+        names,
+        quotas::Quotas,
+    },
     news::{News, NewsService},
+    progress::TransferProgress,
     transaction_stream::Frames,
     transfers::TransfersService,
     users::{UserAccounts, Users, UsersService},
@@ -14,7 +23,12 @@ use crate::protocol::{
 use derive_more::{From, Into};
 use encoding_rs::MACINTOSH;
 use futures::stream::{select, Stream, StreamExt as _, TryStreamExt as _};
-use std::path::PathBuf;
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use thiserror::Error;
 use tokio::{
     io::AsyncRead,
@@ -25,11 +39,18 @@ use tokio::{
 };
 use tracing::debug;
 
+pub mod admin;
+pub mod announcements;
 pub mod application;
 pub mod bus;
 pub mod chat;
+pub mod config;
 pub mod files;
+pub mod hooks;
 pub mod news;
+pub mod progress;
+pub mod sendfile;
+pub mod throttle;
 pub mod transaction_stream;
 pub mod transfers;
 pub mod user_editor;
@@ -117,12 +138,62 @@ impl From<Broadcast> for ServerMessage {
     }
 }
 
+/// An upload which has been put into place at the given path, by the given
+/// login.
+#[derive(Debug, Clone, From, Into)]
+pub struct UploadComplete(pub PathBuf, pub Option<String>);
+
+/// An upload which has been put in the pending folder at the given path.
+#[derive(Debug, Clone, From, Into)]
+pub struct UploadPending(pub PathBuf, pub PendingUpload);
+
+impl From<UploadPending> for ServerMessage {
+    fn from(val: UploadPending) -> Self {
+        let UploadPending(path, upload) = val;
+        let account = upload.account.as_deref().unwrap_or("a guest");
+        let text = format!(
+            "{} is waiting for approval. It was uploaded to {} by {account}.",
+            path.display(),
+            upload.destination.display(),
+        );
+        ServerMessage {
+            message: MACINTOSH.encode(&text).0.into_owned(),
+            user_id: None,
+            user_name: None,
+        }
+    }
+}
+
+/// The progress of every running transfer.
+#[derive(Debug, Clone, From, Into)]
+pub struct TransfersProgress(pub Vec<TransferProgress>);
+
+/// A message from the server to a single user.
 #[derive(Debug, Clone, From, Into)]
-pub struct DownloadInfo(pub proto::ReferenceNumber, pub proto::WaitingCount);
+pub struct ServerNotice(pub UserId, pub Vec<u8>);
+
+impl From<ServerNotice> for ServerMessage {
+    fn from(val: ServerNotice) -> Self {
+        let ServerNotice(_, message) = val;
+        ServerMessage {
+            message,
+            user_id: None,
+            user_name: None,
+        }
+    }
+}
+
+/// The queue position of a transfer, for the user who requested it.
+#[derive(Debug, Clone, From, Into)]
+pub struct DownloadInfo(
+    pub UserId,
+    pub proto::ReferenceNumber,
+    pub proto::WaitingCount,
+);
 
 impl From<DownloadInfo> for proto::DownloadInfo {
     fn from(value: DownloadInfo) -> Self {
-        let DownloadInfo(reference, waiting_count) = value;
+        let DownloadInfo(_, reference, waiting_count) = value;
         Self {
             reference,
             waiting_count,
@@ -370,25 +441,38 @@ impl TryFrom<TransactionFrame> for ClientRequest {
 #[derive(Debug)]
 pub struct NeolithServer {
     user_id: proto::UserId,
-    files: OsFiles,
+    /// The address the user connected from.
+    address: IpAddr,
+    files: Arc<dyn Files>,
     users: watch::Receiver<Users>,
     users_tx: UsersService,
     news: watch::Receiver<News>,
     news_tx: NewsService,
-    chats: watch::Receiver<Chats>,
+    _chats: watch::Receiver<Chats>,
     chats_tx: ChatsService,
     transfers_tx: TransfersService,
     accounts: UserAccounts,
+    account: Option<UserAccount>,
+    homes: HomeFolders,
+    quotas: Quotas,
+    moderation: Moderation,
 }
 
 type ServerResult<T> = anyhow::Result<T>;
 
+/// Why moving or deleting anything but a pending upload is refused.
+const ONLY_PENDING: &str = "Only pending uploads can be moved or deleted on this server.";
+
 impl NeolithServer {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         user_id: proto::UserId,
-        files: OsFiles,
+        address: IpAddr,
+        files: Arc<dyn Files>,
         accounts: UserAccounts,
+        account: Option<UserAccount>,
+        quotas: Quotas,
+        moderation: Moderation,
         users: watch::Receiver<Users>,
         users_tx: UsersService,
         news: watch::Receiver<News>,
@@ -397,15 +481,21 @@ impl NeolithServer {
         chats_tx: ChatsService,
         transfers_tx: TransfersService,
     ) -> Self {
+        let homes = HomeFolders::new(accounts.iter());
         Self {
             user_id,
+            address,
             files,
             accounts,
+            account,
+            homes,
+            quotas,
+            moderation,
             users,
             users_tx,
             news,
             news_tx,
-            chats,
+            _chats: chats,
             chats_tx,
             transfers_tx,
         }
@@ -431,6 +521,7 @@ impl NeolithServer {
                 .await
                 .map(Into::into)
                 .map(Some),
+            ClientRequest::SetFileInfo(req) => self.set_file_info(req).await.map(Some),
             ClientRequest::SetClientUserInfo(req) => {
                 self.set_user_info(req.username, req.icon_id).await?;
                 Ok(None)
@@ -452,13 +543,15 @@ impl NeolithServer {
                 Ok(None)
             }
             ClientRequest::DownloadFile(req) => self
-                .file_download(req.file_path, req.filename)
+                .file_download(req.file_path, req.filename, req.options)
                 .await
                 .map(Some),
             ClientRequest::UploadFile(req) => self
-                .file_upload(req.file_path, req.filename)
+                .file_upload(req.file_path, req.filename, req.transfer_size)
                 .await
                 .map(Some),
+            ClientRequest::MoveFile(req) => self.move_file(req).await.map(Some),
+            ClientRequest::DeleteFile(req) => self.delete_file(req).await.map(Some),
             ClientRequest::GetUser(proto::GetUser(login)) => {
                 if let Some(account) = self.accounts.get(login).cloned() {
                     Ok(Some(ServerResponse::GetUserReply(account.try_into()?)))
@@ -483,7 +576,14 @@ impl NeolithServer {
         let user = users
             .find(user_id)
             .ok_or(anyhow::anyhow!("could not find user with id {user_id:?}"))?;
-        let text = format!("{:#?}", &user).replace('\n', "\r");
+        let mut text = format!("{:#?}", &user);
+        // The paths being transferred are only for administrators to see.
+        if self.is_admin() {
+            for transfer in self.transfers_tx.progress_of(user_id) {
+                text.push_str(&format!("\n{transfer}"));
+            }
+        }
+        let text = text.replace('\n', "\r");
         let (text, _, failed) = MACINTOSH.encode(&text);
         if failed {
             anyhow::bail!("failed to encode user info string");
@@ -506,12 +606,16 @@ impl NeolithServer {
     }
     async fn list_files(&self, path: proto::FilePath) -> ServerResult<proto::GetFileNameListReply> {
         debug!("list {path:?}");
-        let path: PathBuf = path.into();
+        let path = self.authorize_path(path.into())?;
         let files = self
             .files
             .list(&path)
             .await?
             .into_iter()
+            .filter(|entry| {
+                let name = entry.path.file_name().unwrap_or_default();
+                self.allows(&path.join(name))
+            })
             .filter_map(|path| proto::FileNameWithInfo::try_from(path).ok())
             .collect::<Vec<_>>();
         Ok(proto::GetFileNameListReply::with_files(files))
@@ -522,19 +626,139 @@ impl NeolithServer {
         name: proto::FileName,
     ) -> ServerResult<proto::GetFileInfoReply> {
         debug!("info {name:?} @ {path:?}");
-        let path = PathBuf::from(path).join(PathBuf::from(&name));
+        let path = self.authorize_path(Self::join_path(&path, &name))?;
         let info = self.files.get_info(&path).await?;
+        let mut comment = info.comment.clone();
+        if let Some(checksum) = info.checksum {
+            if !comment.is_empty() {
+                comment.push(b'\r');
+            }
+            comment.extend(checksum_line(&checksum));
+        }
         let reply = proto::GetFileInfoReply {
             filename: name,
             size: info.total_size().try_into()?,
             type_code: proto::FileType::from(*info.file_type.bytes()),
             creator: info.creator.bytes().to_vec().into(),
-            comment: info.comment.into(),
+            comment: comment.into(),
             created_at: info.created_at.into(),
             modified_at: info.modified_at.into(),
+            item_count: info.item_count.map(Into::into),
         };
         Ok(reply)
     }
+    async fn set_file_info(&self, req: proto::SetFileInfo) -> ServerResult<ServerResponse> {
+        let proto::SetFileInfo {
+            filename,
+            path,
+            new_name,
+            new_comment,
+        } = req;
+        debug!("set info {filename:?} @ {path:?}: {new_name:?}, {new_comment:?}");
+        let path = self.authorize_path(Self::join_path(&path, &filename))?;
+        let info = self.files.get_info(&path).await?;
+        let is_folder = info.is_folder();
+        let (rename, comment) = if is_folder {
+            (FileOperation::RenameFolder, FileOperation::SetFolderComment)
+        } else {
+            (FileOperation::RenameFile, FileOperation::SetFileComment)
+        };
+        if new_name.is_some() && !self.can(rename) {
+            let message = format!("You are not allowed to rename this {}.", kind(is_folder));
+            return Ok(ServerResponse::Rejected(Some(message)));
+        }
+        if new_comment.is_some() && !self.can(comment) {
+            let message = format!(
+                "You are not allowed to change the comment of this {}.",
+                kind(is_folder)
+            );
+            return Ok(ServerResponse::Rejected(Some(message)));
+        }
+        if let Some(comment) = new_comment {
+            let mut comment: Vec<u8> = comment.into();
+            // Clients send back the checksum line shown by `file_info` along
+            // with the comment, which would otherwise pile up in it.
+            if let Some(checksum) = info.checksum {
+                let line = checksum_line(&checksum);
+                if comment.ends_with(&line) {
+                    comment.truncate(comment.len() - line.len());
+                    if comment.last() == Some(&b'\r') {
+                        comment.pop();
+                    }
+                }
+            }
+            self.files.set_comment(&path, comment).await?;
+        }
+        if let Some(new_name) = new_name {
+            let new_name: Vec<u8> = new_name.into();
+            self.files.rename(&path, &new_name).await?;
+            let new_path = path.with_file_name(names::mac_to_host(&new_name));
+            self.quotas.rename(&path, &new_path).await;
+        }
+        Ok(proto::SetFileInfoReply.into())
+    }
+    /// Whether the current account may perform `op` on files.
+    fn can(&self, op: FileOperation) -> bool {
+        let account = self.account.as_ref();
+        account.is_some_and(|account| account.permissions.file.can(op))
+    }
+    fn is_admin(&self) -> bool {
+        self.account.as_ref().is_some_and(UserAccount::is_admin)
+    }
+    /// Maps a path requested by the current account onto the file area,
+    /// refusing paths within the home folders of other accounts and, unless
+    /// the account moderates uploads, within the pending folder.
+    fn authorize_path(&self, path: PathBuf) -> ServerResult<PathBuf> {
+        let path = HomeFolders::resolve(self.account.as_ref(), &path);
+        if !self.allows(&path) {
+            anyhow::bail!("{path:?} is out of reach of the current account");
+        }
+        Ok(path)
+    }
+    fn allows(&self, path: &Path) -> bool {
+        let account = self.account.as_ref();
+        self.homes.allows(account, path) && self.moderation.allows(account, path)
+    }
+    /// Approves a pending upload by moving it to `new_path`, which is the
+    /// only kind of move supported so far.
+    async fn move_file(&self, req: proto::MoveFile) -> ServerResult<ServerResponse> {
+        let proto::MoveFile {
+            filename,
+            path,
+            new_path,
+        } = req;
+        let path = self.authorize_path(Self::join_path(&path, &filename))?;
+        if self.moderation.pending(&path).is_none() {
+            return Ok(ServerResponse::Rejected(Some(ONLY_PENDING.to_string())));
+        }
+        let folder = self.authorize_path(new_path.into())?;
+        let files = self.files.as_ref();
+        match self.moderation.approve(files, &path, &folder).await {
+            Ok(approved) => {
+                debug!("approved upload {path:?} => {approved:?}");
+                self.quotas.rename(&path, &approved).await;
+                Ok(proto::MoveFileReply.into())
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let message = "There is already a file with this name.";
+                Ok(ServerResponse::Rejected(Some(message.into())))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Rejects a pending upload, which is the only kind of deletion supported
+    /// so far.
+    async fn delete_file(&self, req: proto::DeleteFile) -> ServerResult<ServerResponse> {
+        let proto::DeleteFile { filename, path } = req;
+        let path = self.authorize_path(Self::join_path(&path, &filename))?;
+        if self.moderation.pending(&path).is_none() {
+            return Ok(ServerResponse::Rejected(Some(ONLY_PENDING.to_string())));
+        }
+        self.moderation.reject(self.files.as_ref(), &path).await?;
+        self.quotas.forget(&path).await;
+        debug!("rejected upload {path:?}");
+        Ok(proto::DeleteFileReply.into())
+    }
     fn join_path(path: &proto::FilePath, name: &proto::FileName) -> PathBuf {
         let name_slice = [name.clone().into()];
         let path = path
@@ -542,18 +766,25 @@ impl NeolithServer {
             .into_iter()
             .flat_map(|p| p.iter())
             .chain(name_slice.iter())
-            .map(|p| MACINTOSH.decode(p).0.to_string());
+            .map(|p| names::mac_to_host(p));
         PathBuf::from_iter(path)
     }
     async fn file_download(
         &mut self,
         path: proto::FilePath,
         name: proto::FileName,
+        options: Option<proto::FileTransferOptions>,
     ) -> ServerResult<ServerResponse> {
-        let path = Self::join_path(&path, &name);
+        let path = self.authorize_path(Self::join_path(&path, &name))?;
+        let compression = if options.is_some_and(|options| options.accepts_zlib()) {
+            proto::CompressionType::Zlib
+        } else {
+            proto::CompressionType::None
+        };
+        let account = self.account.clone();
         let reply = self
             .transfers_tx
-            .file_download(self.files.root(), path)
+            .file_download(path, account, self.user_id, self.address, compression)
             .await
             .ok_or_else(|| anyhow::anyhow!("failed to start download"))?;
         Ok(reply.into())
@@ -562,11 +793,34 @@ impl NeolithServer {
         &mut self,
         path: proto::FilePath,
         name: proto::FileName,
+        transfer_size: Option<proto::TransferSize>,
     ) -> ServerResult<ServerResponse> {
-        let path = Self::join_path(&path, &name);
+        let path = self.authorize_path(Self::join_path(&path, &name))?;
+        let account = self.account.as_ref();
+        let exists = self.files.get_info(&path).await.is_ok();
+        if exists && !account.is_some_and(UserAccount::can_overwrite_files) {
+            debug!("rejected upload to existing {path:?}");
+            let name = Vec::<u8>::from(name);
+            let name = MACINTOSH.decode(&name).0;
+            let message = format!(
+                "Cannot accept upload because there is already a file named \"{name}\". Try choosing a different name."
+            );
+            return Ok(ServerResponse::Rejected(Some(message)));
+        }
+        // The transfer connection checks again with the size from its
+        // handshake, which every client sends.
+        let size = transfer_size.map_or(0, u32::from).into();
+        let checked = self
+            .quotas
+            .check(self.files.as_ref(), account, &path, size)
+            .await;
+        if let Err(e) = checked {
+            debug!("rejected upload to {path:?}: {e}");
+            return Ok(ServerResponse::Rejected(Some(e.to_string())));
+        }
         let reply = self
             .transfers_tx
-            .file_upload(self.files.root(), path)
+            .file_upload(path, self.account.clone(), self.user_id, self.address)
             .await
             .ok_or_else(|| anyhow::anyhow!("failed to start upload"))?;
         Ok(reply.into())
@@ -593,10 +847,27 @@ impl NeolithServer {
         message: Vec<u8>,
     ) -> ServerResult<()> {
         let user = self.require_current_user()?;
+        if let Some(command) = AdminCommand::parse(&message).filter(|_| self.is_admin()) {
+            let text = self.admin(command).await;
+            let text = MACINTOSH.encode(&text).0.into_owned();
+            self.chats_tx
+                .notice(ServerNotice(user.user_id, text))
+                .await?;
+            return Ok(());
+        }
         let chat = Chat(None, user.into(), message);
         self.chats_tx.chat(chat.into()).await?;
         Ok(())
     }
+    async fn admin(&mut self, command: AdminCommand) -> String {
+        match command {
+            AdminCommand::Transfers => admin::transfers(&self.transfers_tx.progress().borrow()),
+            AdminCommand::Cancel(reference) => {
+                let found = self.transfers_tx.cancel(reference).await;
+                admin::cancelled(reference, found)
+            }
+        }
+    }
     async fn send_private_chat(
         &mut self,
         _options: proto::ChatOptions,
@@ -623,15 +894,233 @@ impl NeolithServer {
     }
 }
 
+/// The line giving the checksum of a file after its comment.
+fn checksum_line(checksum: &Checksum) -> Vec<u8> {
+    format!("SHA-256: {checksum}").into_bytes()
+}
+
+fn kind(is_folder: bool) -> &'static str {
+    if is_folder {
+        "folder"
+    } else {
+        "file"
+    }
+}
+
 impl From<proto::FilePath> for PathBuf {
     fn from(value: proto::FilePath) -> Self {
         match value {
             proto::FilePath::Root => PathBuf::new(),
-            proto::FilePath::Directory(parts) => parts
-                .iter()
-                .map(|p| MACINTOSH.decode(p).0)
-                .map(|p| p.to_string())
-                .collect(),
+            proto::FilePath::Directory(parts) => {
+                parts.iter().map(|p| names::mac_to_host(p)).collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        application::{UserAccountPermissions, UserOperation},
+        bus::Bus,
+        files::memory::MemoryFiles,
+        hooks::Hooks,
+    };
+    use futures::stream::BoxStream;
+    use std::net::Ipv4Addr;
+
+    fn server(files: Arc<dyn Files>, account: Option<UserAccount>) -> NeolithServer {
+        let bus = Bus::new();
+        let (users_tx, users) = UsersService::new(bus.clone(), Hooks::default());
+        connect(
+            &bus,
+            users_tx,
+            users.subscribe(),
+            UserId::default(),
+            files,
+            account,
+        )
+    }
+
+    fn connect(
+        bus: &Bus,
+        users_tx: UsersService,
+        users: watch::Receiver<Users>,
+        user_id: UserId,
+        files: Arc<dyn Files>,
+        account: Option<UserAccount>,
+    ) -> NeolithServer {
+        let (chats_tx, chats) = ChatsService::new(bus.clone());
+        let (news_tx, news) = NewsService::new(MACINTOSH, bus.clone(), Hooks::default());
+        let (transfers_tx, _) = TransfersService::new(
+            bus.clone(),
+            files.clone(),
+            Default::default(),
+            Hooks::default(),
+        );
+        NeolithServer::new(
+            user_id,
+            Ipv4Addr::LOCALHOST.into(),
+            files,
+            UserAccounts::default(),
+            account,
+            Quotas::in_memory(Default::default()),
+            Moderation::in_memory(Default::default()),
+            users,
+            users_tx,
+            news.subscribe(),
+            news_tx,
+            chats.subscribe(),
+            chats_tx,
+            transfers_tx,
+        )
+    }
+
+    fn set_file_info(name: &[u8], comment: Option<&[u8]>) -> proto::SetFileInfo {
+        proto::SetFileInfo {
+            filename: b"notes.txt".to_vec().into(),
+            path: proto::FilePath::Root,
+            new_name: Some(name.to_vec().into()),
+            new_comment: comment.map(|comment| comment.to_vec().into()),
         }
     }
+
+    #[tokio::test]
+    async fn test_guests_may_not_set_file_info() -> ServerResult<()> {
+        let memory = Arc::new(MemoryFiles::new());
+        memory.insert_file("notes.txt", "hello")?;
+        let files: Arc<dyn Files> = memory.clone();
+
+        let guest = server(files.clone(), None);
+        let reply = guest
+            .set_file_info(set_file_info(b"mine.txt", None))
+            .await?;
+        assert!(matches!(reply, ServerResponse::Rejected(Some(_))));
+        assert!(memory.data("notes.txt").is_some());
+
+        let renamer = UserAccount {
+            permissions: UserAccountPermissions {
+                file: [FileOperation::RenameFile].into_iter().collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let renamer = server(files, Some(renamer));
+        let request = set_file_info(b"mine.txt", Some(b"a comment"));
+        let reply = renamer.set_file_info(request).await?;
+        assert!(matches!(reply, ServerResponse::Rejected(Some(_))));
+        assert!(memory.data("notes.txt").is_some());
+
+        let reply = renamer
+            .set_file_info(set_file_info(b"mine.txt", None))
+            .await?;
+        assert!(matches!(reply, ServerResponse::SetFileInfoReply(_)));
+        assert_eq!(memory.data("mine.txt").unwrap(), b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checksum_is_not_saved_in_comment() -> ServerResult<()> {
+        let memory = Arc::new(MemoryFiles::new());
+        memory.insert_file("notes.txt", "hello")?;
+        let checksum = Checksum::of(&b"hello"[..])?;
+        memory
+            .set_checksum(Path::new("notes.txt"), checksum)
+            .await?;
+        let commenter = UserAccount {
+            permissions: UserAccountPermissions {
+                file: [FileOperation::SetFileComment].into_iter().collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = server(memory.clone(), Some(commenter));
+        let comment = || async {
+            let name = b"notes.txt".to_vec().into();
+            let info = server.file_info(proto::FilePath::Root, name).await?;
+            ServerResult::Ok(Vec::<u8>::from(info.comment))
+        };
+
+        let shown = format!("SHA-256: {checksum}").into_bytes();
+        assert_eq!(comment().await?, shown);
+        for _ in 0..2 {
+            let edited = [&b"Greetings"[..], b"\r", &comment().await?].concat();
+            let request = proto::SetFileInfo {
+                new_name: None,
+                new_comment: Some(edited.into()),
+                ..set_file_info(b"", None)
+            };
+            server.set_file_info(request).await?;
+        }
+        let stored = memory.get_info(Path::new("notes.txt")).await?.comment;
+        assert_eq!(stored, b"Greetings\rGreetings");
+        assert_eq!(
+            comment().await?,
+            [&b"Greetings\rGreetings\r"[..], &shown].concat()
+        );
+        Ok(())
+    }
+
+    async fn next_message(notifications: &mut BoxStream<'_, Notification>) -> Notification {
+        loop {
+            match notifications.next().await {
+                Some(Notification::UserConnect(_)) => {}
+                notification => return notification.expect("bus closed"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_commands_are_answered_privately() -> ServerResult<()> {
+        let bus = Bus::new();
+        let (users_tx, users) = UsersService::new(bus.clone(), Hooks::default());
+        let subscription = users.subscribe();
+        tokio::spawn(users.run());
+        let mut notifications = bus.subscribe().incoming().boxed();
+        let files: Arc<dyn Files> = Arc::new(MemoryFiles::new());
+
+        let chat = |account: Option<UserAccount>| {
+            let (bus, mut users_tx) = (bus.clone(), users_tx.clone());
+            let (users, files) = (subscription.clone(), files.clone());
+            async move {
+                let user = UserNameWithInfo {
+                    user_id: UserId::default(),
+                    icon_id: 0.into(),
+                    user_flags: Default::default(),
+                    username_len: 0,
+                    username: vec![].into(),
+                };
+                let user_id = users_tx.add(user).await?;
+                let mut server = connect(&bus, users_tx, users, user_id, files, account);
+                server
+                    .send_chat(proto::ChatOptions::none(), b"/cancel 7".to_vec())
+                    .await?;
+                ServerResult::Ok(user_id)
+            }
+        };
+        let guest = chat(None).await?;
+        let Notification::Chat(message) = next_message(&mut notifications).await else {
+            panic!("guest commands should be chat");
+        };
+        assert!(message.message.ends_with(b": /cancel 7"));
+
+        let admin = UserAccount {
+            permissions: UserAccountPermissions {
+                user: [UserOperation::CanDisconnectUsers].into_iter().collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let admin = chat(Some(admin)).await?;
+        assert_ne!(admin, guest);
+        let Notification::ServerNotice(ServerNotice(to, text)) =
+            next_message(&mut notifications).await
+        else {
+            panic!("admin commands should be answered");
+        };
+        assert_eq!(to, admin);
+        assert_eq!(text, b"There is no transfer #7.");
+        Ok(())
+    }
 }
@@ -5,6 +5,7 @@ use thiserror::Error;
 use tokio::sync::{mpsc, oneshot, watch};
 
 use super::bus::{Bus, Notification};
+use super::hooks::{Event, Hooks};
 
 pub static SEPARATOR: &str = "\r--\r";
 
@@ -67,22 +68,24 @@ struct Command {
 }
 
 #[derive(Debug, Clone)]
-pub struct NewsService(mpsc::Sender<Command>, Bus);
+pub struct NewsService(mpsc::Sender<Command>, Bus, Hooks);
 
 impl NewsService {
-    pub fn new(encoding: &'static Encoding, bus: Bus) -> (Self, NewsUpdateProcessor) {
+    pub fn new(encoding: &'static Encoding, bus: Bus, hooks: Hooks) -> (Self, NewsUpdateProcessor) {
         let (tx, rx) = mpsc::channel(10);
-        let service = Self(tx, bus);
+        let service = Self(tx, bus, hooks);
         let process = NewsUpdateProcessor::new(rx, encoding);
         (service, process)
     }
     pub async fn post(&mut self, article: Vec<u8>) {
         let (tx, rx) = oneshot::channel();
         let notification = Notification::News(article.clone().into());
+        let event = Event::news_post(&article);
         let command = Command { article, tx };
-        let Self(tx, bus) = self;
+        let Self(tx, bus, hooks) = self;
         tx.send(command).await.ok();
         rx.await.ok();
+        hooks.notify(event);
         bus.publish(notification);
     }
 }
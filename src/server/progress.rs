@@ -0,0 +1,142 @@
+//! Progress of running transfers.
+//!
+//! Each transfer connection counts the bytes moving through its socket in a
+//! [`Tracker`] shared with the transfers actor, which samples it to report
+//! progress and rates and sets it to cancel the transfer.
+
+use futures::task::AtomicWaker;
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+
+use super::throttle::Direction;
+use crate::protocol::{ReferenceNumber, UserId};
+
+/// How far a transfer has got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferProgress {
+    pub reference: ReferenceNumber,
+    pub user_id: UserId,
+    pub login: Option<String>,
+    pub path: PathBuf,
+    pub direction: Direction,
+    /// Bytes moved so far.
+    pub done: u64,
+    /// Bytes expected in all.
+    pub total: u64,
+    /// Bytes per second since the previous sample.
+    pub rate: u64,
+}
+
+impl std::fmt::Display for TransferProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let direction = match self.direction {
+            Direction::Download => "Downloading",
+            Direction::Upload => "Uploading",
+        };
+        write!(
+            f,
+            "{direction} {}: {} of {} bytes at {} bytes/s",
+            self.path.display(),
+            self.done,
+            self.total,
+            self.rate,
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Tracker {
+    done: AtomicU64,
+    cancelled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Tracker {
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+    /// Starts counting again from zero.
+    pub fn reset(&self) {
+        self.done.store(0, Ordering::Relaxed);
+    }
+    /// Makes all further reads and writes fail, including any waiting now.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.waker.wake();
+    }
+    pub(super) fn check(&self, cx: &Context<'_>) -> io::Result<()> {
+        self.waker.register(cx.waker());
+        if self.cancelled.load(Ordering::Relaxed) {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "transfer cancelled",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+    pub(super) fn add(&self, bytes: usize) {
+        self.done.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A socket which counts what passes through it into a [`Tracker`].
+#[derive(Debug)]
+pub struct Tracked<S> {
+    inner: S,
+    tracker: Arc<Tracker>,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            tracker: Default::default(),
+        }
+    }
+    pub fn tracker(&self) -> Arc<Tracker> {
+        self.tracker.clone()
+    }
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.tracker.check(cx)?;
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.tracker.add(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.tracker.check(cx)?;
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.tracker.add(written);
+        Poll::Ready(Ok(written))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,170 @@
+//! Sending forks straight from the page cache to a socket.
+//!
+//! On Linux, forks of ordinary files going to a plain TCP stream are sent
+//! with `sendfile`, which saves copying them through a buffer and hopping
+//! to the blocking pool for every read. Anything else, and any file the
+//! kernel can't send this way, is left to the caller to copy.
+
+use tokio::{io::DuplexStream, net::TcpStream};
+
+use super::progress::Tracked;
+use crate::protocol::FileRange;
+
+/// A socket which may be a plain TCP stream underneath.
+pub trait SendFile {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl SendFile for TcpStream {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl SendFile for DuplexStream {}
+
+impl<S: SendFile> Tracked<S> {
+    /// Sends `len` bytes of `file` without copying them, counting them as
+    /// they go.
+    ///
+    /// Returns `None`, having sent nothing, when the socket or the file
+    /// don't allow it.
+    pub async fn send_file(
+        &mut self,
+        file: &FileRange,
+        len: u64,
+    ) -> Option<tokio::io::Result<u64>> {
+        #[cfg(target_os = "linux")]
+        {
+            let stream = self.get_ref().tcp_stream()?;
+            linux::send_file(stream, file, len, &self.tracker()).await
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (file, len);
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use rustix::io::Errno;
+    use std::task::{ready, Poll};
+    use tokio::{
+        io::{self, Interest},
+        net::TcpStream,
+    };
+
+    use crate::{protocol::FileRange, server::progress::Tracker};
+
+    /// The most to send in one call, so that cancelling takes effect soon.
+    const CHUNK: u64 = 1 << 20;
+
+    pub(super) async fn send_file(
+        stream: &TcpStream,
+        file: &FileRange,
+        len: u64,
+        tracker: &Tracker,
+    ) -> Option<io::Result<u64>> {
+        let mut offset = file.offset;
+        let end = file.offset + len;
+        std::future::poll_fn(|cx| loop {
+            if let Err(e) = tracker.check(cx) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if offset == end {
+                return Poll::Ready(Some(Ok(len)));
+            }
+            if let Err(e) = ready!(stream.poll_write_ready(cx)) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            let count = (end - offset).min(CHUNK) as usize;
+            let sent = stream.try_io(Interest::WRITABLE, || {
+                rustix::fs::sendfile(stream, &file.file, Some(&mut offset), count)
+                    .map_err(io::Error::from)
+            });
+            match sent {
+                Ok(0) => {
+                    let e = io::Error::new(io::ErrorKind::UnexpectedEof, "file ended early");
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Ok(sent) => tracker.add(sent),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) if offset == file.offset && unsupported(&e) => {
+                    return Poll::Ready(None);
+                }
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        })
+        .await
+    }
+
+    fn unsupported(e: &io::Error) -> bool {
+        let errno = e.raw_os_error().map(Errno::from_raw_os_error);
+        matches!(errno, Some(Errno::INVAL | Errno::NOSYS | Errno::OPNOTSUPP))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::files::scratch::scratch_dir;
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn test_send_file_over_tcp() -> tokio::io::Result<()> {
+        let scratch = scratch_dir("sendfile")?;
+        let path = scratch.join("file");
+        let contents: Vec<u8> = (0..=255).cycle().take(3 << 20).collect();
+        tokio::fs::write(&path, &contents).await?;
+        let file = tokio::fs::File::open(&path).await?;
+        let range = FileRange {
+            file: file.into_std().await,
+            offset: 10,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        let receive = tokio::spawn(async move {
+            let mut client = client;
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.map(|_| received)
+        });
+        let mut socket = Tracked::new(server);
+        let len = contents.len() as u64 - 20;
+        let sent = socket.send_file(&range, len).await;
+        socket.shutdown().await?;
+        let received = receive.await.unwrap()?;
+
+        if cfg!(target_os = "linux") {
+            assert_eq!(sent.unwrap()?, len);
+            assert_eq!(socket.tracker().done(), len);
+            assert!(received == contents[10..contents.len() - 10]);
+        } else {
+            assert!(sent.is_none());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_file_needs_tcp() -> tokio::io::Result<()> {
+        let scratch = scratch_dir("duplex")?;
+        let path = scratch.join("file");
+        tokio::fs::write(&path, b"data").await?;
+        let file = tokio::fs::File::open(&path).await?;
+        let range = FileRange {
+            file: file.into_std().await,
+            offset: 0,
+        };
+        let (socket, _peer) = tokio::io::duplex(64);
+        let mut socket = Tracked::new(socket);
+        assert!(socket.send_file(&range, 4).await.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! Bandwidth limits for file transfers.
+//!
+//! Each limit is a token bucket holding up to a second's worth of bytes.
+//! Transfers charge the buckets for what they have already moved and, once a
+//! bucket is in debt, pause until it has refilled. Every transfer sharing a
+//! bucket waits behind the debt of the others, so the rate is split evenly
+//! among them.
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncRead, ReadBuf},
+    time::Instant,
+};
+
+/// Limits in bytes per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// The rate shared by all transfers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    /// The rate shared by the transfers of a single account, where all
+    /// guests count as one account.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, where zero means unlimited.
+    rate: AtomicU64,
+    state: Mutex<BucketState>,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>) -> Self {
+        let rate = rate.unwrap_or(0);
+        Self {
+            rate: AtomicU64::new(rate),
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+    fn set_rate(&self, rate: Option<u64>) {
+        self.rate.store(rate.unwrap_or(0), Ordering::Relaxed);
+    }
+    /// Takes `bytes` from the bucket and returns how long to wait until it
+    /// is out of debt.
+    fn charge(&self, bytes: usize) -> Duration {
+        let rate = self.rate.load(Ordering::Relaxed) as f64;
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated).as_secs_f64();
+        state.updated = now;
+        if rate == 0.0 {
+            state.tokens = 0.0;
+            return Duration::ZERO;
+        }
+        state.tokens = (state.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if state.tokens < 0.0 {
+            Duration::from_secs_f64(-state.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    download: RateLimits,
+    upload: RateLimits,
+    totals: HashMap<Direction, Arc<Bucket>>,
+    accounts: HashMap<(Direction, Option<String>), Arc<Bucket>>,
+}
+
+impl Buckets {
+    fn limits(&self, direction: Direction) -> RateLimits {
+        match direction {
+            Direction::Download => self.download,
+            Direction::Upload => self.upload,
+        }
+    }
+}
+
+/// The buckets of all transfers, whose rates may be changed while transfers
+/// are running.
+#[derive(Debug, Clone, Default)]
+pub struct Throttles {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl Throttles {
+    pub fn new(download: RateLimits, upload: RateLimits) -> Self {
+        let buckets = Buckets {
+            download,
+            upload,
+            ..Default::default()
+        };
+        Self {
+            buckets: Arc::new(Mutex::new(buckets)),
+        }
+    }
+    /// Changes the limits for `direction`, including for running transfers.
+    pub fn set_limits(&self, direction: Direction, limits: RateLimits) {
+        let mut buckets = self.buckets.lock().unwrap();
+        match direction {
+            Direction::Download => buckets.download = limits,
+            Direction::Upload => buckets.upload = limits,
+        }
+        if let Some(total) = buckets.totals.get(&direction) {
+            total.set_rate(limits.total);
+        }
+        buckets
+            .accounts
+            .iter()
+            .filter(|((bucket_direction, _), _)| *bucket_direction == direction)
+            .for_each(|(_, bucket)| bucket.set_rate(limits.account));
+    }
+    /// Whether any rate applies to transfers in `direction`.
+    pub fn is_limited(&self, direction: Direction) -> bool {
+        let limits = self.buckets.lock().unwrap().limits(direction);
+        [limits.total, limits.account]
+            .into_iter()
+            .any(|rate| rate.is_some_and(|rate| rate > 0))
+    }
+    /// Limits the bytes read from `inner` by a transfer of `login`.
+    pub fn throttle<R>(
+        &self,
+        direction: Direction,
+        login: Option<String>,
+        inner: R,
+    ) -> Throttled<R> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let limits = buckets.limits(direction);
+        let total = buckets
+            .totals
+            .entry(direction)
+            .or_insert_with(|| Arc::new(Bucket::new(limits.total)))
+            .clone();
+        // Forget the accounts which have no transfers left.
+        buckets
+            .accounts
+            .retain(|_, bucket| Arc::strong_count(bucket) > 1);
+        let account = buckets
+            .accounts
+            .entry((direction, login))
+            .or_insert_with(|| Arc::new(Bucket::new(limits.account)))
+            .clone();
+        Throttled {
+            inner,
+            buckets: [total, account],
+            delay: None,
+        }
+    }
+}
+
+/// A reader which keeps to the rates of its buckets.
+pub struct Throttled<R> {
+    inner: R,
+    buckets: [Arc<Bucket>; 2],
+    delay: Option<BoxFuture<'static, ()>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        let filled = buf.filled().len();
+        if let Poll::Ready(result) = Pin::new(&mut self.inner).poll_read(cx, buf) {
+            result?;
+            let read = buf.filled().len() - filled;
+            let wait = self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.charge(read))
+                .max()
+                .unwrap_or_default();
+            if !wait.is_zero() {
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt as _;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttles_share_rate() -> io::Result<()> {
+        let throttles = Throttles::new(
+            RateLimits {
+                total: Some(1000),
+                account: None,
+            },
+            RateLimits::default(),
+        );
+        let data = vec![0u8; 3000];
+        let start = tokio::time::Instant::now();
+        let read = |login: &str| {
+            let mut reader = throttles.throttle(Direction::Download, Some(login.into()), &data[..]);
+            async move {
+                let mut sink = vec![];
+                reader.read_to_end(&mut sink).await.map(|_| sink.len())
+            }
+        };
+        let (alice, bob) = tokio::join!(read("alice"), read("bob"));
+        assert_eq!((alice?, bob?), (3000, 3000));
+        // A second's worth of bytes comes out of the full bucket right away.
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((4.5..=5.5).contains(&elapsed), "took {elapsed}s");
+
+        throttles.set_limits(Direction::Download, RateLimits::default());
+        let start = tokio::time::Instant::now();
+        read("alice").await?;
+        assert!(start.elapsed() < Duration::from_millis(1));
+
+        let mut upload = throttles.throttle(Direction::Upload, None, &data[..]);
+        upload.read_to_end(&mut vec![]).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_idle_accounts_are_forgotten() {
+        let throttles = Throttles::new(RateLimits::default(), RateLimits::default());
+        let alice = throttles.throttle(Direction::Download, Some("alice".into()), &b""[..]);
+        drop(throttles.throttle(Direction::Download, Some("bob".into()), &b""[..]));
+        let _carol = throttles.throttle(Direction::Upload, Some("carol".into()), &b""[..]);
+        let accounts = |throttles: &Throttles| {
+            let buckets = throttles.buckets.lock().unwrap();
+            let mut logins: Vec<_> = buckets.accounts.keys().cloned().collect();
+            logins.sort_by(|a, b| a.1.cmp(&b.1));
+            logins
+        };
+        assert_eq!(
+            accounts(&throttles),
+            [
+                (Direction::Download, Some("alice".into())),
+                (Direction::Upload, Some("carol".into()))
+            ]
+        );
+        drop(alice);
+        drop(throttles.throttle(Direction::Download, None, &b""[..]));
+        assert_eq!(
+            accounts(&throttles),
+            [
+                (Direction::Download, None),
+                (Direction::Upload, Some("carol".into()))
+            ]
+        );
+    }
+}
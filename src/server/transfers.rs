@@ -1,20 +1,76 @@
 use deku::prelude::*;
 use derive_more::{From, Into};
+use futures::StreamExt as _;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::IpAddr,
     num::TryFromIntError,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
 use tokio::{
     io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::{mpsc, oneshot, watch},
+    time::Instant,
 };
 use tracing::{debug, error, warn};
 
-use crate::apple;
-use crate::protocol::{self as proto, HotlineProtocol, ReferenceNumber};
-use crate::server::{bus::Bus, files::OsFiles};
+use crate::protocol::{self as proto, HotlineProtocol, ReferenceNumber, UserId};
+use crate::server::{
+    application::{Files, UserAccount},
+    bus::{Bus, Notification, Notifications},
+    files::{
+        checksum::Hashing,
+        hidden::STAGING_PREFIX,
+        homes::HomeFolders,
+        moderation::{Moderation, PendingUpload},
+        quotas::{QuotaError, Quotas},
+    },
+    hooks::{Event, Hooks},
+    progress::{Tracked, Tracker, TransferProgress},
+    sendfile::SendFile,
+    throttle::{Direction, RateLimits, Throttles},
+    DownloadInfo, TransfersProgress, UploadComplete, UploadPending,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransfersConfig {
+    /// How many seconds a transfer request waits for its connection before
+    /// it is forgotten.
+    pub expiry: u64,
+    /// The most transfers which may run at once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    /// The most transfers which may run at once for a single account, where
+    /// all guests count as one account.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<usize>,
+    /// Bandwidth limits for downloads. Both rates are reloaded from the
+    /// configuration file when the server is sent SIGHUP.
+    pub download_rate: RateLimits,
+    /// Bandwidth limits for uploads.
+    pub upload_rate: RateLimits,
+    /// The largest fork, in bytes, which is sent compressed when a client
+    /// asks for it. Compressed forks are kept in memory until they are sent.
+    pub max_compressed_fork: u64,
+}
+
+impl Default for TransfersConfig {
+    fn default() -> Self {
+        Self {
+            expiry: 60,
+            total: None,
+            account: None,
+            download_rate: Default::default(),
+            upload_rate: Default::default(),
+            max_compressed_fork: 8 << 20,
+        }
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum TransferError {
@@ -26,14 +82,66 @@ pub enum TransferError {
     FileSize(#[from] TryFromIntError),
     #[error("invalid upload or download request id")]
     InvalidRequest,
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("file already exists")]
+    AlreadyExists,
+    #[error("{0}")]
+    Quota(#[from] QuotaError),
+    #[error("refused by hook")]
+    Refused,
 }
 
 type TransferResult<T> = Result<T, TransferError>;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 enum Request {
-    FileDownload { root: PathBuf, path: PathBuf },
-    FileUpload { root: PathBuf, path: PathBuf },
+    FileDownload {
+        path: PathBuf,
+        account: Option<UserAccount>,
+        user_id: UserId,
+        address: IpAddr,
+        /// The forks to send compressed with zlib, already compressed.
+        compressed: BTreeMap<proto::ForkType, Arc<[u8]>>,
+    },
+    FileUpload {
+        path: PathBuf,
+        account: Option<UserAccount>,
+        user_id: UserId,
+        address: IpAddr,
+    },
+}
+
+impl Request {
+    /// The user who made the request and the login of their account.
+    fn owner(&self) -> (UserId, Option<String>) {
+        let (Self::FileDownload {
+            account, user_id, ..
+        }
+        | Self::FileUpload {
+            account, user_id, ..
+        }) = self;
+        let login = account
+            .as_ref()
+            .map(|account| account.identity.login.clone());
+        (*user_id, login)
+    }
+    /// Whether `address` is where the request came from.
+    fn made_from(&self, address: IpAddr) -> bool {
+        let (Self::FileDownload { address: from, .. } | Self::FileUpload { address: from, .. }) =
+            self;
+        from.to_canonical() == address.to_canonical()
+    }
+    fn direction(&self) -> Direction {
+        match self {
+            Self::FileDownload { .. } => Direction::Download,
+            Self::FileUpload { .. } => Direction::Upload,
+        }
+    }
+    fn path(&self) -> &Path {
+        let (Self::FileDownload { path, .. } | Self::FileUpload { path, .. }) = self;
+        path
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -54,84 +162,179 @@ impl From<proto::UploadFileReply> for TransferReply {
     }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
+struct Pending {
+    request: Request,
+    /// The size of the transfer, where it is known before it starts.
+    size: u64,
+    expires: Instant,
+    /// Whether a connection has taken the request.
+    claimed: bool,
+}
+
+/// Requests waiting for or served by transfer connections.
+///
+/// Reference numbers are random so that they cannot be guessed by other
+/// clients, and each may be used by only one connection from the address
+/// which made the request.
+#[derive(Debug, Clone)]
 pub struct Requests {
-    requests: HashMap<ReferenceNumber, Request>,
-    next_id: u32,
+    requests: HashMap<ReferenceNumber, Pending>,
+    expiry: Duration,
 }
 
 impl Requests {
-    fn new() -> Self {
+    fn new(expiry: Duration) -> Self {
         Self {
             requests: Default::default(),
-            next_id: u32::MIN,
+            expiry,
         }
     }
-    fn add_download(&mut self, root: PathBuf, path: PathBuf) -> ReferenceNumber {
+    fn add(&mut self, request: Request, size: u64) -> ReferenceNumber {
         let id = self.next_id();
-        self.requests
-            .insert(id, Request::FileDownload { root, path });
+        let pending = Pending {
+            request,
+            size,
+            expires: Instant::now() + self.expiry,
+            claimed: false,
+        };
+        self.requests.insert(id, pending);
         debug!("added transfer {id:?}, size={}", self.requests.len());
         id
     }
-    fn add_upload(&mut self, root: PathBuf, path: PathBuf) -> ReferenceNumber {
-        let id = self.next_id();
-        self.requests.insert(id, Request::FileUpload { root, path });
-        id
+    /// The request `id` if a connection from `address` has claimed it.
+    fn claimed(&self, id: ReferenceNumber, address: IpAddr) -> Option<&Request> {
+        self.requests
+            .get(&id)
+            .filter(|pending| pending.claimed && pending.request.made_from(address))
+            .map(|pending| &pending.request)
     }
-    fn get(&self, id: ReferenceNumber) -> Option<&Request> {
-        self.requests.get(&id)
+    /// Hands the request `id` to a connection from `address`, unless another
+    /// connection has taken it or it has expired.
+    fn claim(&mut self, id: ReferenceNumber, address: IpAddr) -> Option<&Pending> {
+        let pending = self.requests.get_mut(&id)?;
+        if pending.claimed || pending.expires <= Instant::now() {
+            return None;
+        }
+        if !pending.request.made_from(address) {
+            return None;
+        }
+        pending.claimed = true;
+        Some(pending)
     }
     fn remove(&mut self, id: ReferenceNumber) {
         self.requests.remove(&id);
         warn!("removed transfer {id:?}, size={}", self.requests.len());
     }
-    fn next_id(&mut self) -> ReferenceNumber {
-        let id = self.next_id.into();
-        self.next_id += 1;
-        id
+    /// Forgets unclaimed requests which have expired.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.requests
+            .retain(|_, pending| pending.claimed || pending.expires > now);
+    }
+    /// Forgets the unclaimed requests of a user who has disconnected.
+    fn forget_user(&mut self, user_id: UserId) {
+        self.requests
+            .retain(|_, pending| pending.claimed || pending.request.owner().0 != user_id);
+    }
+    fn next_id(&self) -> ReferenceNumber {
+        loop {
+            let id = rand::random::<u32>().into();
+            if !self.requests.contains_key(&id) {
+                return id;
+            }
+        }
     }
 }
 
+/// What a connection needs to know to send a download.
+struct Download {
+    path: PathBuf,
+    login: Option<String>,
+    compressed: BTreeMap<proto::ForkType, Arc<[u8]>>,
+}
+
 pub struct TransferConnection<S> {
-    files: OsFiles,
+    files: Arc<dyn Files>,
+    homes: HomeFolders,
+    quotas: Quotas,
+    moderation: Moderation,
     transfers: TransfersService,
     requests: watch::Receiver<Requests>,
-    socket: S,
+    socket: Tracked<S>,
+    peer: IpAddr,
 }
 
 impl<S> TransferConnection<S> {
+    /// The request `id`, which must have come from the peer of this
+    /// connection and been claimed by it when it started.
     fn get_request(&self, id: ReferenceNumber) -> TransferResult<Request> {
         self.requests
             .borrow()
-            .get(id)
+            .claimed(id, self.peer)
             .cloned()
             .ok_or(TransferError::InvalidRequest)
     }
-    fn get_file_download(&self, id: ReferenceNumber) -> TransferResult<PathBuf> {
-        match self.get_request(id)? {
-            Request::FileDownload { path, .. } => Ok(path),
+    fn get_file_download(&self, id: ReferenceNumber) -> TransferResult<Download> {
+        let request = self.get_request(id)?;
+        let (_, login) = request.owner();
+        match request {
+            Request::FileDownload {
+                path,
+                account,
+                compressed,
+                ..
+            } => Ok(Download {
+                path: self.authorize(path, account)?,
+                login,
+                compressed,
+            }),
             _ => Err(TransferError::InvalidRequest),
         }
     }
-    fn get_file_upload(&self, id: ReferenceNumber) -> TransferResult<PathBuf> {
+    fn get_file_upload(
+        &self,
+        id: ReferenceNumber,
+    ) -> TransferResult<(PathBuf, Option<UserAccount>)> {
         match self.get_request(id)? {
-            Request::FileUpload { path, .. } => Ok(path),
+            Request::FileUpload { path, account, .. } => {
+                let path = self.authorize(path, account.clone())?;
+                Ok((path, account))
+            }
             _ => Err(TransferError::InvalidRequest),
         }
     }
+    /// Keeps requests from reaching into the home folders of other accounts
+    /// and, unless they come from moderators, into the pending folder.
+    fn authorize(&self, path: PathBuf, account: Option<UserAccount>) -> TransferResult<PathBuf> {
+        let account = account.as_ref();
+        if self.homes.allows(account, &path) && self.moderation.allows(account, &path) {
+            Ok(path)
+        } else {
+            Err(TransferError::PermissionDenied)
+        }
+    }
 }
 
-impl<S: AsyncRead + AsyncWrite + Unpin + Send> TransferConnection<S> {
+impl<S: AsyncRead + AsyncWrite + SendFile + Unpin + Send> TransferConnection<S> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         socket: S,
-        files: OsFiles,
+        peer: IpAddr,
+        files: Arc<dyn Files>,
+        homes: HomeFolders,
+        quotas: Quotas,
+        moderation: Moderation,
         transfers: TransfersService,
         requests: watch::Receiver<Requests>,
     ) -> Self {
         Self {
-            socket,
+            socket: Tracked::new(socket),
+            peer,
             files,
+            homes,
+            quotas,
+            moderation,
             transfers,
             requests,
         }
@@ -146,6 +349,13 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> TransferConnection<S> {
             format!("{:#x}", u32::from(handshake.reference)),
         );
         let id = handshake.reference;
+        let tracker = self.socket.tracker();
+        tracker.reset();
+        let size = i32::from(handshake.size).max(0) as u64;
+        if let Err(e) = transfers.start(id, self.peer, size, tracker).await {
+            error!("rejected transfer: {e:?}");
+            return Ok(());
+        }
         let result = if handshake.is_upload() {
             self.handle_file_upload(id, handshake.size).await
         } else {
@@ -165,32 +375,51 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> TransferConnection<S> {
         Ok(handshake)
     }
     async fn write_fork(
-        socket: &mut S,
+        socket: &mut Tracked<S>,
         header: proto::ForkHeader,
         body: proto::AsyncDataSource,
-    ) -> io::Result<u64> {
+        throttles: &Throttles,
+        login: Option<String>,
+        compressed: Option<Arc<[u8]>>,
+    ) -> TransferResult<u64> {
+        let mut header = header;
+        if let Some(compressed) = compressed {
+            header.data_size = compressed.len().into();
+            header.compression_type = proto::CompressionType::Zlib;
+            socket.write_all(&header.to_bytes().unwrap()).await?;
+            let mut fork = throttles.throttle(Direction::Download, login, &compressed[..]);
+            let bytes = tokio::io::copy(&mut fork, socket).await?;
+            return Ok(bytes);
+        }
+        header.compression_type = proto::CompressionType::None;
         let bytes = header.to_bytes().unwrap();
         socket.write_all(&bytes).await?;
+        let len = i32::from(header.data_size) as u32 as u64;
+        if !throttles.is_limited(Direction::Download) {
+            if let Some(file) = body.file() {
+                if let Some(sent) = socket.send_file(file, len).await {
+                    return Ok(sent?);
+                }
+            }
+        }
         let (len, fork) = body.into();
-        let mut fork = fork.take(len);
+        let mut fork = throttles.throttle(Direction::Download, login, fork.take(len));
         let bytes = tokio::io::copy(&mut fork, socket).await?;
         Ok(bytes)
     }
-    fn get_appledouble(path: &Path) -> PathBuf {
-        let basename = path
-            .file_name()
-            .and_then(|name| name.to_str())
-            .map(|name| format!("._{}", name))
-            .expect("no filename");
-        path.to_path_buf().with_file_name(basename)
-    }
     async fn handle_file_download(self, id: ReferenceNumber) -> TransferResult<()> {
-        let path = self.get_file_download(id)?;
+        let Download {
+            path,
+            login,
+            compressed,
+        } = self.get_file_download(id)?;
         let Self {
             mut socket,
             files,
+            transfers,
             ..
         } = self;
+        let throttles = transfers.throttles();
         let mut file = files.read(&path).await?;
         let (info_header, info) = file.info();
         let header = file.header();
@@ -200,158 +429,497 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> TransferConnection<S> {
         socket.write_all(&info_header).await?;
         let info = info.to_bytes().unwrap();
         socket.write_all(&info).await?;
-        if let Some((header, body)) = file.take_fork(proto::ForkType::Resource) {
-            let size = Self::write_fork(&mut socket, header, body).await?;
-            tracing::Span::current().record("rsrc_size", size);
-        }
-        if let Some((header, body)) = file.take_fork(proto::ForkType::Data) {
-            let size = Self::write_fork(&mut socket, header, body).await?;
-            tracing::Span::current().record("data_size", size);
+        for (fork_type, field) in [
+            (proto::ForkType::Resource, "rsrc_size"),
+            (proto::ForkType::Data, "data_size"),
+        ] {
+            let Some((header, body)) = file.take_fork(fork_type) else {
+                continue;
+            };
+            let compressed = compressed.get(&fork_type).cloned();
+            let size = Self::write_fork(
+                &mut socket,
+                header,
+                body,
+                throttles,
+                login.clone(),
+                compressed,
+            )
+            .await?;
+            tracing::Span::current().record(field, size);
         }
         debug!("done");
+        transfers
+            .hooks()
+            .notify(Event::DownloadComplete { path, login });
         Ok(())
     }
     async fn handle_file_upload(
         mut self,
         id: ReferenceNumber,
-        _: proto::DataSize,
+        size: proto::DataSize,
     ) -> TransferResult<()> {
-        let path = self.get_file_upload(id)?;
-        let header = self.read_file_header().await?;
-        debug!("got header {header:?}");
-        let _finf_header = self.read_fork_header().await?;
-        let finf = self.read_file_info().await?;
-        debug!("got finf {finf:?}");
-        for _ in 1..header.fork_count.into() {
-            let fork_header = self.read_fork_header().await?;
-            let size = i32::from(fork_header.data_size) as u64;
-            match fork_header.fork_type {
+        let (path, account) = self.get_file_upload(id)?;
+        // The size of the whole flattened file, from the handshake.
+        let declared = i32::from(size).max(0) as u64;
+        self.quotas
+            .check(self.files.as_ref(), account.as_ref(), &path, declared)
+            .await?;
+        let folder = if self.moderation.holds(account.as_ref()) {
+            self.moderation.folder()
+        } else {
+            path.parent().unwrap_or(Path::new(""))
+        };
+        let staging = staging_path(folder, id);
+        let mut started = false;
+        let result = self
+            .receive_file(&staging, &path, account.as_ref(), &mut started)
+            .await;
+        let result = match result {
+            Ok(received) => match self
+                .vet_upload(&staging, &path, account.as_ref(), received)
+                .await
+            {
+                Ok(()) => self
+                    .commit_upload(&staging, &path, account.as_ref())
+                    .await
+                    .map(|stored| (stored, received)),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((stored, received)) => {
+                self.quotas
+                    .record(account.as_ref(), &stored, received)
+                    .await;
+                if !self.moderation.holds(account.as_ref()) {
+                    let login = account.map(|account| account.identity.login);
+                    self.transfers.upload_complete(stored, login);
+                }
+                debug!("done");
+                Ok(())
+            }
+            Err(e) => {
+                if started {
+                    if let Err(e) = self.files.remove(&staging).await {
+                        warn!("failed to discard incomplete upload {staging:?}: {e:?}");
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+    /// Runs the upload hooks on a complete upload of `size` bytes, which
+    /// refuse it if any of them fails.
+    async fn vet_upload(
+        &self,
+        staging: &Path,
+        path: &Path,
+        account: Option<&UserAccount>,
+        size: u64,
+    ) -> TransferResult<()> {
+        let hooks = self.transfers.hooks();
+        let info = self.files.get_info(staging).await?;
+        let event = Event::UploadComplete {
+            path: path.to_path_buf(),
+            host_path: self.files.host_path(staging).await?,
+            login: account.map(|account| account.identity.login.clone()),
+            size,
+            sha256: info.checksum.map(|checksum| checksum.to_string()),
+        };
+        if hooks.check(&event).await {
+            Ok(())
+        } else {
+            Err(TransferError::Refused)
+        }
+    }
+    /// Moves a complete upload from `staging` to `path`, unless another
+    /// upload has taken the name meanwhile and `account` may not replace it,
+    /// and returns where it was stored.
+    ///
+    /// Uploads held for moderation go to the pending folder instead.
+    async fn commit_upload(
+        &self,
+        staging: &Path,
+        path: &Path,
+        account: Option<&UserAccount>,
+    ) -> TransferResult<PathBuf> {
+        if self.moderation.holds(account) {
+            let files = self.files.as_ref();
+            let pending = self.moderation.hold(files, path, account).await?;
+            if let Err(e) = files.replace(staging, &pending, false).await {
+                self.moderation.release(&pending).await;
+                return Err(e.into());
+            }
+            if let Some(upload) = self.moderation.pending(&pending) {
+                self.transfers.upload_pending(pending.clone(), upload);
+            }
+            return Ok(pending);
+        }
+        let overwrite = account.is_some_and(UserAccount::can_overwrite_files);
+        match self.files.replace(staging, path, overwrite).await {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(TransferError::AlreadyExists),
+            result => Ok(result.map(|_| path.to_path_buf())?),
+        }
+    }
+    /// Stores the forks of an upload at `staging`, checking quotas for
+    /// `path` before each one, and returns the number of bytes received.
+    async fn receive_file(
+        &mut self,
+        staging: &Path,
+        path: &Path,
+        account: Option<&UserAccount>,
+        started: &mut bool,
+    ) -> TransferResult<u64> {
+        let login = account.map(|account| account.identity.login.clone());
+        let throttles = self.transfers.throttles();
+        let socket = throttles.throttle(Direction::Upload, login, &mut self.socket);
+        let mut decoder = proto::FlattenedFileDecoder::new(socket).await?;
+        debug!("got header {:?}", decoder.header());
+        let mut finf = None;
+        let mut wrote_metadata = false;
+        let mut checksum = None;
+        let mut received = 0u64;
+        while let Some(mut fork) = decoder.next_fork().await? {
+            let size = fork.size();
+            if matches!(
+                fork.fork_type(),
+                proto::ForkType::Data | proto::ForkType::Resource
+            ) {
+                received += size;
+                self.quotas
+                    .check(self.files.as_ref(), account, path, received)
+                    .await?;
+                *started = true;
+            }
+            let compression = fork.header().compression_type.clone();
+            match fork.fork_type() {
+                proto::ForkType::Info => {
+                    let info = fork.read_info().await?;
+                    debug!("got finf {info:?}");
+                    finf = Some(info);
+                }
                 proto::ForkType::Data => {
-                    debug!("data fork {size} => {path:?}");
-                    let mut socket = self.socket.take(size);
-                    let mut file = self.files.write(&path, 0).await?;
-                    tokio::io::copy(&mut socket, &mut file).await?;
-                    self.socket = socket.into_inner();
+                    debug!("data fork {size} ({compression:?}) => {staging:?}");
+                    // A compressed fork may inflate to far more than its
+                    // declared size, so it is cut off just past the quota.
+                    let room = self
+                        .quotas
+                        .remaining(self.files.as_ref(), account, path)
+                        .await?
+                        .map_or(u64::MAX, |room| room.saturating_sub(received - size));
+                    let mut file = self.files.write(staging, 0).await?;
+                    let body = compression.decode(&mut fork)?;
+                    let mut body = Hashing::new(body.take(room.saturating_add(1)));
+                    let written = tokio::io::copy(&mut body, &mut file)
+                        .await
+                        .map_err(proto::ProtocolError::from_io)?;
+                    file.shutdown().await?;
+                    checksum = Some(body.checksum());
+                    if written != size {
+                        received = received - size + written;
+                        self.quotas
+                            .check(self.files.as_ref(), account, path, received)
+                            .await?;
+                    }
                     debug!("copied data fork");
                 }
                 proto::ForkType::Resource => {
-                    let finf_descriptor = apple::EntryDescriptor {
-                        id: apple::EntryId::FinderInfo.into(),
-                        length: apple::FinderInfo::calculate_size() as u32,
-                        offset: 0,
-                    };
-                    let comment_descriptor = apple::EntryDescriptor {
-                        id: apple::EntryId::Comment.into(),
-                        length: finf.comment_len as u32,
-                        offset: 0,
-                    };
-                    let rsrc_descriptor = apple::EntryDescriptor {
-                        id: apple::EntryId::ResourceFork.into(),
-                        length: size as u32,
-                        offset: 0,
-                    };
-                    let entries = vec![finf_descriptor, comment_descriptor, rsrc_descriptor];
-                    let hdr = apple::AppleSingleHeader::new_double(entries);
-
-                    let flags_bytes: u32 = finf.platform_flags.into();
-                    let flags = apple::FinderFlags::from(flags_bytes as u16);
-                    let comment = finf.comment.as_slice();
-
-                    let finf = apple::FinderInfo {
-                        file_type: apple::FileType(finf.type_code.0.into()),
-                        creator: apple::Creator(finf.creator_code.0.into()),
-                        flags,
-                        location: Default::default(),
-                        folder: Default::default(),
-                    };
-
-                    let rsrc_path = Self::get_appledouble(&path);
-                    debug!("rsrc fork {size} => {rsrc_path:?}");
-                    let mut socket = self.socket.take(size);
-                    let mut file = self.files.write(&rsrc_path, 0).await?;
-                    file.write_all(hdr.to_bytes().unwrap().as_slice()).await?;
-                    file.write_all(finf.to_bytes().unwrap().as_slice()).await?;
-                    file.write_all(comment).await?;
-                    tokio::io::copy(&mut socket, &mut file).await?;
-                    self.socket = socket.into_inner();
+                    debug!("rsrc fork {size} ({compression:?}) => {staging:?}");
+                    let finf = finf.as_ref().ok_or(proto::ProtocolError::MissingInfoFork)?;
+                    if compression == proto::CompressionType::None {
+                        self.files
+                            .write_metadata(staging, finf, &mut fork, size)
+                            .await
+                            .map_err(proto::ProtocolError::from_io)?;
+                    } else {
+                        // The resource fork is stored after a header giving
+                        // its length, so it is decompressed in memory first.
+                        let mut rsrc = vec![];
+                        compression
+                            .decode(&mut fork)?
+                            .take(MAX_DECOMPRESSED_RESOURCE_FORK + 1)
+                            .read_to_end(&mut rsrc)
+                            .await
+                            .map_err(proto::ProtocolError::from_io)?;
+                        let written = rsrc.len() as u64;
+                        if written > MAX_DECOMPRESSED_RESOURCE_FORK {
+                            let fork_type = proto::ForkType::Resource;
+                            return Err(proto::ProtocolError::ForkTooLarge(fork_type).into());
+                        }
+                        received = received - size + written;
+                        self.quotas
+                            .check(self.files.as_ref(), account, path, received)
+                            .await?;
+                        self.files
+                            .write_metadata(staging, finf, &mut &rsrc[..], written)
+                            .await?;
+                    }
+                    wrote_metadata = true;
                     debug!("copied rsrc fork");
                 }
-                fork => {
-                    error!("ignoring {fork:?} fork");
-                    tokio::io::copy(&mut self.socket, &mut tokio::io::sink()).await?;
-                }
+                fork_type => error!("skipping {fork_type:?} fork"),
             }
         }
+        let finf = finf.ok_or(proto::ProtocolError::MissingInfoFork)?;
 
-        debug!("done");
+        *started = true;
+        if !wrote_metadata {
+            self.files
+                .write_metadata(staging, &finf, &mut io::empty(), 0)
+                .await?;
+        }
+        if let Some(checksum) = checksum {
+            debug!("data fork checksum {checksum}");
+            self.files.set_checksum(staging, checksum).await?;
+        }
+        self.files.set_times(staging, &finf).await?;
 
-        Ok(())
+        Ok(received)
     }
-    async fn read_file_header(&mut self) -> TransferResult<proto::FlattenedFileHeader> {
-        let mut buf = [0u8; 24];
-        self.socket.read_exact(&mut buf).await?;
-        match proto::FlattenedFileHeader::try_from(&buf[..]) {
-            Ok(header) => Ok(header),
-            _ => Err(proto::ProtocolError::ParseHeader.into()),
+}
+
+/// The largest compressed resource fork accepted in an upload, once
+/// decompressed.
+const MAX_DECOMPRESSED_RESOURCE_FORK: u64 = 16 << 20;
+
+/// The forks of `path` which shrink when compressed with `compression`,
+/// compressed, leaving out those longer than `max_len`.
+///
+/// The compressed forks are what the download sends, so that they are only
+/// compressed once and the sizes offered to the client are those sent even
+/// if the file changes in the meantime.
+async fn compress_forks(
+    files: &dyn Files,
+    path: &Path,
+    compression: &proto::CompressionType,
+    max_len: u64,
+) -> TransferResult<BTreeMap<proto::ForkType, Arc<[u8]>>> {
+    let mut file = files.read(path).await?;
+    let mut forks = BTreeMap::new();
+    for fork_type in [proto::ForkType::Data, proto::ForkType::Resource] {
+        let Some((_, body)) = file.take_fork(fork_type) else {
+            continue;
+        };
+        let (len, fork) = body.into();
+        if len > max_len {
+            continue;
         }
-    }
-    async fn read_fork_header(&mut self) -> TransferResult<proto::ForkHeader> {
-        let mut buf = [0u8; 16];
-        self.socket.read_exact(&mut buf).await?;
-        match proto::ForkHeader::try_from(&buf[..]) {
-            Ok(header) => Ok(header),
-            _ => Err(proto::ProtocolError::ParseHeader.into()),
-        }
-    }
-    async fn read_file_info(&mut self) -> TransferResult<proto::InfoFork> {
-        let mut buf = vec![0u8; 72];
-        self.socket.read_exact(&mut buf[..72]).await?;
-        let filename_len = i16::from_be_bytes([buf[70], buf[71]]) as usize;
-        let mut filename = vec![0u8; filename_len + 2];
-        self.socket
-            .read_exact(&mut filename[..filename_len + 2])
+        let mut compressed = vec![];
+        compression
+            .encode(fork.take(len))?
+            .read_to_end(&mut compressed)
             .await?;
-        buf.extend(&filename);
-        let comment_len =
-            i16::from_be_bytes([filename[filename_len], filename[filename_len + 1]]) as usize;
-        if comment_len > 0 {
-            let mut comment = vec![0u8; comment_len];
-            self.socket.read_exact(&mut comment[..comment_len]).await?;
-            buf.extend(&comment);
-        }
-        match proto::InfoFork::try_from(&buf[..]) {
-            Ok(info) => Ok(info),
-            _ => Err(proto::ProtocolError::ParseHeader.into()),
+        if (compressed.len() as u64) < len {
+            forks.insert(fork_type, compressed.into());
         }
     }
+    Ok(forks)
+}
+
+/// Where an upload into `folder` is kept until all of it has arrived, under
+/// a name which is always hidden from clients.
+fn staging_path(folder: &Path, id: ReferenceNumber) -> PathBuf {
+    folder.join(format!("{STAGING_PREFIX}{:08x}", u32::from(id)))
 }
 
 enum Command {
     Transfer(Request, oneshot::Sender<TransferReply>),
+    Start {
+        reference: ReferenceNumber,
+        address: IpAddr,
+        size: u64,
+        tracker: Arc<Tracker>,
+        start: oneshot::Sender<()>,
+    },
     Complete(ReferenceNumber, oneshot::Sender<()>),
+    Cancel(ReferenceNumber, oneshot::Sender<bool>),
+}
+
+/// A connection waiting for a transfer slot.
+#[derive(Debug)]
+struct Waiting {
+    reference: ReferenceNumber,
+    user_id: UserId,
+    login: Option<String>,
+    start: oneshot::Sender<()>,
+}
+
+/// The transfers which are running and those queued behind the limits.
+#[derive(Debug, Default)]
+struct Slots {
+    limits: TransfersConfig,
+    running: HashMap<ReferenceNumber, Option<String>>,
+    waiting: VecDeque<Waiting>,
+}
+
+impl Slots {
+    fn new(limits: TransfersConfig) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+    fn admits(&self, login: &Option<String>) -> bool {
+        let total = self.running.len();
+        let own = self
+            .running
+            .values()
+            .filter(|owner| *owner == login)
+            .count();
+        self.limits.total.is_none_or(|limit| total < limit)
+            && self.limits.account.is_none_or(|limit| own < limit)
+    }
+    /// The queue position a new request would get, if it has to wait.
+    fn waiting_count(&self, login: &Option<String>) -> Option<proto::WaitingCount> {
+        if self.waiting.is_empty() && self.admits(login) {
+            None
+        } else {
+            Some((self.waiting.len() as i32 + 1).into())
+        }
+    }
+    /// Starts `waiting` right away if the limits allow it, and queues it
+    /// otherwise, returning its position in the queue.
+    fn start(&mut self, waiting: Waiting) -> Option<proto::WaitingCount> {
+        if self.admits(&waiting.login) {
+            self.run(waiting);
+            None
+        } else {
+            self.waiting.push_back(waiting);
+            Some((self.waiting.len() as i32).into())
+        }
+    }
+    fn run(&mut self, waiting: Waiting) {
+        let Waiting {
+            reference,
+            login,
+            start,
+            ..
+        } = waiting;
+        if start.send(()).is_ok() {
+            self.running.insert(reference, login);
+        }
+    }
+    /// Frees the slot of `reference` and starts whichever queued transfers
+    /// now fit, returning whether the queue changed.
+    fn finish(&mut self, reference: ReferenceNumber) -> bool {
+        self.running.remove(&reference);
+        let queued = self.waiting.len();
+        self.waiting
+            .retain(|waiting| waiting.reference != reference && !waiting.start.is_closed());
+        let mut index = 0;
+        while index < self.waiting.len() {
+            if self.admits(&self.waiting[index].login) {
+                let waiting = self.waiting.remove(index).unwrap();
+                self.run(waiting);
+            } else {
+                index += 1;
+            }
+        }
+        self.waiting.len() != queued
+    }
+    /// The queue positions to tell waiting users about.
+    fn positions(&self) -> impl Iterator<Item = DownloadInfo> + '_ {
+        self.waiting.iter().zip(1i32..).map(|(waiting, position)| {
+            DownloadInfo(waiting.user_id, waiting.reference, position.into())
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TransfersService {
-    _bus: Bus,
+    bus: Bus,
     tx: mpsc::Sender<Command>,
+    files: Arc<dyn Files>,
+    throttles: Throttles,
+    progress: watch::Receiver<Vec<TransferProgress>>,
+    hooks: Hooks,
+    max_compressed_fork: u64,
 }
 
 impl TransfersService {
-    pub fn new(bus: Bus) -> (Self, TransfersUpdateProcessor) {
+    pub fn new(
+        bus: Bus,
+        files: Arc<dyn Files>,
+        limits: TransfersConfig,
+        hooks: Hooks,
+    ) -> (Self, TransfersUpdateProcessor) {
         let (tx, rx) = mpsc::channel(10);
-        let service = Self { _bus: bus, tx };
-        let process = TransfersUpdateProcessor::new(rx);
+        let throttles = Throttles::new(limits.download_rate, limits.upload_rate);
+        let max_compressed_fork = limits.max_compressed_fork;
+        let (progress_tx, progress) = watch::channel(vec![]);
+        let process =
+            TransfersUpdateProcessor::new(rx, files.clone(), bus.clone(), limits, progress_tx);
+        let service = Self {
+            bus,
+            tx,
+            files,
+            throttles,
+            progress,
+            hooks,
+            max_compressed_fork,
+        };
         (service, process)
     }
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+    /// The progress of every transfer which has been claimed by its
+    /// connection, updated every second.
+    pub fn progress(&self) -> watch::Receiver<Vec<TransferProgress>> {
+        self.progress.clone()
+    }
+    /// The progress of the transfers of `user_id`.
+    pub fn progress_of(&self, user_id: UserId) -> Vec<TransferProgress> {
+        self.progress
+            .borrow()
+            .iter()
+            .filter(|progress| progress.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+    /// Stops the running transfer `reference`, returning whether there was
+    /// one.
+    pub async fn cancel(&mut self, reference: ReferenceNumber) -> bool {
+        let Self { tx: queue, .. } = self;
+        let (tx, rx) = oneshot::channel();
+        let cmd = Command::Cancel(reference, tx);
+        queue.send(cmd).await.ok();
+        rx.await.unwrap_or(false)
+    }
+    /// The bandwidth limits of running transfers, which may be changed at
+    /// any time.
+    pub fn throttles(&self) -> &Throttles {
+        &self.throttles
+    }
     pub async fn file_download(
         &mut self,
-        root: PathBuf,
         path: PathBuf,
+        account: Option<UserAccount>,
+        user_id: UserId,
+        address: IpAddr,
+        compression: proto::CompressionType,
     ) -> Option<proto::DownloadFileReply> {
+        let compressed = if compression == proto::CompressionType::None {
+            BTreeMap::new()
+        } else {
+            let max_len = self.max_compressed_fork;
+            compress_forks(self.files.as_ref(), &path, &compression, max_len)
+                .await
+                .inspect_err(|e| warn!("not compressing {path:?}: {e:?}"))
+                .unwrap_or_default()
+        };
         let Self { tx: queue, .. } = self;
         let (tx, rx) = oneshot::channel();
-        let cmd = Command::Transfer(Request::FileDownload { root, path }, tx);
+        let request = Request::FileDownload {
+            path,
+            account,
+            user_id,
+            address,
+            compressed,
+        };
+        let cmd = Command::Transfer(request, tx);
         queue.send(cmd).await.ok();
         if let Ok(TransferReply::FileDownload(reply)) = rx.await {
             Some(reply)
@@ -361,12 +929,20 @@ impl TransfersService {
     }
     pub async fn file_upload(
         &mut self,
-        root: PathBuf,
         path: PathBuf,
+        account: Option<UserAccount>,
+        user_id: UserId,
+        address: IpAddr,
     ) -> Option<proto::UploadFileReply> {
         let Self { tx: queue, .. } = self;
         let (tx, rx) = oneshot::channel();
-        let cmd = Command::Transfer(Request::FileUpload { root, path }, tx);
+        let request = Request::FileUpload {
+            path,
+            account,
+            user_id,
+            address,
+        };
+        let cmd = Command::Transfer(request, tx);
         queue.send(cmd).await.ok();
         if let Ok(TransferReply::FileUpload(reply)) = rx.await {
             Some(reply)
@@ -374,6 +950,40 @@ impl TransfersService {
             None
         }
     }
+    /// Lets moderators know that an upload is waiting at `path`.
+    pub fn upload_pending(&self, path: PathBuf, upload: PendingUpload) {
+        let notification = Notification::UploadPending(UploadPending(path, upload));
+        self.bus.publish(notification);
+    }
+    /// Lets others know that an upload was put into place at `path`.
+    pub fn upload_complete(&self, path: PathBuf, login: Option<String>) {
+        let notification = Notification::UploadComplete(UploadComplete(path, login));
+        self.bus.publish(notification);
+    }
+    /// Claims `reference` for a connection from `address` and waits until
+    /// the limits on concurrent transfers let it run.
+    ///
+    /// The connection counts its bytes in `tracker`, towards the `size` from
+    /// its handshake unless the request already knew its size.
+    pub async fn start(
+        &mut self,
+        reference: proto::ReferenceNumber,
+        address: IpAddr,
+        size: u64,
+        tracker: Arc<Tracker>,
+    ) -> TransferResult<()> {
+        let Self { tx: queue, .. } = self;
+        let (tx, rx) = oneshot::channel();
+        let cmd = Command::Start {
+            reference,
+            address,
+            size,
+            tracker,
+            start: tx,
+        };
+        queue.send(cmd).await.ok();
+        rx.await.map_err(|_| TransferError::InvalidRequest)
+    }
     pub async fn complete(&mut self, reference: proto::ReferenceNumber) -> TransferResult<()> {
         let Self { tx: queue, .. } = self;
         let (tx, rx) = oneshot::channel();
@@ -384,79 +994,765 @@ impl TransfersService {
     }
 }
 
+/// A claimed transfer whose progress is being sampled.
+#[derive(Debug)]
+struct Active {
+    request: Request,
+    total: u64,
+    tracker: Arc<Tracker>,
+    sampled: Instant,
+    done: u64,
+    rate: u64,
+}
+
+impl Active {
+    fn new(request: Request, total: u64, tracker: Arc<Tracker>) -> Self {
+        Self {
+            request,
+            total,
+            tracker,
+            sampled: Instant::now(),
+            done: 0,
+            rate: 0,
+        }
+    }
+    fn sample(&mut self) {
+        let now = Instant::now();
+        let done = self.tracker.done();
+        let elapsed = now.duration_since(self.sampled).as_secs_f64();
+        if elapsed > 0.0 {
+            self.rate = (done.saturating_sub(self.done) as f64 / elapsed) as u64;
+        }
+        self.sampled = now;
+        self.done = done;
+    }
+    fn progress(&self, reference: ReferenceNumber) -> TransferProgress {
+        let (user_id, login) = self.request.owner();
+        TransferProgress {
+            reference,
+            user_id,
+            login,
+            path: self.request.path().to_path_buf(),
+            direction: self.request.direction(),
+            done: self.done,
+            total: self.total,
+            rate: self.rate,
+        }
+    }
+}
+
 pub struct TransfersUpdateProcessor {
     queue: mpsc::Receiver<Command>,
+    files: Arc<dyn Files>,
+    bus: Bus,
+    slots: Slots,
     requests: Requests,
     updates: watch::Sender<Requests>,
+    notifications: Notifications,
+    progress: watch::Sender<Vec<TransferProgress>>,
 }
 
 impl TransfersUpdateProcessor {
-    fn new(queue: mpsc::Receiver<Command>) -> Self {
-        let requests = Requests::new();
+    fn new(
+        queue: mpsc::Receiver<Command>,
+        files: Arc<dyn Files>,
+        bus: Bus,
+        limits: TransfersConfig,
+        progress: watch::Sender<Vec<TransferProgress>>,
+    ) -> Self {
+        let requests = Requests::new(Duration::from_secs(limits.expiry));
         let (updates, _) = watch::channel(requests.clone());
+        let notifications = bus.subscribe();
         Self {
             queue,
+            files,
+            bus,
+            slots: Slots::new(limits),
             requests,
             updates,
+            notifications,
+            progress,
         }
     }
     #[tracing::instrument(name = "TransfersUpdateProcessor", skip(self))]
     pub async fn run(self) -> TransferResult<()> {
         let Self {
             mut queue,
+            files,
+            bus,
+            mut slots,
             mut requests,
             updates,
+            notifications,
+            progress,
         } = self;
-        while let Some(command) = queue.recv().await {
+        let mut active = HashMap::<ReferenceNumber, Active>::new();
+        let mut reaper = tokio::time::interval(requests.expiry.max(Duration::from_secs(1)));
+        let mut sampler = tokio::time::interval(Duration::from_secs(1));
+        let notifications = notifications.incoming();
+        tokio::pin!(notifications);
+        loop {
+            let command = tokio::select! {
+                command = queue.recv() => command,
+                _ = reaper.tick() => {
+                    requests.expire();
+                    updates.send(requests.clone()).ok();
+                    continue;
+                }
+                _ = sampler.tick() => {
+                    if !active.is_empty() || !progress.borrow().is_empty() {
+                        Self::publish_progress(&mut active, &progress, &bus);
+                    }
+                    continue;
+                }
+                Some(notification) = notifications.next() => {
+                    if let Notification::UserDisconnect(user) = notification {
+                        requests.forget_user(user.into());
+                        updates.send(requests.clone()).ok();
+                    }
+                    continue;
+                }
+            };
+            let Some(command) = command else {
+                break;
+            };
             match command {
-                Command::Transfer(Request::FileDownload { root, path }, tx) => {
-                    let reply = Self::handle_download(&root, &path, 0, &mut requests).await?;
-                    tx.send(reply.into()).ok();
+                // A request which cannot be served is dropped along with
+                // `tx`, which tells the requester, and the others carry on.
+                Command::Transfer(request @ Request::FileDownload { .. }, tx) => {
+                    let files = files.as_ref();
+                    match Self::handle_download(files, request, 0, &slots, &mut requests).await {
+                        Ok(reply) => {
+                            tx.send(reply.into()).ok();
+                        }
+                        Err(e) => warn!("refused download: {e:?}"),
+                    }
                 }
-                Command::Transfer(Request::FileUpload { root, path }, tx) => {
-                    let reply = Self::handle_upload(&root, &path, 0, &mut requests).await?;
-                    tx.send(reply.into()).ok();
+                Command::Transfer(request @ Request::FileUpload { .. }, tx) => {
+                    match Self::handle_upload(request, 0, &mut requests).await {
+                        Ok(reply) => {
+                            tx.send(reply.into()).ok();
+                        }
+                        Err(e) => warn!("refused upload: {e:?}"),
+                    }
+                }
+                Command::Start {
+                    reference: id,
+                    address,
+                    size,
+                    tracker,
+                    start: tx,
+                } => {
+                    let Some(pending) = requests.claim(id, address) else {
+                        warn!("refused transfer {id:?} from {address}");
+                        continue;
+                    };
+                    let request = pending.request.clone();
+                    let total = if pending.size > 0 { pending.size } else { size };
+                    let (user_id, login) = request.owner();
+                    active.insert(id, Active::new(request, total, tracker));
+                    // The connection reads its request once it may start.
+                    updates.send(requests.clone()).ok();
+                    let waiting = Waiting {
+                        reference: id,
+                        user_id,
+                        login,
+                        start: tx,
+                    };
+                    if let Some(position) = slots.start(waiting) {
+                        debug!("queued transfer {id:?} at {position:?}");
+                        let info = DownloadInfo(user_id, id, position);
+                        bus.publish(Notification::DownloadInfo(info));
+                    }
                 }
                 Command::Complete(id, tx) => {
                     requests.remove(id);
+                    active.remove(&id);
+                    if slots.finish(id) {
+                        for info in slots.positions() {
+                            bus.publish(Notification::DownloadInfo(info));
+                        }
+                    }
                     tx.send(()).ok();
                 }
+                Command::Cancel(id, tx) => {
+                    let running = active.get(&id);
+                    if let Some(running) = running {
+                        running.tracker.cancel();
+                    }
+                    tx.send(running.is_some()).ok();
+                }
             };
             updates.send(requests.clone()).ok();
         }
         Ok(())
     }
+    fn publish_progress(
+        active: &mut HashMap<ReferenceNumber, Active>,
+        progress: &watch::Sender<Vec<TransferProgress>>,
+        bus: &Bus,
+    ) {
+        let mut transfers = active
+            .iter_mut()
+            .map(|(reference, active)| {
+                active.sample();
+                active.progress(*reference)
+            })
+            .collect::<Vec<_>>();
+        transfers.sort_by_key(|progress| u32::from(progress.reference));
+        progress.send_replace(transfers.clone());
+        let notification = Notification::TransfersProgress(TransfersProgress(transfers));
+        bus.publish(notification);
+    }
     async fn handle_download(
-        root: &Path,
-        path: &Path,
+        files: &dyn Files,
+        request: Request,
         offset: u64,
+        slots: &Slots,
         requests: &mut Requests,
     ) -> TransferResult<proto::DownloadFileReply> {
-        let files = OsFiles::with_root(root).await?;
+        let Request::FileDownload {
+            path, compressed, ..
+        } = &request
+        else {
+            return Err(TransferError::InvalidRequest);
+        };
         let file = files.read(path).await?;
-        let file_size = file.fork_len(proto::ForkType::Data).unwrap_or(0)
-            + file.fork_len(proto::ForkType::Resource).unwrap_or(0);
+        let fork_sizes = [proto::ForkType::Data, proto::ForkType::Resource].map(|fork_type| {
+            let size = file.fork_len(fork_type).unwrap_or(0) as u64;
+            let sent = compressed.get(&fork_type).map(|fork| fork.len() as u64);
+            (size, sent.unwrap_or(size))
+        });
+        let file_size = fork_sizes.iter().map(|(size, _)| size).sum::<u64>();
+        let sent_size = fork_sizes.iter().map(|(_, sent)| sent).sum::<u64>();
         let (_, info) = file.info();
-        let transfer_size = info.size() as u64 + file_size as u64 - offset;
-        let reference = requests.add_download(root.to_path_buf(), path.to_path_buf());
+        let transfer_size = info.size() as u64 + sent_size - offset;
+        let waiting_count = slots.waiting_count(&request.owner().1);
         let reply = proto::DownloadFileReply {
             transfer_size: transfer_size.try_into()?,
             file_size: file_size.try_into()?,
-            reference,
-            waiting_count: None,
+            // Only added once the sizes fit, so that a refused request is
+            // not left behind.
+            reference: requests.add(request, transfer_size),
+            waiting_count,
         };
         Ok(reply)
     }
     async fn handle_upload(
-        root: &Path,
-        path: &Path,
+        request: Request,
         _offset: u64,
         requests: &mut Requests,
     ) -> TransferResult<proto::UploadFileReply> {
-        let reference = requests.add_upload(root.to_path_buf(), path.to_path_buf());
+        let reference = requests.add(request, 0);
         Ok(proto::UploadFileReply { reference })
     }
     pub fn subscribe(&self) -> watch::Receiver<Requests> {
         self.updates.subscribe()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        application::{FileOperation, HomeFolder, UserAccountPermissions},
+        files::{
+            checksum::Checksum, memory::MemoryFiles, moderation::ModerationConfig,
+            quotas::QuotaConfig,
+        },
+    };
+    use std::net::Ipv4Addr;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const UNCOMPRESSED: proto::CompressionType = proto::CompressionType::None;
+
+    /// What a transfer connection checks requests against.
+    #[derive(Default)]
+    struct Policies {
+        homes: HomeFolders,
+        quotas: Quotas,
+        moderation: Moderation,
+    }
+
+    /// A transfers service over a `MemoryFiles` with its processor running.
+    #[derive(Clone)]
+    struct Fixture {
+        files: Arc<dyn Files>,
+        transfers: TransfersService,
+        requests: watch::Receiver<Requests>,
+    }
+
+    impl Fixture {
+        fn new(memory: &MemoryFiles) -> Self {
+            Self::with(memory, Bus::new(), Default::default())
+        }
+        fn with(memory: &MemoryFiles, bus: Bus, limits: TransfersConfig) -> Self {
+            let files: Arc<dyn Files> = Arc::new(memory.clone());
+            let (transfers, processor) =
+                TransfersService::new(bus, files.clone(), limits, Hooks::default());
+            let requests = processor.subscribe();
+            tokio::spawn(processor.run());
+            Self {
+                files,
+                transfers,
+                requests,
+            }
+        }
+        /// Requests an uncompressed download of `path` for `account`.
+        async fn download(
+            &mut self,
+            path: &str,
+            account: Option<UserAccount>,
+        ) -> proto::TransferHandshake {
+            let reply = self
+                .transfers
+                .file_download(
+                    path.into(),
+                    account,
+                    UserId::default(),
+                    LOCALHOST,
+                    UNCOMPRESSED,
+                )
+                .await
+                .unwrap();
+            handshake(reply.reference, 0)
+        }
+        /// Requests an upload of `size` bytes to `path` for `account`.
+        async fn upload(
+            &mut self,
+            path: &str,
+            account: Option<UserAccount>,
+            size: usize,
+        ) -> proto::TransferHandshake {
+            let reply = self
+                .transfers
+                .file_upload(path.into(), account, UserId::default(), LOCALHOST)
+                .await
+                .unwrap();
+            handshake(reply.reference, size)
+        }
+        /// The flattened file a client downloading `path` receives.
+        async fn flattened(&mut self, path: &str) -> TransferResult<Vec<u8>> {
+            let handshake = self.download(path, None).await;
+            self.transfer(Policies::default(), handshake, &[]).await
+        }
+        /// Connects as a client sending `handshake` and then `upload`, and
+        /// returns what the server sends back.
+        async fn transfer(
+            &self,
+            policies: Policies,
+            handshake: proto::TransferHandshake,
+            upload: &[u8],
+        ) -> TransferResult<Vec<u8>> {
+            let reference = handshake.reference;
+            let mut requests = self.requests.clone();
+            requests
+                .wait_for(|requests| requests.requests.contains_key(&reference))
+                .await
+                .unwrap();
+            let (mut client, server) = io::duplex(64 * 1024);
+            let Policies {
+                homes,
+                quotas,
+                moderation,
+            } = policies;
+            let connection = TransferConnection::new(
+                server,
+                LOCALHOST,
+                self.files.clone(),
+                homes,
+                quotas,
+                moderation,
+                self.transfers.clone(),
+                requests,
+            );
+            let task = tokio::spawn(connection.run());
+            client.write_all(&handshake.to_bytes().unwrap()).await?;
+            client.write_all(upload).await?;
+            client.shutdown().await?;
+            let mut received = vec![];
+            client.read_to_end(&mut received).await?;
+            task.await.unwrap()?;
+            Ok(received)
+        }
+    }
+
+    fn handshake(reference: ReferenceNumber, size: usize) -> proto::TransferHandshake {
+        proto::TransferHandshake {
+            reference,
+            size: (size as i32).into(),
+        }
+    }
+
+    fn overwriter() -> UserAccount {
+        UserAccount {
+            permissions: UserAccountPermissions {
+                file: [FileOperation::DeleteFile].into_iter().collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refused_request_does_not_stop_transfers() {
+        let memory = MemoryFiles::new();
+        memory.insert_file("Original", "data fork").unwrap();
+        let mut fixture = Fixture::new(&memory);
+        let missing = fixture
+            .transfers
+            .file_download(
+                "Missing".into(),
+                None,
+                UserId::default(),
+                LOCALHOST,
+                UNCOMPRESSED,
+            )
+            .await;
+        assert!(missing.is_none());
+        fixture.download("Original", None).await;
+    }
+
+    #[test]
+    fn test_slots_queue_transfers_over_limits() {
+        let mut slots = Slots::new(TransfersConfig {
+            total: Some(2),
+            account: Some(1),
+            ..Default::default()
+        });
+        let mut started = vec![];
+        let mut waiting = |reference: u32, login: &str| {
+            let (start, rx) = oneshot::channel();
+            started.push(rx);
+            Waiting {
+                reference: reference.into(),
+                user_id: UserId::default(),
+                login: Some(login.to_string()),
+                start,
+            }
+        };
+        let (alice, alice_again, bob, carol) = (
+            waiting(1, "alice"),
+            waiting(2, "alice"),
+            waiting(3, "bob"),
+            waiting(4, "carol"),
+        );
+        assert!(slots.start(alice).is_none());
+        assert_eq!(slots.start(alice_again).map(i32::from), Some(1));
+        assert!(slots.start(bob).is_none());
+        assert_eq!(
+            slots.waiting_count(&Some("dave".into())).map(i32::from),
+            Some(2)
+        );
+        assert_eq!(slots.start(carol).map(i32::from), Some(2));
+
+        assert!(slots.finish(1.into()));
+        let positions = slots
+            .positions()
+            .map(|DownloadInfo(_, reference, position)| (u32::from(reference), i32::from(position)))
+            .collect::<Vec<_>>();
+        assert_eq!(positions, [(4, 1)]);
+        let started = started
+            .iter_mut()
+            .map(|rx| rx.try_recv().is_ok())
+            .collect::<Vec<_>>();
+        assert_eq!(started, [true, true, true, false]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_are_claimed_once_before_expiring() {
+        let mut requests = Requests::new(Duration::from_secs(60));
+        let request = |user_id: i16| Request::FileDownload {
+            path: "file".into(),
+            account: None,
+            user_id: user_id.into(),
+            address: LOCALHOST,
+            compressed: Default::default(),
+        };
+        let first = requests.add(request(1), 0);
+        let second = requests.add(request(1), 0);
+        let third = requests.add(request(2), 0);
+        assert_ne!(first, second);
+
+        let elsewhere = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        assert!(requests.claim(first, elsewhere).is_none());
+        let mapped = IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped());
+        assert!(requests.claim(first, mapped).is_some());
+        assert!(requests.claim(first, LOCALHOST).is_none());
+        assert!(requests.claimed(first, LOCALHOST).is_some());
+        assert!(requests.claimed(second, LOCALHOST).is_none());
+
+        requests.forget_user(2.into());
+        assert!(requests.claim(third, LOCALHOST).is_none());
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(requests.claim(second, LOCALHOST).is_none());
+        requests.expire();
+        assert!(!requests.requests.contains_key(&second));
+        assert!(requests.claimed(first, LOCALHOST).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_progress_is_tracked_until_cancelled() -> TransferResult<()> {
+        let memory = MemoryFiles::new();
+        memory.insert_file("Large", vec![0u8; 10_000])?;
+        let limits = TransfersConfig {
+            download_rate: RateLimits {
+                total: Some(1000),
+                account: None,
+            },
+            ..Default::default()
+        };
+        let mut fixture = Fixture::with(&memory, Bus::new(), limits);
+
+        let user_id = UserId::from(7);
+        let download = fixture
+            .transfers
+            .file_download("Large".into(), None, user_id, LOCALHOST, UNCOMPRESSED)
+            .await
+            .unwrap();
+        let mut progress = fixture.transfers.progress();
+        let task = {
+            let fixture = fixture.clone();
+            let handshake = handshake(download.reference, 0);
+            tokio::spawn(async move { fixture.transfer(Policies::default(), handshake, &[]).await })
+        };
+        let running = progress
+            .wait_for(|progress| progress.iter().any(|transfer| transfer.done > 0))
+            .await
+            .unwrap()
+            .clone();
+        let [running] = &running[..] else {
+            panic!("expected one transfer, got {running:?}");
+        };
+        assert_eq!(running.reference, download.reference);
+        assert_eq!(running.user_id, user_id);
+        assert_eq!(running.path, Path::new("Large"));
+        assert_eq!(running.direction, Direction::Download);
+        assert_eq!(running.total, u32::from(download.transfer_size) as u64);
+        assert!(running.rate > 0);
+        let transfers = &mut fixture.transfers;
+        assert_eq!(transfers.progress_of(user_id).len(), 1);
+        assert!(transfers.progress_of(UserId::from(8)).is_empty());
+
+        assert!(transfers.cancel(download.reference).await);
+        let received = task.await.unwrap()?;
+        assert!(received.len() < 10_000);
+        progress.wait_for(Vec::is_empty).await.unwrap();
+        assert!(!transfers.cancel(download.reference).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_then_upload() -> TransferResult<()> {
+        let memory = MemoryFiles::new();
+        memory.insert_file("Original", "data fork")?;
+        let info = proto::InfoFork {
+            type_code: proto::FileType::from(*b"APPL"),
+            creator_code: proto::Creator(*b"CARO"),
+            comment_len: 7,
+            comment: b"comment".to_vec(),
+            ..memory.read(Path::new("Original")).await?.info().1
+        };
+        memory
+            .write_metadata(Path::new("Original"), &info, &mut &b"rsrc fork"[..], 9)
+            .await?;
+        let mut fixture = Fixture::new(&memory);
+
+        let flattened = fixture.flattened("Original").await?;
+        let upload = fixture.upload("Copy", None, flattened.len()).await;
+        fixture
+            .transfer(Policies::default(), upload, &flattened)
+            .await?;
+
+        assert_eq!(memory.data("Copy").unwrap(), b"data fork");
+        assert_eq!(memory.rsrc("Copy").unwrap(), b"rsrc fork");
+        let copy = memory.get_info(Path::new("Copy")).await?;
+        assert_eq!(copy.file_type.bytes(), b"APPL");
+        assert_eq!(copy.creator.bytes(), b"CARO");
+        assert_eq!(copy.comment, b"comment");
+        let checksum = Checksum::of(&b"data fork"[..])?;
+        assert_eq!(copy.checksum, Some(checksum));
+        let original = memory.get_info(Path::new("Original")).await?;
+        assert_eq!(original.checksum, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compressed_download_then_upload() -> TransferResult<()> {
+        let memory = MemoryFiles::new();
+        let data = "data fork ".repeat(100);
+        memory.insert_file("Original", data.clone())?;
+        let mut fixture = Fixture::new(&memory);
+
+        let zlib = proto::CompressionType::Zlib;
+        let download = fixture
+            .transfers
+            .file_download("Original".into(), None, UserId::default(), LOCALHOST, zlib)
+            .await
+            .unwrap();
+        assert_eq!(u32::from(download.file_size), 1000);
+        let flattened = fixture
+            .transfer(Policies::default(), handshake(download.reference, 0), &[])
+            .await?;
+
+        let mut decoder = proto::FlattenedFileDecoder::new(&flattened[..]).await?;
+        let mut sent = 0;
+        while let Some(mut fork) = decoder.next_fork().await? {
+            let mut body = vec![];
+            fork.read_to_end(&mut body).await?;
+            assert_eq!(body.len() as u64, fork.size());
+            sent += fork.size();
+            if fork.fork_type() == proto::ForkType::Data {
+                assert_eq!(fork.header().compression_type, proto::CompressionType::Zlib);
+                assert!(body.len() < data.len());
+            }
+        }
+        assert_eq!(u32::from(download.transfer_size) as u64, sent);
+
+        let upload = fixture.upload("Copy", None, flattened.len()).await;
+        fixture
+            .transfer(Policies::default(), upload, &flattened)
+            .await?;
+        assert_eq!(memory.data("Copy").unwrap(), data.as_bytes());
+
+        // The compressed fork fits within the quota but what it inflates to
+        // does not.
+        let upload = fixture.upload("Inflated", None, flattened.len()).await;
+        let quotas = Quotas::in_memory(QuotaConfig {
+            account: Some(flattened.len() as u64 + 100),
+            ..Default::default()
+        });
+        let policies = Policies {
+            quotas,
+            ..Default::default()
+        };
+        fixture.transfer(policies, upload, &flattened).await?;
+        assert!(memory.data("Inflated").is_none());
+        assert_eq!(memory.list(Path::new("")).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_homes_are_private() -> TransferResult<()> {
+        let memory = MemoryFiles::new();
+        memory.create_dir("alice")?;
+        memory.insert_file("alice/secret", "secret")?;
+        let alice = UserAccount {
+            home: Some(HomeFolder {
+                path: "alice".into(),
+                is_root: false,
+            }),
+            ..Default::default()
+        };
+        let homes = HomeFolders::new([&alice]);
+        let mut fixture = Fixture::new(&memory);
+
+        for (account, allowed) in [(None, false), (Some(alice.clone()), true)] {
+            let download = fixture.download("alice/secret", account).await;
+            let policies = Policies {
+                homes: homes.clone(),
+                ..Default::default()
+            };
+            let received = fixture.transfer(policies, download, &[]).await?;
+            assert_eq!(!received.is_empty(), allowed);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_quota_is_discarded() -> TransferResult<()> {
+        let memory = MemoryFiles::new();
+        memory.insert_file("Original", "data fork")?;
+        let info = memory.read(Path::new("Original")).await?.info().1;
+        memory
+            .write_metadata(Path::new("Original"), &info, &mut &b"rsrc fork"[..], 9)
+            .await?;
+        let mut fixture = Fixture::new(&memory);
+        let flattened = fixture.flattened("Original").await?;
+        let quotas = Quotas::in_memory(QuotaConfig {
+            account: Some(10),
+            ..Default::default()
+        });
+        let policies = || Policies {
+            quotas: quotas.clone(),
+            ..Default::default()
+        };
+
+        // The client understates the size, so the quota is only exceeded by
+        // the data fork, after the resource fork has been stored.
+        let upload = fixture.upload("Copy", None, 1).await;
+        fixture.transfer(policies(), upload, &flattened).await?;
+        assert!(memory.rsrc("Copy").is_none());
+        assert_eq!(memory.list(Path::new("")).await?.len(), 1);
+
+        // Nor does a failed upload over an existing file touch the file.
+        let upload = fixture.upload("Original", Some(overwriter()), 1).await;
+        fixture.transfer(policies(), upload, &flattened).await?;
+        assert_eq!(memory.data("Original").unwrap(), b"data fork");
+        assert_eq!(memory.rsrc("Original").unwrap(), b"rsrc fork");
+        assert_eq!(memory.list(Path::new("")).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_uploads_replace_files_only_when_complete() -> TransferResult<()> {
+        let memory = MemoryFiles::new();
+        memory.insert_file("Original", "new data")?;
+        memory.insert_file("Copy", "old data")?;
+        let mut fixture = Fixture::new(&memory);
+        let flattened = fixture.flattened("Original").await?;
+
+        let truncated = &flattened[..flattened.len() - 3];
+        for (account, upload, replaced) in [
+            (Some(overwriter()), truncated, false),
+            (None, &flattened[..], false),
+            (Some(overwriter()), &flattened[..], true),
+        ] {
+            let handshake = fixture.upload("Copy", account, upload.len()).await;
+            fixture
+                .transfer(Policies::default(), handshake, upload)
+                .await?;
+            let expected: &[u8] = if replaced { b"new data" } else { b"old data" };
+            assert_eq!(memory.data("Copy").unwrap(), expected);
+            assert_eq!(memory.list(Path::new("")).await?.len(), 2);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_held_uploads_wait_in_pending_folder() -> TransferResult<()> {
+        let memory = MemoryFiles::new();
+        memory.insert_file("Original", "data fork")?;
+        memory.create_dir("Pending Uploads")?;
+        let bus = Bus::new();
+        let mut notifications = bus.subscribe().incoming().boxed();
+        let mut fixture = Fixture::with(&memory, bus, Default::default());
+        let flattened = fixture.flattened("Original").await?;
+        let moderation = Moderation::in_memory(ModerationConfig {
+            enabled: true,
+            ..Default::default()
+        });
+
+        let upload = fixture.upload("Copy", None, flattened.len()).await;
+        let policies = Policies {
+            moderation: moderation.clone(),
+            ..Default::default()
+        };
+        fixture.transfer(policies, upload, &flattened).await?;
+
+        let pending = Path::new("Pending Uploads/Copy");
+        assert!(memory.data("Copy").is_none());
+        assert_eq!(memory.data(pending).unwrap(), b"data fork");
+        assert_eq!(
+            moderation.pending(pending).unwrap().destination,
+            Path::new("Copy")
+        );
+        let notification = notifications.next().await;
+        assert!(matches!(
+            notification,
+            Some(Notification::UploadPending(UploadPending(path, _))) if path == pending
+        ));
+        Ok(())
+    }
+}
@@ -4,7 +4,11 @@ use anyhow::Result;
 use dialoguer::{Input, MultiSelect, Password};
 use strum::IntoEnumIterator;
 
-use super::application::{Permissions, UserAccount, UserDataFile};
+use super::{
+    application::{Permissions, UserAccount, UserDataFile},
+    config::{self, Config},
+    hooks::{Event, Hooks},
+};
 
 fn input_permissions<F, P>(prompt: &str, perms: &mut P) -> Result<()>
 where
@@ -34,7 +38,7 @@ impl InteractiveUserEditor {
     fn input_identity(&mut self) -> Result<()> {
         let username_pattern = regex::Regex::new(r"^[a-z0-9_-]{1,32}$")?;
         fn byte_length(s: &str, min: usize, max: usize) -> bool {
-            let len = s.as_bytes().len();
+            let len = s.len();
             min <= len && len <= max
         }
 
@@ -89,9 +93,26 @@ impl InteractiveUserEditor {
         Ok(self)
     }
 
-    pub fn serialize(self) -> Result<String> {
+    /// Runs the account-change hooks of the server settings for the edited
+    /// account, once it has been written.
+    pub fn notify(&self, created: bool) -> Result<()> {
         let Self(account) = self;
-        let file: UserDataFile = account.into();
+        let event = Event::account_change(account, created);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let config = Config::load(config::DEFAULT_PATH).await?;
+            if !Hooks::new(config.hooks).check(&event).await {
+                eprintln!("an account-change hook failed");
+            }
+            Ok(())
+        })
+    }
+
+    pub fn serialize(&self) -> Result<String> {
+        let Self(account) = self;
+        let file: UserDataFile = account.clone().into();
         let toml = toml::to_string(&file)?;
         Ok(toml)
     }
@@ -17,6 +17,7 @@ use tracing::{debug, error};
 use super::{
     application::UserAccount,
     bus::{Bus, Notification},
+    hooks::{Event, Hooks},
 };
 
 #[derive(Debug, Error)]
@@ -107,22 +108,23 @@ enum Command {
 }
 
 #[derive(Debug, Clone, From)]
-pub struct UsersService(mpsc::Sender<Command>, Bus);
+pub struct UsersService(mpsc::Sender<Command>, Bus, Hooks);
 
 impl UsersService {
-    pub fn new(bus: Bus) -> (Self, UserUpdateProcessor) {
+    pub fn new(bus: Bus, hooks: Hooks) -> (Self, UserUpdateProcessor) {
         let (tx, rx) = mpsc::channel(10);
-        let service = Self(tx, bus);
+        let service = Self(tx, bus, hooks);
         let process = UserUpdateProcessor::new(rx);
         (service, process)
     }
     pub async fn add(&mut self, mut user: UserNameWithInfo) -> UsersResult<UserId> {
         let (tx, rx) = oneshot::channel();
         let command = Command::Connect(user.clone(), tx);
-        let Self(tx, bus) = self;
+        let Self(tx, bus, hooks) = self;
         tx.send(command).await?;
         let id = rx.await?;
         user.user_id = id;
+        hooks.notify(Event::login(&user));
         let notification = Notification::UserConnect(user.into());
         bus.publish(notification);
         Ok(id)
@@ -131,7 +133,7 @@ impl UsersService {
         let (tx, rx) = oneshot::channel();
         let notification = Notification::UserUpdate(user.clone().into());
         let command = Command::Update(user, tx);
-        let Self(tx, bus) = self;
+        let Self(tx, bus, _) = self;
         tx.send(command).await?;
         rx.await?;
         bus.publish(notification);
@@ -139,11 +141,13 @@ impl UsersService {
     }
     pub async fn delete(&mut self, user: UserNameWithInfo) -> UsersResult<()> {
         let (tx, rx) = oneshot::channel();
+        let event = Event::logout(&user);
         let notification = Notification::UserDisconnect(user.clone().into());
         let command = Command::Disconnect(user, tx);
-        let Self(tx, bus) = self;
+        let Self(tx, bus, hooks) = self;
         tx.send(command).await?;
         rx.await?;
+        hooks.notify(event);
         bus.publish(notification);
         Ok(())
     }
@@ -229,6 +233,9 @@ impl UserAccounts {
         }
         Ok(users)
     }
+    pub fn iter(&self) -> impl Iterator<Item = &UserAccount> {
+        self.users.values()
+    }
     pub fn get(&self, login: proto::UserLogin) -> Option<&UserAccount> {
         let username = login.text();
         self.users.get(&username)